@@ -0,0 +1,384 @@
+use generational_arena::Index;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::{Event, PlayerThing, ProjectileThing, Solid, State, Thing, PROJECTILE_LIFETIME_SEC};
+
+/// the time it takes to switch from one ability to another
+const SWITCH_COOLDOWN_SEC:f32 = 0.3;
+
+/// the abilities a player can use, i.e. the weapons
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Ability {
+    /// rapid fire of single projectiles, never runs out of ammo
+    Blaster,
+    /// a spread of projectiles
+    Shotgun,
+    /// a slow projectile bouncing off walls
+    Bouncer,
+    /// a fast projectile charged while the trigger is held, fired on release
+    Rail
+}
+
+impl Default for Ability {
+    fn default() -> Self {
+        Self::Blaster
+    }
+}
+
+/// the parameters of an ability and the projectiles it fires
+#[derive(Clone, Copy, Debug)]
+pub struct AbilityStats {
+    /// time between two uses
+    pub cooldown:f32,
+    /// ammo given on spawn, unlimited if None
+    pub max_ammo:Option<u8>,
+    pub projectiles:u8,
+    /// the angle in radians the projectiles are spread across
+    pub spread:f32,
+    pub speed:f32,
+    pub radius:f32,
+    pub damage:i8,
+    /// times the projectiles bounce off walls before being removed
+    pub bounces:u8,
+    /// seconds the projectiles fly before being removed
+    pub lifetime:f32,
+    /// time needed to fully charge, zero for abilities fired right away
+    pub charge_sec:f32
+}
+
+impl Ability {
+    pub const ALL:[Ability; 4] = [Ability::Blaster, Ability::Shotgun, Ability::Bouncer, Ability::Rail];
+
+    pub fn stats(&self) -> AbilityStats {
+        match self {
+            Ability::Blaster => AbilityStats {
+                cooldown:0.25,
+                max_ammo:None,
+                projectiles:1,
+                spread:0.0,
+                speed:20.0,
+                radius:0.25,
+                damage:1,
+                bounces:0,
+                lifetime:PROJECTILE_LIFETIME_SEC,
+                charge_sec:0.0
+            },
+            Ability::Shotgun => AbilityStats {
+                cooldown:0.8,
+                max_ammo:Some(12),
+                projectiles:5,
+                spread:0.5,
+                speed:18.0,
+                radius:0.2,
+                damage:1,
+                bounces:0,
+                lifetime:0.5,
+                charge_sec:0.0
+            },
+            Ability::Bouncer => AbilityStats {
+                cooldown:0.4,
+                max_ammo:Some(20),
+                projectiles:1,
+                spread:0.0,
+                speed:14.0,
+                radius:0.3,
+                damage:1,
+                bounces:3,
+                lifetime:4.0,
+                charge_sec:0.0
+            },
+            Ability::Rail => AbilityStats {
+                cooldown:1.0,
+                max_ammo:Some(5),
+                projectiles:1,
+                spread:0.0,
+                speed:60.0,
+                radius:0.15,
+                damage:3,
+                bounces:0,
+                lifetime:PROJECTILE_LIFETIME_SEC,
+                charge_sec:1.0
+            }
+        }
+    }
+
+    /// the index of the ability, e.g. into `PlayerThing::ammo`
+    pub fn index(&self) -> usize {
+        Self::ALL.iter().position(|a| a == self).unwrap_or(0)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Ability::Blaster => "Blaster",
+            Ability::Shotgun => "Shotgun",
+            Ability::Bouncer => "Bouncer",
+            Ability::Rail => "Rail"
+        }
+    }
+}
+
+impl PlayerThing {
+    /// the ammo left for the ability, None if unlimited
+    pub fn ammo(&self, ability:Ability) -> Option<u8> {
+        match ability.stats().max_ammo {
+            Some(_) => Some(self.ammo.get(ability.index()).copied().unwrap_or(0)),
+            None => None
+        }
+    }
+
+    /// fills up the ammo of every ability
+    pub fn refill_ammo(&mut self) {
+        self.ammo = Ability::ALL.iter().map(|a| a.stats().max_ammo.unwrap_or(0)).collect();
+    }
+
+    /// adds ammo to the ability, up to its max
+    pub fn give_ammo(&mut self, ability:Ability, amount:u8) {
+        if let Some(max) = ability.stats().max_ammo {
+            if self.ammo.len() < Ability::ALL.len() {
+                self.ammo.resize(Ability::ALL.len(), 0);
+            }
+
+            let ammo = &mut self.ammo[ability.index()];
+            *ammo = ammo.saturating_add(amount).min(max);
+        }
+    }
+
+    /// true if the ability can be used, ignoring the cooldown
+    pub fn has_ammo(&self, ability:Ability) -> bool {
+        self.ammo(ability).map(|ammo| ammo > 0).unwrap_or(true)
+    }
+}
+
+/// switches the ability of the player thing, delaying its next use
+pub fn switch_ability(state:&mut State, thing_id:Index, ability:Ability) {
+    if let Some(Thing::Player(player)) = state.things.get_mut(thing_id) {
+        if player.ability != ability && player.is_alive() {
+            player.ability = ability;
+            player.charge = None;
+            player.ability_cooldown = player.ability_cooldown.max(SWITCH_COOLDOWN_SEC);
+        }
+    }
+}
+
+/// uses the ability of the player thing towards the target if it is alive and ready
+/// abilities which charge start charging while triggered and are used when the trigger is released
+/// returns the projectiles fired, which are not yet spawned
+pub fn use_ability(state:&mut State, thing_id:Index, trigger:bool, target:Vec2) -> Vec<Thing> {
+    let mut projectiles = Vec::new();
+    if let Some(Thing::Player(player)) = state.things.get_mut(thing_id) {
+        let ability = player.ability;
+        let stats = ability.stats();
+        if !player.is_alive() || player.ability_cooldown > 0.0 || !player.has_ammo(ability) {
+            player.charge = None;
+            return projectiles;
+        }
+
+        let mut damage = stats.damage;
+        if stats.charge_sec > 0.0 {
+            match (trigger, player.charge) {
+                (true, None) => {
+                    player.charge = Some(0.0);
+                    return projectiles;
+                }
+                (false, Some(charge)) => {
+                    // an uncharged shot deals a single heart of damage
+                    let fraction = (charge / stats.charge_sec).min(1.0);
+                    damage = 1 + ((damage - 1) as f32 * fraction).round() as i8;
+                    player.charge = None;
+                }
+                _ => return projectiles
+            }
+        } else if !trigger {
+            return projectiles;
+        }
+
+        let dir = target - player.pos;
+        if dir.length() == 0.0 {
+            return projectiles;
+        }
+
+        let angle = dir.y.atan2(dir.x);
+        for i in 0..stats.projectiles {
+            let offset = if stats.projectiles > 1 {
+                stats.spread * (i as f32 / (stats.projectiles - 1) as f32 - 0.5)
+            } else {
+                0.0
+            };
+
+            let dir = Vec2::new((angle + offset).cos(), (angle + offset).sin());
+            projectiles.push(Thing::Projectile(ProjectileThing {
+                pos:player.pos,
+                radius:stats.radius,
+                solid:Solid::Partial,
+                vel:dir * stats.speed,
+                owner:thing_id,
+                damage,
+                bounces:stats.bounces,
+                lifetime:stats.lifetime
+            }));
+        }
+
+        player.ability_cooldown = stats.cooldown;
+        if stats.max_ammo.is_some() {
+            if let Some(ammo) = player.ammo.get_mut(ability.index()) {
+                *ammo = ammo.saturating_sub(1);
+            }
+        }
+
+        state.events.push(Event::ProjectileFired {
+            pos:player.pos
+        });
+    }
+
+    projectiles
+}
+
+/// counts down the cooldown of the player thing and charges its ability
+pub fn update_ability(player:&mut PlayerThing, dt:f32) {
+    player.ability_cooldown = (player.ability_cooldown - dt).max(0.0);
+    if let Some(charge) = &mut player.charge {
+        *charge = (*charge + dt).min(player.ability.stats().charge_sec);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Map;
+
+    /// a state with a single living player using the ability
+    fn armed(ability:Ability) -> (State, Index) {
+        let mut state = State::with_map(Map::new(), 100.0, 100.0);
+        let mut thing = Thing::new_player("a");
+        if let Thing::Player(player) = &mut thing {
+            player.spawn_pos = Some(Vec2::new(50.0, 50.0));
+        }
+        thing.spawn();
+        if let Thing::Player(player) = &mut thing {
+            player.ability = ability;
+        }
+
+        let id = state.things.insert(thing);
+        (state, id)
+    }
+
+    fn player(state:&State, id:Index) -> &PlayerThing {
+        match state.things.get(id) {
+            Some(Thing::Player(player)) => player,
+            _ => panic!("no player")
+        }
+    }
+
+    fn tick(state:&mut State, id:Index, dt:f32) {
+        if let Some(Thing::Player(player)) = state.things.get_mut(id) {
+            update_ability(player, dt);
+        }
+    }
+
+    fn fire(state:&mut State, id:Index, trigger:bool) -> Vec<Thing> {
+        use_ability(state, id, trigger, Vec2::new(60.0, 50.0))
+    }
+
+    fn damage(projectiles:&[Thing]) -> Vec<i8> {
+        projectiles.iter().filter_map(|p| match p {
+            Thing::Projectile(p) => Some(p.damage),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn blaster_fires_on_trigger_once_cooled_down() {
+        let (mut state, id) = armed(Ability::Blaster);
+        assert!(fire(&mut state, id, false).is_empty());
+
+        let projectiles = fire(&mut state, id, true);
+        assert_eq!(projectiles.len(), 1);
+        assert!(matches!(state.events.last(), Some(Event::ProjectileFired { .. })));
+        if let Thing::Projectile(projectile) = &projectiles[0] {
+            assert_eq!(projectile.vel, Vec2::new(Ability::Blaster.stats().speed, 0.0));
+            assert_eq!(projectile.owner, id);
+        }
+
+        assert!(fire(&mut state, id, true).is_empty());
+        tick(&mut state, id, 0.2);
+        assert!(fire(&mut state, id, true).is_empty());
+        tick(&mut state, id, 0.1);
+        assert_eq!(fire(&mut state, id, true).len(), 1);
+        assert_eq!(player(&state, id).ammo(Ability::Blaster), None);
+    }
+
+    #[test]
+    fn shotgun_spreads_and_runs_out_of_ammo() {
+        let (mut state, id) = armed(Ability::Shotgun);
+        let stats = Ability::Shotgun.stats();
+        let max = stats.max_ammo.unwrap();
+
+        let projectiles = fire(&mut state, id, true);
+        assert_eq!(projectiles.len(), stats.projectiles as usize);
+        let angles:Vec<f32> = projectiles.iter().map(|p| match p {
+            Thing::Projectile(p) => p.vel.y.atan2(p.vel.x),
+            _ => 0.0
+        }).collect();
+        assert!((angles[angles.len() - 1] - angles[0] - stats.spread).abs() < 1e-4);
+        assert_eq!(player(&state, id).ammo(Ability::Shotgun), Some(max - 1));
+
+        for _ in 1..max {
+            tick(&mut state, id, stats.cooldown);
+            assert!(!fire(&mut state, id, true).is_empty());
+        }
+
+        tick(&mut state, id, stats.cooldown);
+        assert!(!player(&state, id).has_ammo(Ability::Shotgun));
+        assert!(fire(&mut state, id, true).is_empty());
+
+        if let Some(Thing::Player(player)) = state.things.get_mut(id) {
+            player.give_ammo(Ability::Shotgun, u8::MAX);
+        }
+        assert_eq!(player(&state, id).ammo(Ability::Shotgun), Some(max));
+    }
+
+    #[test]
+    fn rail_damage_scales_with_charge() {
+        let (mut state, id) = armed(Ability::Rail);
+        let stats = Ability::Rail.stats();
+
+        // released right away, an uncharged shot
+        assert!(fire(&mut state, id, true).is_empty());
+        assert_eq!(damage(&fire(&mut state, id, false)), vec![1]);
+
+        // half charged
+        tick(&mut state, id, stats.cooldown);
+        assert!(fire(&mut state, id, true).is_empty());
+        tick(&mut state, id, stats.charge_sec / 2.0);
+        assert!(fire(&mut state, id, true).is_empty());
+        assert_eq!(damage(&fire(&mut state, id, false)), vec![1 + (stats.damage - 1) / 2]);
+
+        // charging longer than needed deals full damage
+        tick(&mut state, id, stats.cooldown);
+        fire(&mut state, id, true);
+        tick(&mut state, id, stats.charge_sec * 3.0);
+        assert_eq!(player(&state, id).charge, Some(stats.charge_sec));
+        assert_eq!(damage(&fire(&mut state, id, false)), vec![stats.damage]);
+        assert_eq!(player(&state, id).ammo(Ability::Rail), Some(stats.max_ammo.unwrap() - 3));
+    }
+
+    #[test]
+    fn switching_cancels_the_charge_and_delays_the_next_use() {
+        let (mut state, id) = armed(Ability::Rail);
+        fire(&mut state, id, true);
+        assert!(player(&state, id).charge.is_some());
+
+        switch_ability(&mut state, id, Ability::Bouncer);
+        assert_eq!(player(&state, id).ability, Ability::Bouncer);
+        assert_eq!(player(&state, id).charge, None);
+        assert!(fire(&mut state, id, true).is_empty());
+
+        tick(&mut state, id, SWITCH_COOLDOWN_SEC);
+        // switching to the ability in use changes nothing
+        switch_ability(&mut state, id, Ability::Bouncer);
+        let projectiles = fire(&mut state, id, true);
+        assert_eq!(projectiles.len(), 1);
+        assert!(matches!(&projectiles[0], Thing::Projectile(p) if p.bounces == Ability::Bouncer.stats().bounces));
+    }
+}
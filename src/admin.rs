@@ -0,0 +1,271 @@
+//! operating a running server, from the console of the server or by clients authenticated as admin
+//!
+//! every command is written to the audit log, together with who gave it and its outcome
+
+use std::{collections::BTreeMap, fs::{File, OpenOptions}, io::{BufRead, Write}, path::Path, sync::Mutex, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use hostess::{client::Uuid, log::{info, warn}};
+use sample_lib::AdminCommand;
+
+use crate::server;
+
+/// appends a line for each admin command and each failed attempt to authenticate as admin
+pub struct AuditLog {
+    file:Mutex<File>
+}
+
+impl AuditLog {
+    pub fn open(path:&Path) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file:Mutex::new(file)
+        })
+    }
+
+    /// records what was done on the instance, by whom and with which outcome
+    pub fn record(&self, instance:usize, source:&str, action:&str, outcome:&str) {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let line = format!("{} instance {} {}: {} -> {}\n", secs, instance, source, action, outcome);
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        if let Err(err) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            warn!("Failed to write audit log: {}", err);
+        }
+    }
+}
+
+/// the failed logins allowed before logins are refused for a while
+const FREE_LOGIN_ATTEMPTS:u32 = 3;
+
+/// how long logins are refused after the free attempts are used up, doubled with each further failed login
+const LOGIN_BACKOFF:Duration = Duration::from_secs(2);
+const MAX_LOGIN_BACKOFF:Duration = Duration::from_secs(300);
+
+/// how long the failed logins of a client are remembered after its last one
+const FORGET_LOGINS_AFTER:Duration = Duration::from_secs(3600);
+
+/// the failed logins of every client together allowed within the window, before any login is refused until the window ends
+const MAX_FAILED_LOGINS:u32 = 30;
+const FAILED_LOGINS_WINDOW:Duration = Duration::from_secs(60);
+
+/// the failed logins of a single client
+struct Backoff {
+    failures:u32,
+    refused_until:Option<Instant>,
+    last_failure:Instant
+}
+
+/// slows down guessing the admin password by refusing logins for a while after failed ones
+/// each client is throttled by every id it is known by, i.e. its connection and its player id,
+/// such that one client failing to log in does not keep an admin from logging in
+/// as clients choose their ids, the failed logins of every client together are limited as well
+pub struct LoginThrottle {
+    clients:BTreeMap<Uuid, Backoff>,
+    window_start:Option<Instant>,
+    window_failures:u32
+}
+
+impl LoginThrottle {
+    pub const fn new() -> Self {
+        Self {
+            clients:BTreeMap::new(),
+            window_start:None,
+            window_failures:0
+        }
+    }
+
+    /// returns how much longer logins of the client known by the ids are refused, None if a login can be attempted now
+    pub fn refused_for(&self, ids:&[Uuid], now:Instant) -> Option<Duration> {
+        let client = ids.iter()
+            .filter_map(|id| self.clients.get(id))
+            .filter_map(|backoff| backoff.refused_until)
+            .max();
+        let everyone = match self.window_failures >= MAX_FAILED_LOGINS {
+            true => self.window_start.map(|start| start + FAILED_LOGINS_WINDOW),
+            false => None
+        };
+
+        client.into_iter().chain(everyone).max().filter(|until| *until > now).map(|until| until - now)
+    }
+
+    /// records a failed login of the client known by the ids, returning how long its logins are refused from now on
+    pub fn failed(&mut self, ids:&[Uuid], now:Instant) -> Option<Duration> {
+        self.clients.retain(|_, backoff| now.saturating_duration_since(backoff.last_failure) < FORGET_LOGINS_AFTER);
+        if self.window_start.map(|start| now.saturating_duration_since(start) >= FAILED_LOGINS_WINDOW).unwrap_or(true) {
+            self.window_start = Some(now);
+            self.window_failures = 0;
+        }
+        self.window_failures = self.window_failures.saturating_add(1);
+
+        let mut refused = None;
+        for id in ids {
+            let backoff = self.clients.entry(*id).or_insert(Backoff {
+                failures:0,
+                refused_until:None,
+                last_failure:now
+            });
+            backoff.failures = backoff.failures.saturating_add(1);
+            backoff.last_failure = now;
+            if backoff.failures < FREE_LOGIN_ATTEMPTS {
+                continue;
+            }
+
+            let doublings = (backoff.failures - FREE_LOGIN_ATTEMPTS).min(16);
+            let wait = (LOGIN_BACKOFF * 2u32.pow(doublings)).min(MAX_LOGIN_BACKOFF);
+            backoff.refused_until = Some(now + wait);
+            refused = refused.max(Some(wait));
+        }
+
+        refused
+    }
+
+    /// forgets the failed logins of the client known by the ids
+    pub fn succeeded(&mut self, ids:&[Uuid]) {
+        for id in ids {
+            self.clients.remove(id);
+        }
+    }
+}
+
+/// true if the password given matches the password expected
+/// compares every byte, such that the time taken does not tell how much of the password is right
+pub fn password_matches(given:&str, expected:&str) -> bool {
+    let (given, expected) = (given.as_bytes(), expected.as_bytes());
+    let mut diff = given.len() ^ expected.len();
+    for (i, b) in expected.iter().enumerate() {
+        diff |= (given.get(i).copied().unwrap_or(0) ^ b) as usize;
+    }
+
+    diff == 0
+}
+
+/// reads commands from stdin on a thread of its own, sending them to the instances
+/// a command is sent to every instance, unless prefixed with @ and the number of an instance, e.g. `@2 restart`
+pub fn spawn_console(instances:usize) {
+    let spawned = std::thread::Builder::new().name("console".into()).spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break
+            };
+            run_console_line(&line, instances);
+        }
+    });
+
+    if let Err(err) = spawned {
+        warn!("Failed to start console: {}", err);
+    }
+}
+
+fn run_console_line(line:&str, instances:usize) {
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+
+    if line == "help" {
+        info!("Commands, prefix with @<instance> to only run on one of the {} instances:", instances);
+        for help in AdminCommand::HELP.iter() {
+            info!("  {}", help);
+        }
+        return;
+    }
+
+    let (instance, line) = match line.strip_prefix('@') {
+        Some(rest) => {
+            let (instance, command) = rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+            match instance.parse::<usize>() {
+                Ok(instance) if instance < instances => (Some(instance), command),
+                _ => {
+                    warn!("Unknown instance {}, expected 0 to {}", instance, instances - 1);
+                    return;
+                }
+            }
+        }
+        None => (None, line)
+    };
+
+    match AdminCommand::parse(line) {
+        Ok(command) => server::send_admin_command(instance, command),
+        Err(err) => warn!("{}, type help to list the commands", err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_must_match_exactly() {
+        assert!(password_matches("secret", "secret"));
+        assert!(!password_matches("secre", "secret"));
+        assert!(!password_matches("secrets", "secret"));
+        assert!(!password_matches("", "secret"));
+    }
+
+    #[test]
+    fn failed_logins_back_off() {
+        let mut throttle = LoginThrottle::new();
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        let now = Instant::now();
+        for _ in 1..FREE_LOGIN_ATTEMPTS {
+            assert_eq!(throttle.failed(&ids, now), None);
+            assert_eq!(throttle.refused_for(&ids, now), None);
+        }
+
+        assert_eq!(throttle.failed(&ids, now), Some(LOGIN_BACKOFF));
+        assert_eq!(throttle.refused_for(&ids, now), Some(LOGIN_BACKOFF));
+        assert_eq!(throttle.refused_for(&ids, now + LOGIN_BACKOFF), None);
+
+        let later = now + LOGIN_BACKOFF;
+        assert_eq!(throttle.failed(&ids, later), Some(LOGIN_BACKOFF * 2));
+        for _ in 0..20 {
+            throttle.failed(&ids, later);
+        }
+        assert_eq!(throttle.refused_for(&ids, later), Some(MAX_LOGIN_BACKOFF));
+        // reconnecting with the same player id does not help
+        assert_eq!(throttle.refused_for(&[Uuid::new_v4(), ids[1]], later), Some(MAX_LOGIN_BACKOFF));
+
+        throttle.succeeded(&ids);
+        assert_eq!(throttle.refused_for(&ids, later), None);
+        assert_eq!(throttle.failed(&ids, later), None);
+    }
+
+    #[test]
+    fn admin_can_log_in_while_another_client_is_throttled() {
+        let mut throttle = LoginThrottle::new();
+        let attacker = [Uuid::new_v4(), Uuid::new_v4()];
+        let admin = [Uuid::new_v4(), Uuid::new_v4()];
+        let now = Instant::now();
+        for i in 0..10 {
+            throttle.failed(&attacker, now + MAX_LOGIN_BACKOFF * i);
+        }
+
+        let later = now + MAX_LOGIN_BACKOFF * 9;
+        assert!(throttle.refused_for(&attacker, later).is_some());
+        assert_eq!(throttle.refused_for(&admin, later), None);
+        // the admin mistyping the password once is not refused either
+        assert_eq!(throttle.failed(&admin, later), None);
+        assert_eq!(throttle.refused_for(&admin, later), None);
+    }
+
+    #[test]
+    fn failed_logins_of_everyone_are_capped() {
+        let mut throttle = LoginThrottle::new();
+        let now = Instant::now();
+        for _ in 0..MAX_FAILED_LOGINS {
+            throttle.failed(&[Uuid::new_v4()], now);
+        }
+
+        let admin = [Uuid::new_v4()];
+        assert_eq!(throttle.refused_for(&admin, now), Some(FAILED_LOGINS_WINDOW));
+        assert_eq!(throttle.refused_for(&admin, now + FAILED_LOGINS_WINDOW), None);
+
+        // the window starts over with the next failed login
+        assert_eq!(throttle.failed(&[Uuid::new_v4()], now + FAILED_LOGINS_WINDOW), None);
+        assert_eq!(throttle.refused_for(&admin, now + FAILED_LOGINS_WINDOW), None);
+    }
+}
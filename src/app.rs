@@ -2,12 +2,12 @@ use std::{collections::VecDeque, f32::consts::PI};
 
 use super::Canvas;
 use crate::{
-    apply_input, get_item, performance_now_ms, set_item, reload, CustomMsg, Input,
-    State, StateHistory, Thing, play_sound,
+    get_item, performance_now_ms, set_item, reload, sanitize_chat, Ability, AdminCommand, CustomMsg, Input, MAX_CHAT_LENGTH,
+    GameMode, MatchPhase, PickupKind, Team, Prediction, Replay, ReplayPlayer, SnapshotBuffer, State, StateDelta, StateHistory, Thing, play_sound,
 };
-use generational_arena::Arena;
+use generational_arena::{Arena, Index};
 use glam::Vec2;
-use hostess::{uuid::Uuid, client::Bincoded, client::ClientMsg, client::{ServerMsg, InstanceInfo}};
+use hostess::{uuid::Uuid, client::Bincoded, client::ClientMsg, client::{ServerMsg, InstanceInfo}, log::{warn, error}};
 
 
 // Dev flags
@@ -16,29 +16,60 @@ static DEV_QUICK_LOGIN:bool         = false;
 static DEV_QUICK_JOIN:bool          = false;
 static DEV_SHOW_SPAWNPOINTS:bool    = false;
 static DEV_SHOW_NETSTAT:bool        = false;
-static INSTRUCTIONS:[&str;3]        = ["Use WASD to Move.", "Use the mouse to point and shoot.", "Use tab to show the score"];
+static SNAPSHOT_BUFFER_SIZE:usize   = 64;
+static INSTRUCTIONS:[&str;6]        = ["Use WASD to Move.", "Use the mouse to point and shoot.", "Use 1..4 to switch weapon.", "Use tab to show the score", "Use V to spectate", "Use T to chat, Y to chat with your team"];
+/// how much closer the camera is when following a player
+static FOLLOW_ZOOM:f64              = 1.5;
+/// how far left and right seek in a replay
+static REPLAY_SEEK_SEC:f64          = 5.0;
+static REPLAY_MIN_SPEED:f32         = 0.25;
+static REPLAY_MAX_SPEED:f32         = 8.0;
+/// how long a message from an admin is shown
+static NOTICE_SEC:f32               = 5.0;
+/// the most lines kept in the chat log
+static CHAT_LOG_SIZE:usize          = 8;
+/// how long a chat line is shown before fading out
+static CHAT_SHOW_SEC:f32            = 8.0;
+static CHAT_FADE_SEC:f32            = 2.0;
 
 pub struct App {
     servers:Vec<InstanceInfo>,
     player_name: String,
     debug: bool,
     app_state: AppState,
+    /// the id of this connection, differing for every tab such that several can play at once
     id: Uuid,
+    /// the identity of the player, the same for every tab
+    player_id: Uuid,
     canvas: Canvas,
     current: State,
     history: StateHistory,
+    /// the recent snapshots received, which delta snapshots are based on
+    snapshots: SnapshotBuffer,
     connection_status: String,
     ping: f64,
     client_bytes_sec: f32,
     server_bytes_sec: f32,
     input: Input,
-    input_history: VecDeque<Input>,
+    prediction: Prediction,
     updates: u64,
     server_tick_rate: f32,
     since_last_snapshot_sec: f32,
     lerp_alpha: f32,
     effects: Arena<Effect>,
     show_score: bool,
+    /// true if only watching the game, without a thing
+    spectator: bool,
+    /// the thing followed by the camera when spectating, the whole map is shown if None
+    follow: Option<Index>,
+    /// the replay being watched, if any
+    replay: Option<ReplayPlayback>,
+    /// the latest message from an admin and the seconds left to show it
+    notice: Option<(String, f32)>,
+    /// the recent chat messages, the newest last
+    chat_log: VecDeque<ChatLine>,
+    /// the message being typed, if chatting
+    chat_input: Option<ChatInput>,
     pub server_messages: Vec<ServerMsg>,
     pub client_messages: Vec<ClientMsg>,
 }
@@ -63,6 +94,8 @@ enum AppState {
     JoinServer {server:InstanceInfo},
     /// when in game and playing
     InGame,
+    /// when watching a replay, the server is ignored until the page is reloaded
+    Replay,
 }
 
 /*
@@ -98,6 +131,38 @@ enum Effect {
     Smoke(Smoke),
 }
 
+/// a line of the chat log
+struct ChatLine {
+    text: String,
+    color: &'static str,
+    /// seconds since the line was received
+    age: f32,
+}
+
+impl ChatLine {
+    pub fn alpha(&self) -> f32 {
+        let a = (self.age - CHAT_SHOW_SEC) / CHAT_FADE_SEC;
+        1.0 - a.clamp(0.0, 1.0)
+    }
+}
+
+/// a chat message being typed
+struct ChatInput {
+    text: String,
+    /// true if only said to the team
+    team_only: bool,
+}
+
+/// a replay being watched
+struct ReplayPlayback {
+    player: ReplayPlayer,
+    paused: bool,
+    /// how many times faster than real time the replay is played
+    speed: f32,
+    /// seconds played since the current frame
+    time: f64,
+}
+
 impl App {
     pub fn new() -> Self {
         Self {
@@ -107,20 +172,28 @@ impl App {
             canvas: Canvas::new(),
             current: State::new(),
             input: Input::default(),
-            input_history: VecDeque::new(),
+            prediction: Prediction::new(),
             server_messages: Vec::new(),
             connection_status: "Not connected!".into(),
             client_messages: Vec::new(),
             id: Uuid::new_v4(),
+            player_id: persistent_id(),
             ping: 0.0,
             server_bytes_sec: 0.0,
             client_bytes_sec: 0.0,
             updates: 0,
             history: StateHistory::new(),
+            snapshots: SnapshotBuffer::new(SNAPSHOT_BUFFER_SIZE),
             server_tick_rate: 64.0,
             since_last_snapshot_sec: 0.0,
             lerp_alpha: 0.0,
             show_score: false,
+            spectator: false,
+            follow: None,
+            replay: None,
+            notice: None,
+            chat_log: VecDeque::new(),
+            chat_input: None,
             effects: Arena::new(),
             servers:Vec::new()
         }
@@ -134,8 +207,13 @@ impl App {
         let grid_size = 16.0;
         self.canvas.set_scale(grid_size);
 
-        if self.app_state == AppState::InGame {
+        if self.shows_game() {
+            match self.follow_pos() {
+                Some(pos) => self.canvas.set_camera(grid_size * FOLLOW_ZOOM, pos.x as f64, pos.y as f64),
+                None => self.canvas.set_scale(grid_size)
+            }
             self.draw_game();
+            self.canvas.set_scale(grid_size);
         }
 
         let cx = (self.canvas.width() / grid_size as u32 / 2) as f64;
@@ -165,10 +243,63 @@ impl App {
     fn draw_thing(&self, thing: &Thing, pos: Vec2) {
         let x = pos.x as f64;
         let y = pos.y as f64;
-        if let Thing::Player(player) = thing {
-            if !player.is_alive() {
+        match thing {
+            Thing::Player(player) => {
+                if !player.is_alive() {
+                    return;
+                }
+
+                if player.shield > 0.0 {
+                    self.canvas.save();
+                    self.canvas.set_stroke_style("rgba(0,128,255,0.75)");
+                    self.canvas.draw_circle(x, y, *thing.radius() as f64 + 0.25);
+                    self.canvas.restore();
+                }
+
+                if let Some(team) = player.team {
+                    self.canvas.save();
+                    self.canvas.set_stroke_style(team.color());
+                    self.canvas.draw_circle(x, y, *thing.radius() as f64);
+                    self.canvas.restore();
+                    return;
+                }
+            }
+            Thing::Flag(flag) => {
+                self.canvas.save();
+                self.canvas.set_stroke_style(flag.team.color());
+                self.canvas.set_fille_style(flag.team.color());
+                self.canvas.begin_path();
+                self.canvas.move_to(x, y + 0.5);
+                self.canvas.line_to(x, y - 0.75);
+                self.canvas.line_to(x + 0.6, y - 0.5);
+                self.canvas.line_to(x, y - 0.25);
+                self.canvas.stroke();
+                self.canvas.fill();
+                self.canvas.restore();
                 return;
             }
+            Thing::Pickup(pickup) => {
+                if !pickup.is_active() {
+                    return;
+                }
+
+                let (color, label) = match pickup.kind {
+                    PickupKind::Heart => ("red", "❤"),
+                    PickupKind::Speed => ("orange", "»"),
+                    PickupKind::Shield => ("blue", "◯"),
+                    PickupKind::Weapon(ability) => ("green", &ability.name()[0..1])
+                };
+
+                self.canvas.save();
+                self.canvas.set_stroke_style(color);
+                self.canvas.set_fille_style(color);
+                self.canvas.draw_circle(x, y, *thing.radius() as f64);
+                self.canvas.set_text_style("center", "middle");
+                self.canvas.fill_text(label, x, y);
+                self.canvas.restore();
+                return;
+            }
+            _ => {}
         }
 
         self.canvas.draw_circle(x, y, *thing.radius() as f64);
@@ -206,6 +337,15 @@ impl App {
             self.canvas.stroke();
         }
 
+        if self.current.mode == GameMode::CaptureTheFlag {
+            for zone in &self.current.map.capture_zones {
+                self.canvas.save();
+                self.canvas.set_stroke_style(zone.team.color());
+                self.canvas.draw_circle(zone.pos.x as f64, zone.pos.y as f64, zone.radius as f64);
+                self.canvas.restore();
+            }
+        }
+
         if DEV_SHOW_SPAWNPOINTS {
             for spawn in &self.current.map.spawn_points {
                 self.canvas.draw_circle(spawn.x as f64, spawn.y as f64, 0.1);
@@ -213,18 +353,35 @@ impl App {
         }
     }
 
+    /// true if the game is shown, either playing or watching a replay
+    fn shows_game(&self) -> bool {
+        self.app_state == AppState::InGame || self.app_state == AppState::Replay
+    }
+
     fn draw_game(&self) {
-        if self.app_state != AppState::InGame {
+        if !self.shows_game() {
             return;
         }
 
-        for (id, thing) in &self.current.things {
+        for (id, thing) in self.current.things.iter() {
 
-            if thing.no_interpolate() {
-                self.draw_thing(thing, *thing.pos());
+            // the thing of the player is predicted, not interpolated
+            if Some(id) == self.input.thing_id {
+                self.draw_thing(thing, self.prediction.smoothed_pos(*thing.pos()));
                 continue;
             }
 
+            // and so is a flag carried by the player
+            if let Thing::Flag(flag) = thing {
+                if let (Some(carrier), Some(thing_id)) = (flag.carrier, self.input.thing_id) {
+                    if carrier == thing_id {
+                        if let Some(carrier) = self.current.things.get(carrier) {
+                            self.draw_thing(thing, self.prediction.smoothed_pos(*carrier.pos()));
+                            continue;
+                        }
+                    }
+                }
+            }
 
             if let Some(prev) = self.history.prev().things.get(id) {
                 self.draw_thing(thing, thing.lerp_pos(prev, self.lerp_alpha));
@@ -243,9 +400,9 @@ impl App {
             }
 
         }
-        for (id, thing) in &self.current.things {
-            if thing.no_interpolate() {
-                self.draw_thing_name(thing, *thing.pos());
+        for (id, thing) in self.current.things.iter() {
+            if Some(id) == self.input.thing_id {
+                self.draw_thing_name(thing, self.prediction.smoothed_pos(*thing.pos()));
                 continue;
             }
 
@@ -261,6 +418,33 @@ impl App {
         }
     }
 
+    /// the interpolated position of the thing followed when spectating or waiting to respawn
+    fn follow_pos(&self) -> Option<Vec2> {
+        let id = self.follow?;
+        let thing = self.current.things.get(id)?;
+        match self.history.prev().things.get(id) {
+            Some(prev) => Some(thing.lerp_pos(prev, self.lerp_alpha)),
+            None => Some(*thing.pos())
+        }
+    }
+
+    /// follows the next or previous player alive, going through the whole map in between the first and last player
+    fn cycle_follow(&mut self, forward: bool) {
+        let mut ids: Vec<Option<Index>> = vec![None];
+        for (id, thing) in self.current.things.iter() {
+            if let Thing::Player(player) = thing {
+                if player.is_alive() {
+                    ids.push(Some(id));
+                }
+            }
+        }
+
+        let i = ids.iter().position(|id| *id == self.follow).unwrap_or(0);
+        let len = ids.len();
+        let next = if forward { (i + 1) % len } else { (i + len - 1) % len };
+        self.follow = ids[next];
+    }
+
     fn is_my_thing_alive(&self) -> bool {
         if let Some(thing_id) = self.input.thing_id {
             for (id, thing) in self.current.things.iter() {
@@ -276,12 +460,40 @@ impl App {
     }
 
     fn draw_ui_gameui(&self, cx: f64) {
-        if self.app_state != AppState::InGame {
+        if !self.shows_game() {
             return;
         }
 
         self.canvas.set_text_style("left", "middle");
 
+        if let Some(playback) = &self.replay {
+            self.draw_ui_replay(playback, cx);
+        }
+
+        if let Some((notice, _)) = &self.notice {
+            self.canvas.save();
+            self.canvas.set_text_style("center", "middle");
+            self.canvas.fill_text(notice, cx, 2.0);
+            self.canvas.restore();
+        }
+
+        self.draw_ui_chat();
+
+        if self.spectator {
+            let following = match self.follow.and_then(|id| self.current.things.get(id)) {
+                Some(thing) => format!("Spectating {}", thing.name()),
+                None => "Spectating".into()
+            };
+            self.canvas.fill_text(&following, 0.5, 1.0);
+            self.canvas.fill_text("Use left and right to follow a player, V to play", 0.5, 2.0);
+        } else if self.app_state == AppState::InGame && self.input.thing_id.is_some() && !self.is_my_thing_alive() {
+            let following = match self.follow.and_then(|id| self.current.things.get(id)) {
+                Some(thing) => format!("Following {} until you respawn", thing.name()),
+                None => "Use left and right to follow a player until you respawn".into()
+            };
+            self.canvas.fill_text(&following, 0.5, 2.0);
+        }
+
         if let Some(thing_id) = self.input.thing_id {
             if let Some(thing) = self.current.things.get(thing_id) {
                 if let Thing::Player(player) = thing {
@@ -291,31 +503,79 @@ impl App {
                     }
                     self.canvas
                         .fill_text(hearts.as_str(), 0.5, 1.0);
+
+                    let ability = player.ability;
+                    let ammo = match player.ammo(ability) {
+                        Some(ammo) => format!("{}", ammo),
+                        None => "∞".into()
+                    };
+                    let charge = match player.charge {
+                        Some(charge) => format!(" {:0.0}%", charge / ability.stats().charge_sec * 100.0),
+                        None => String::new()
+                    };
+                    self.canvas
+                        .fill_text(&format!("{} {}{}", ability.name(), ammo, charge), 0.5, 2.0);
+
+                    let mut powerups = Vec::new();
+                    if player.speed_boost > 0.0 {
+                        powerups.push(format!("Speed {:0.0}", player.speed_boost.ceil()));
+                    }
+                    if player.shield > 0.0 {
+                        powerups.push(format!("Shield {:0.0}", player.shield.ceil()));
+                    }
+                    self.canvas
+                        .fill_text(&powerups.join(" "), 0.5, 3.0);
                 }
             }
         }
 
-        let show_score = self.show_score;
+        let intermission = self.current.phase == MatchPhase::Intermission;
+        let show_score = self.show_score || intermission;
         
         if show_score {
             let mut y = 3.0;
             self.canvas.save();
             self.canvas.set_text_style("center", "middle");
-            self.canvas.fill_text("Score", cx as f64, y);
+            self.canvas.fill_text(if intermission { "Final Score" } else { "Score" }, cx as f64, y);
             y += 2.0;
+
+            // team scores first, with the players sorted by team
+            let team_based = self.current.mode.is_team_based();
+            if team_based {
+                let unit = if self.current.mode == GameMode::CaptureTheFlag { "captures" } else { "kills" };
+                for team in Team::ALL.iter() {
+                    self.canvas.set_fille_style(team.color());
+                    self.canvas.set_text_style("right", "middle");
+                    self.canvas.fill_text(team.name(), cx as f64 - 1.0, y);
+                    self.canvas.fill_text(&format!("{}", self.current.team_scores[team.index()]), cx + 1.0, y);
+                    self.canvas.set_text_style("left", "middle");
+                    self.canvas.fill_text(unit, cx + 1.25, y);
+                    y += 1.0;
+                }
+
+                y += 1.0;
+            }
+
             let mut scores = Vec::new();
             for (_, thing) in self.current.things.iter() {
                 if let Thing::Player(player) = thing {
-                    scores.push((thing.name().clone(), player.kills.clone()));
+                    scores.push((thing.name().to_string(), player.kills, player.team));
                 }
             }
 
+            // players which left during the round are still on the scoreboard
+            for departed in self.current.departed.iter() {
+                scores.push((format!("{} (left)", departed.name), departed.kills, departed.team));
+            }
+
             scores.sort_by(|a, b| {
-                b.1.cmp(&a.1)
+                let team = |t:&Option<Team>| t.map(|t| t.index()).unwrap_or(0);
+                team(&a.2).cmp(&team(&b.2)).then(b.1.cmp(&a.1))
             });
 
-            for (name, kills) in scores {
+            for (name, kills, team) in scores {
                 let space = 1.0;
+                self.canvas.set_fille_style(team.map(|t| t.color()).unwrap_or("black"));
                 self.canvas.set_text_style("right", "middle");
                 self.canvas.fill_text(&name, cx as f64 - space, y);
 
@@ -331,12 +591,93 @@ impl App {
         }
 
         let y = 3.0;
-        if self.current.warmup && self.updates % 60 > 30 {
-            self.canvas.set_text_style("center", "middle");
-            self.canvas.fill_text("Warmup with bots, awaiting more players to join...", cx, y);
+        match self.current.phase {
+            MatchPhase::Warmup => {
+                if self.updates % 60 > 30 {
+                    self.canvas.set_text_style("center", "middle");
+                    self.canvas.fill_text("Warmup with bots, awaiting more players to join...", cx, y);
+                }
+            }
+            MatchPhase::Round => {
+                let timer = self.current.phase_timer.max(0.0) as u32;
+                self.canvas.set_text_style("center", "middle");
+                self.canvas.fill_text(&format!("{}:{:02}", timer / 60, timer % 60), cx, 1.0);
+                self.canvas.set_text_style("right", "middle");
+                let goal = match self.current.mode {
+                    GameMode::Deathmatch => format!("First to {} kills", self.current.score_limit),
+                    GameMode::TeamDeathmatch => format!("First team to {} kills", self.current.score_limit),
+                    GameMode::CaptureTheFlag => format!("First team to {} captures", self.current.score_limit)
+                };
+                self.canvas.fill_text(&goal, cx * 2.0 - 0.5, 1.0);
+            }
+            MatchPhase::Intermission => {
+                self.canvas.set_text_style("center", "middle");
+                self.canvas.fill_text(&format!("Next round in {:0.0}...", self.current.phase_timer.max(0.0).ceil()), cx, 1.0);
+            }
         }
     }
 
+    /// the chat log in the bottom left, with the message being typed below it
+    /// lines fade out as they get older, unless chatting
+    fn draw_ui_chat(&self) {
+        let mut y = self.canvas.height() as f64 / 16.0 - 1.0;
+        self.canvas.save();
+        self.canvas.set_text_style("left", "middle");
+        if let Some(input) = &self.chat_input {
+            let prompt = if input.team_only { "Say to team:" } else { "Say:" };
+            let cursor = if self.updates % 60 > 30 { "|" } else { " " };
+            self.canvas.set_fille_style("black");
+            self.canvas.fill_text(&format!("{} {}{}", prompt, input.text, cursor), 0.5, y);
+        }
+
+        y -= 1.0;
+        for line in self.chat_log.iter().rev() {
+            let alpha = if self.chat_input.is_some() { 1.0 } else { line.alpha() };
+            // older lines have faded out as well
+            if alpha <= 0.0 {
+                break;
+            }
+
+            self.canvas.set_global_alpha(alpha as f64);
+            self.canvas.set_fille_style(line.color);
+            self.canvas.fill_text(&line.text, 0.5, y);
+            y -= 1.0;
+        }
+        self.canvas.restore();
+    }
+
+    /// the position and speed of the replay, with a bar showing how far it has been played
+    fn draw_ui_replay(&self, playback: &ReplayPlayback, cx: f64) {
+        let replay = playback.player.replay();
+        let time = |pos: usize| {
+            let secs = (pos as f64 * replay.delta()) as u32;
+            format!("{}:{:02}", secs / 60, secs % 60)
+        };
+        let following = match self.follow.and_then(|id| self.current.things.get(id)) {
+            Some(thing) => format!(", following {}", thing.name()),
+            None => String::new()
+        };
+        let status = format!("Replay {} / {} x{}{}{}",
+            time(playback.player.pos()),
+            time(playback.player.end()),
+            playback.speed,
+            if playback.paused { ", paused" } else { "" },
+            following);
+
+        let bottom = self.canvas.height() as f64 / 16.0;
+        self.canvas.fill_text(&status, 0.5, bottom - 3.0);
+        self.canvas.fill_text("Space to pause, left and right to seek, up and down to change speed, F to follow a player", 0.5, bottom - 2.0);
+
+        let width = cx * 2.0 - 1.0;
+        let played = playback.player.pos() as f64 / playback.player.end().max(1) as f64;
+        self.canvas.save();
+        self.canvas.set_fille_style("gray");
+        self.canvas.fill_rect(0.5, bottom - 1.0, width, 0.25);
+        self.canvas.set_fille_style("black");
+        self.canvas.fill_rect(0.5, bottom - 1.0, width * played, 0.25);
+        self.canvas.restore();
+    }
+
     fn draw_ui_centercontent(&self, cx: f64, cy: f64) {
         self.canvas.set_text_style("center", "middle");
         match &self.app_state {
@@ -379,6 +720,7 @@ impl App {
 
                 self.canvas.fill_text(name.as_str(), cx, cy + 1.0);
             }
+            AppState::Replay => {}
             AppState::InGame {} => {
                 if let Some(thing_id) = self.input.thing_id {
                     if let Some(thing) = self.current.things.get(thing_id) {
@@ -432,37 +774,49 @@ impl App {
     pub fn recv_custom(&mut self, msg: CustomMsg) {
         match msg {
             CustomMsg::ServerSnapshotFull {
+                seq,
                 state,
-                input_timestamp_sec,
+                input_seq,
             } => {
-                self.server_tick_rate = 1.0 / self.since_last_snapshot_sec;
-                self.since_last_snapshot_sec = 0.0;
-                self.history.remember(state.clone());
-                self.current = state;
-                let inputs = self.input_history.clone();
-                self.input_history.clear();
-                for input in inputs {
-                    if input.timestamp_sec > input_timestamp_sec {
-                        apply_input(&mut self.current, &input, false);
-                        self.input_history.push_back(input);
-                    }
-                }
+                self.recv_snapshot(seq, input_seq, state);
             }
             CustomMsg::ServerSnapshotDelta {
+                seq,
+                baseline_seq,
                 delta,
-                input_timestamp_sec,
+                input_seq,
             } => {
-                let state = State::from_delta_bincode(self.history.current(), &delta);
-                let state = state.expect("Failed to deserialize from delta!");
-                self.recv_custom(CustomMsg::ServerSnapshotFull {
-                    input_timestamp_sec,
-                    state,
-                });
+                if self.is_stale_snapshot(seq) {
+                    return;
+                }
+
+                let baseline = match self.snapshots.get(baseline_seq) {
+                    Some(baseline) => baseline,
+                    None => {
+                        warn!("Snapshot {} is based on unknown snapshot {}", seq, baseline_seq);
+                        return;
+                    }
+                };
+
+                match StateDelta::decode(&delta).and_then(|delta| delta.apply(baseline)) {
+                    Some(state) => {
+                        self.recv_snapshot(seq, input_seq, state);
+                    }
+                    None => {
+                        error!("Failed to deserialize snapshot {} from delta", seq);
+                    }
+                }
             }
             CustomMsg::ServerPlayerInfo {
                 thing_id,
                 tick_rate:_,
+                spectator,
             } => {
+                if spectator != self.spectator {
+                    self.prediction.clear();
+                    self.follow = None;
+                }
+                self.spectator = spectator;
                 self.input.thing_id = thing_id;
                 if let Some(thing_id) = thing_id {
                     if let Some(thing) = self.current.things.get(thing_id) {
@@ -470,10 +824,122 @@ impl App {
                     }
                 }
             }
+            CustomMsg::ServerBroadcast { text } => {
+                self.notice = Some((format!("Admin: {}", text), NOTICE_SEC));
+            }
+            CustomMsg::ServerAdminResult { ok, message } => {
+                if !ok {
+                    warn!("Admin: {}", message);
+                }
+                self.push_chat_line(message, if ok { "gray" } else { "red" });
+            }
+            CustomMsg::ServerChat { from, team, team_only, text } => {
+                let text = format!("{}{}: {}", if team_only { "(team) " } else { "" }, from, text);
+                self.push_chat_line(text, team.map(|t| t.color()).unwrap_or("black"));
+            }
+            CustomMsg::ServerChatRejected { reason } => {
+                self.push_chat_line(reason, "gray");
+            }
+            CustomMsg::ServerKicked { reason } => {
+                warn!("Kicked from server: {}", reason);
+                self.input.thing_id = None;
+                self.prediction.clear();
+                self.connection_status = format!("Kicked: {}", reason);
+                self.new_app_state(AppState::Initial);
+            }
             _ => {}
         }
     }
 
+    fn push_chat_line(&mut self, text: String, color: &'static str) {
+        self.chat_log.push_back(ChatLine {
+            text,
+            color,
+            age: 0.0,
+        });
+        while self.chat_log.len() > CHAT_LOG_SIZE {
+            self.chat_log.pop_front();
+        }
+    }
+
+    /// sends the message typed, messages starting with / are admin commands, e.g. /login <password> or /kick <name>
+    fn send_chat(&mut self, input: ChatInput) {
+        let text = match sanitize_chat(&input.text) {
+            Some(text) => text,
+            None => return
+        };
+
+        if let Some(command) = text.strip_prefix('/') {
+            match command.strip_prefix("login ") {
+                Some(password) => self.send_custom(CustomMsg::ClientAdminLogin {
+                    password: password.trim().into(),
+                }),
+                None => match AdminCommand::parse(command) {
+                    Ok(command) => self.send_custom(CustomMsg::ClientAdminCommand { command }),
+                    Err(err) => self.push_chat_line(err, "red"),
+                },
+            }
+            return;
+        }
+
+        self.send_custom(CustomMsg::ClientChat {
+            text,
+            team_only: input.team_only,
+        });
+    }
+
+    /// types the message while chatting, enter sends it and escape cancels it
+    fn keydown_chat(&mut self, key: &str) {
+        let input = match &mut self.chat_input {
+            Some(input) => input,
+            None => return
+        };
+
+        match key {
+            "Enter" => {
+                if let Some(input) = self.chat_input.take() {
+                    self.send_chat(input);
+                }
+            }
+            "Escape" => self.chat_input = None,
+            "Backspace" => {
+                input.text.pop();
+            }
+            _ => {
+                if key.chars().count() == 1 && input.text.chars().count() < MAX_CHAT_LENGTH {
+                    input.text += key;
+                }
+            }
+        }
+    }
+
+    /// true if a newer snapshot than the one given has already been received
+    fn is_stale_snapshot(&self, seq: u32) -> bool {
+        match self.snapshots.latest_seq() {
+            Some(latest) => seq <= latest,
+            None => false,
+        }
+    }
+
+    /// remembers the snapshot, acknowledges it and reconciles it with the prediction
+    fn recv_snapshot(&mut self, seq: u32, input_seq: u32, state: State) {
+        // snapshots arriving out of order are older than what is already shown, ignore them
+        if self.is_stale_snapshot(seq) {
+            return;
+        }
+
+        self.snapshots.push(seq, state.clone());
+        self.send_custom(CustomMsg::ClientSnapshotAck { seq });
+
+        self.server_tick_rate = 1.0 / self.since_last_snapshot_sec;
+        self.since_last_snapshot_sec = 0.0;
+        self.history.remember(state.clone());
+        let thing_id = self.input.thing_id;
+        let predicted_pos = thing_id.and_then(|id| self.current.things.get(id)).map(|thing| *thing.pos());
+        self.current = state;
+        self.prediction.reconcile(&mut self.current, input_seq, thing_id, predicted_pos);
+    }
+
     pub fn recv(&mut self, msg: &ServerMsg) {
         match msg {
             ServerMsg::JoinedLobby {} => {
@@ -488,6 +954,7 @@ impl App {
             }
             ServerMsg::JoinedInstance { instance } => {
                 self.connection_status = format!("✓ Joined server {} ✓ ", instance.id);
+                self.send_custom(CustomMsg::ClientIdentity { player_id: self.player_id });
                 self.new_app_state(AppState::InGame);
             }
             ServerMsg::Pong {
@@ -515,14 +982,19 @@ impl App {
     }
 
     pub fn update(&mut self, dt: f64) {
-        // process messages
+        // process messages, the server is ignored while watching a replay
         self.since_last_snapshot_sec += dt as f32;
-        for msg in &self.server_messages.clone() {
-            self.recv(msg);
+        if self.app_state != AppState::Replay {
+            for msg in &self.server_messages.clone() {
+                self.recv(msg);
+            }
         }
 
         // calculate lerp which is used to do smooth linear interpolation between things
         self.lerp_alpha = self.since_last_snapshot_sec / (1.0 / self.server_tick_rate as f32);
+        if self.app_state == AppState::Replay {
+            self.update_replay(dt);
+        }
 
         // ping server every 60 update
         if self.updates % 60 == 0 {
@@ -534,34 +1006,49 @@ impl App {
         // calculate movement and apply to local thing
         // update input with timestamp and movement data, and send to server
         self.input.timestamp_sec = performance_now_ms() / 1000.0;
+        let prev_timestamp = self.history.prev().timestamp;
+        let current_timestamp = self.history.current().timestamp;
+        self.input.view_timestamp_sec = prev_timestamp + (current_timestamp - prev_timestamp) * self.lerp_alpha.clamp(0.0, 1.0) as f64;
         self.input.movement = self.input.movement_dir * dt as f32;
 
-        // remember input for later processing
-        self.input_history.push_back(self.input.clone());
-
-        // apply input now
-        apply_input(&mut self.current, &self.input, false);
+        // apply input now and remember it until processed by the server
+        self.prediction.predict(&mut self.current, &mut self.input);
+        self.prediction.update(dt as f32);
 
         // send input to server
         self.send_custom(CustomMsg::ClientInput {
             input: self.input.clone(),
         });
 
-        // ensure player is not interpolated
-        if let Some(thing_id) = self.input.thing_id {
-            if let Some(thing) = self.current.things.get_mut(thing_id) {
-                if let Thing::Player(player) = thing {
-                    player.no_interpolation = true;
-                }
-            }
-        }
-
         // if in lobby, refresh list of servers each 60th update
         if self.app_state == AppState::InLobby && self.updates % 60 == 0 {
             self.send(ClientMsg::RefreshInstances {
             });
         }
 
+        for line in self.chat_log.iter_mut() {
+            line.age += dt as f32;
+        }
+
+        if let Some((_, sec)) = &mut self.notice {
+            *sec -= dt as f32;
+            if *sec <= 0.0 {
+                self.notice = None;
+            }
+        }
+
+        // stop following things which are gone or dead, or once respawned
+        if let Some(id) = self.follow {
+            let alive = match self.current.things.get(id) {
+                Some(Thing::Player(player)) => player.is_alive(),
+                _ => false
+            };
+            let respawned = self.app_state == AppState::InGame && !self.spectator && self.is_my_thing_alive();
+            if !alive || respawned {
+                self.follow = None;
+            }
+        }
+
         // process events
         for e in self.current.events.drain(..) {
             match e {
@@ -593,9 +1080,39 @@ impl App {
                         radius: 0.25,
                     }));
                 },
+                crate::Event::ProjectileBounced { pos, normal } => {
+                    self.effects.insert(Effect::Smoke(Smoke {
+                        pos,
+                        time: 0.0,
+                        end_time: 0.2,
+                        vel: normal * 2.0,
+                        radius: 0.1,
+                    }));
+                },
                 crate::Event::ProjectileFired { pos:_ } => {
                     play_sound("sfx/laser2.ogg");
                 },
+                crate::Event::PickupCollected { thing_id:_, kind:_, pos } => {
+                    self.effects.insert(Effect::Smoke(Smoke {
+                        pos,
+                        time: 0.0,
+                        end_time: 0.25,
+                        vel: Vec2::default(),
+                        radius: 0.5,
+                    }));
+                    play_sound("sfx/pickup.wav");
+                },
+                crate::Event::PickupExpired { thing_id, kind:_ } => {
+                    if Some(thing_id) == self.input.thing_id {
+                        play_sound("sfx/expire.wav");
+                    }
+                },
+                crate::Event::FlagTaken { team:_, thing_id:_ } | crate::Event::FlagCaptured { team:_, thing_id:_ } => {
+                    play_sound("sfx/pickup.wav");
+                },
+                crate::Event::FlagDropped { team:_, pos:_ } | crate::Event::FlagReturned { team:_ } => {
+                    play_sound("sfx/expire.wav");
+                },
                 
             }
         }
@@ -637,6 +1154,97 @@ impl App {
         self.updates += 1;
     }
 
+    /// plays back the replay from the start, replacing whatever is shown
+    pub fn load_replay(&mut self, data: &[u8]) {
+        let player = match Replay::decode(data).and_then(ReplayPlayer::new) {
+            Ok(player) => player,
+            Err(err) => {
+                error!("Failed to load replay: {}", err);
+                return;
+            }
+        };
+
+        self.input.thing_id = None;
+        self.prediction.clear();
+        self.spectator = false;
+        self.follow = None;
+        self.chat_input = None;
+        self.chat_log.clear();
+        self.replay = Some(ReplayPlayback {
+            player,
+            paused: false,
+            speed: 1.0,
+            time: 0.0,
+        });
+        self.new_app_state(AppState::Replay);
+        self.show_replay_state();
+    }
+
+    /// advances the replay by the time passed, taking the events of every frame played
+    fn update_replay(&mut self, dt: f64) {
+        let playback = match &mut self.replay {
+            Some(playback) => playback,
+            None => return
+        };
+
+        let delta = playback.player.replay().delta();
+        if !playback.paused {
+            playback.time += dt * playback.speed as f64;
+        }
+
+        let mut events = Vec::new();
+        while playback.time >= delta {
+            playback.time -= delta;
+            match playback.player.step() {
+                Ok(true) => {
+                    let state = playback.player.state().clone();
+                    events.extend(state.events.iter().cloned());
+                    self.history.remember(state.clone());
+                    self.current = state;
+                }
+                Ok(false) => {
+                    playback.paused = true;
+                    playback.time = 0.0;
+                }
+                Err(err) => {
+                    error!("Failed to play replay: {}", err);
+                    playback.paused = true;
+                    playback.time = 0.0;
+                }
+            }
+        }
+
+        self.current.events = events;
+        self.lerp_alpha = (playback.time / delta) as f32;
+    }
+
+    /// jumps forward or backward in the replay
+    fn seek_replay(&mut self, secs: f64) {
+        if let Some(playback) = &mut self.replay {
+            let ticks = (secs / playback.player.replay().delta()) as i64;
+            let pos = (playback.player.pos() as i64 + ticks).max(0) as usize;
+            if let Err(err) = playback.player.seek(pos) {
+                error!("Failed to seek replay: {}", err);
+            }
+            playback.time = 0.0;
+        }
+
+        self.show_replay_state();
+    }
+
+    /// shows the state of the replay without interpolating from what was shown before
+    fn show_replay_state(&mut self) {
+        if let Some(playback) = &self.replay {
+            let mut state = playback.player.state().clone();
+            state.events.clear();
+            self.history.clear();
+            self.history.remember(state.clone());
+            self.history.remember(state.clone());
+            self.current = state;
+            self.effects.clear();
+        }
+    }
+
     pub fn keyup(&mut self, code: KeyCode, _key: &str) {
         match &self.app_state {
             AppState::InGame {} => {
@@ -662,6 +1270,11 @@ impl App {
                     self.show_score = false;
                 }
             }
+            AppState::Replay => {
+                if code == 9 {
+                    self.show_score = false;
+                }
+            }
             _ => {}
         };
     }
@@ -716,6 +1329,23 @@ impl App {
                 }
             }
             AppState::InGame {} => {
+                // while chatting, keys are typed instead of playing
+                if self.chat_input.is_some() {
+                    self.keydown_chat(key);
+                    return;
+                }
+
+                // t or enter chats with everyone, y with the team if the game mode is team based
+                if code == 84 || code == 89 || key == "Enter" {
+                    self.chat_input = Some(ChatInput {
+                        text: String::new(),
+                        team_only: code == 89 && self.current.mode.is_team_based(),
+                    });
+                    self.input.movement_dir = Vec2::ZERO;
+                    self.input.ability_trigger = false;
+                    return;
+                }
+
                 let i = &mut self.input;
                 if code == 87 {
                     i.movement_dir.y = -1.0;
@@ -737,6 +1367,45 @@ impl App {
                 if code == 9 {
                     self.show_score = true;
                 }
+
+                // keys 1..4 switch ability
+                if code >= 49 && code < 49 + Ability::ALL.len() as u32 {
+                    i.ability = Ability::ALL[(code - 49) as usize];
+                }
+
+                // v switches between playing and spectating
+                if code == 86 {
+                    let spectate = !self.spectator;
+                    self.send_custom(CustomMsg::ClientSpectate { spectate });
+                }
+
+                // left and right cycle the player followed when spectating or waiting to respawn
+                if (self.spectator || !self.is_my_thing_alive()) && (code == 37 || code == 39) {
+                    self.cycle_follow(code == 39);
+                }
+            }
+            AppState::Replay => {
+                if code == 9 {
+                    self.show_score = true;
+                }
+                if code == 70 {
+                    self.cycle_follow(true);
+                }
+                if code == 37 || code == 39 {
+                    self.seek_replay(if code == 39 { REPLAY_SEEK_SEC } else { -REPLAY_SEEK_SEC });
+                }
+
+                if let Some(playback) = &mut self.replay {
+                    if code == 32 {
+                        playback.paused = !playback.paused;
+                    }
+                    if code == 38 {
+                        playback.speed = (playback.speed * 2.0).min(REPLAY_MAX_SPEED);
+                    }
+                    if code == 40 {
+                        playback.speed = (playback.speed / 2.0).max(REPLAY_MIN_SPEED);
+                    }
+                }
             }
             _ => {}
         };
@@ -812,7 +1481,14 @@ impl App {
     }
 
     pub fn connected(&mut self) {
+        if self.app_state == AppState::Replay {
+            self.connection_status = format!("Connected");
+            return;
+        }
+
         self.history.clear();
+        self.snapshots.clear();
+        self.prediction.clear();
         self.current = State::new();
         self.connection_status = format!("Connected");
         self.new_app_state(AppState::EnterName {
@@ -822,9 +1498,22 @@ impl App {
 
     pub fn disconnected(&mut self) {
         self.connection_status = "Trying to reconnect...".into();
-        self.new_app_state(AppState::Initial);
+        if self.app_state != AppState::Replay {
+            self.new_app_state(AppState::Initial);
+        }
     }
 }
 
 unsafe impl Send for App {}
 unsafe impl Sync for App {}
+
+/// the identity of the player, kept in the storage of the browser such that it is the same every time the game is played
+fn persistent_id() -> Uuid {
+    if let Some(id) = get_item("player_id").and_then(|id| Uuid::parse_str(&id).ok()) {
+        return id;
+    }
+
+    let id = Uuid::new_v4();
+    set_item("player_id", &id.to_string());
+    id
+}
@@ -0,0 +1,139 @@
+use std::{alloc::{GlobalAlloc, Layout, System}, sync::atomic::{AtomicUsize, Ordering}, time::{Duration, Instant}};
+
+use glam::Vec2;
+use hostess::client::Bincoded;
+use rand::Rng;
+use sample_lib::{update_things, Broadphase, SimRng, State, StateDelta, Thing};
+
+const PLAYERS:usize = 8;
+const PROJECTILES:usize = 200;
+const TICKS:u32 = 1000;
+const TICK_RATE:f64 = 20.0;
+
+/// allocator counting the number of allocations made
+struct CountingAlloc;
+
+static ALLOCATIONS:AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL:CountingAlloc = CountingAlloc;
+
+/// the measurements of a run of the benchmark
+struct BenchResult {
+    elapsed:Duration,
+    allocations:usize,
+    rle_bytes:usize,
+    delta_bytes:usize
+}
+
+/// measures the cost of a server tick with many projectiles in flight
+/// run once looking at every thing when resolving collisions, as before the broadphase grid, and once with the grid
+/// the allocations are also measured with the state cloned every tick, as update_things did before the collider view
+/// usage: bench [--grid-only]
+fn main() {
+    let grid_only = std::env::args().any(|arg| arg == "--grid-only");
+    println!("{} players, {} projectiles, {} ticks", PLAYERS, PROJECTILES, TICKS);
+
+    let brute_force = match grid_only {
+        true => None,
+        false => Some(run(true, false))
+    };
+    let grid = run(false, false);
+    let cloned = run(false, true);
+
+    if let Some(before) = &brute_force {
+        print_result("brute force", before);
+    }
+    print_result("grid", &grid);
+    if let Some(before) = &brute_force {
+        println!("grid takes {:.1}% of the time of brute force", grid.elapsed.as_secs_f64() / before.elapsed.as_secs_f64().max(f64::EPSILON) * 100.0);
+    }
+
+    let (before, after) = (cloned.allocations / TICKS as usize, grid.allocations / TICKS as usize);
+    println!("allocations per tick: {} cloning the state, {} with the collider view, {} fewer", before, after, before as i64 - after as i64);
+}
+
+fn print_result(name:&str, result:&BenchResult) {
+    println!("{}: total {:.3} ms, {:.3} ms per tick, {} allocations per tick", name,
+        result.elapsed.as_secs_f64() * 1000.0, result.elapsed.as_secs_f64() * 1000.0 / TICKS as f64, result.allocations / TICKS as usize);
+    println!("{}: snapshot rle {} bytes per tick, delta {} bytes per tick", name, result.rle_bytes / TICKS as usize, result.delta_bytes / TICKS as usize);
+}
+
+/// runs the ticks from the same seed, such that every run simulates the same
+/// with brute force, collisions are resolved against every thing instead of those in nearby cells of the grid
+/// with clone_state, the state is cloned before updating the things, as was done to resolve collisions against
+fn run(brute_force:bool, clone_state:bool) -> BenchResult {
+    let mut state = State::new();
+    state.rng = SimRng::new(1);
+    let mut rng = SimRng::new(2);
+
+    let mut players = Vec::new();
+    for i in 0..PLAYERS {
+        let mut thing = Thing::new_player(&format!("player {}", i));
+        if let Thing::Player(player) = &mut thing {
+            player.spawn_pos = state.map.spawn_points.get(i % state.map.spawn_points.len()).copied();
+        }
+        thing.spawn();
+        players.push(state.things.insert(thing));
+    }
+
+    let delta = 1.0 / TICK_RATE;
+    let mut elapsed = Duration::default();
+    let mut allocations = 0;
+    let mut snapshot = state.quantized();
+    let mut rle_bytes = 0;
+    let mut delta_bytes = 0;
+    for tick in 0..TICKS {
+        // keep the number of projectiles in flight constant
+        let projectiles = state.things.iter().filter(|(_, t)| matches!(t, Thing::Projectile(_))).count();
+        for _ in projectiles..PROJECTILES {
+            let owner = players[rng.gen_range(0..players.len())];
+            let pos = Vec2::new(rng.gen_range(2.0..state.width - 2.0), rng.gen_range(3.0..state.height - 2.0));
+            let vel = Vec2::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5).normalize_or_zero() * 20.0;
+            state.things.insert(Thing::new_projectile(pos, vel, owner));
+        }
+
+        state.events.clear();
+        state.timestamp = tick as f64 * delta;
+
+        // only the tick itself is measured, not the spawning of projectiles
+        let start = Instant::now();
+        let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+        let cloned = match clone_state {
+            true => Some(state.clone()),
+            false => None
+        };
+        let broadphase = match brute_force {
+            true => Broadphase::brute_force(&state),
+            false => Broadphase::new(&state)
+        };
+        update_things(&mut state, &broadphase, delta);
+        drop(cloned);
+        allocations += ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+        elapsed += start.elapsed();
+
+        // compare the byte level rle of bincode with the field level delta
+        let next = state.quantized();
+        rle_bytes += next.to_delta_bincode(&snapshot).len();
+        delta_bytes += StateDelta::new(&snapshot, &next).encode().len();
+        snapshot = next;
+    }
+
+    BenchResult {
+        elapsed,
+        allocations,
+        rle_bytes,
+        delta_bytes
+    }
+}
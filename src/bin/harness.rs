@@ -0,0 +1,64 @@
+use std::{fs, path::Path, process::exit};
+
+use sample_lib::{Harness, MapFile, Script, State};
+
+/// runs a scripted headless simulation and prints the state hash of each tick
+/// with --latency, the prediction error of the first player is printed instead
+/// usage: harness <script.json> [map.json] [--latency <ticks>]
+fn main() {
+    let mut args:Vec<String> = std::env::args().collect();
+    let mut latency = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--latency") {
+        latency = match args.get(i + 1).and_then(|ticks| ticks.parse::<u64>().ok()) {
+            Some(ticks) => Some(ticks),
+            None => {
+                eprintln!("--latency expects a number of ticks");
+                exit(1);
+            }
+        };
+        args.drain(i..i + 2);
+    }
+
+    if args.len() < 2 {
+        eprintln!("usage: {} <script.json> [map.json] [--latency <ticks>]", args[0]);
+        exit(1);
+    }
+
+    let script = match fs::read_to_string(&args[1]) {
+        Ok(json) => json,
+        Err(err) => {
+            eprintln!("could not read script {}: {}", args[1], err);
+            exit(1);
+        }
+    };
+
+    let script:Script = match serde_json::from_str(&script) {
+        Ok(script) => script,
+        Err(err) => {
+            eprintln!("could not parse script {}: {}", args[1], err);
+            exit(1);
+        }
+    };
+
+    let state = match args.get(2) {
+        Some(path) => match MapFile::load(Path::new(path)) {
+            Ok(map) => map.to_state(),
+            Err(err) => {
+                eprintln!("could not load map {}: {}", path, err);
+                exit(1);
+            }
+        },
+        None => State::new()
+    };
+
+    if let Some(latency) = latency {
+        let stats = Harness::measure_prediction(state, &script, latency);
+        println!("latency {} ticks each way", latency);
+        println!("{} samples, mean error {:.4}, max error {:.4}", stats.samples, stats.mean_error, stats.max_error);
+        return;
+    }
+
+    for (tick, hash) in Harness::run(state, &script).iter().enumerate() {
+        println!("{} {:016x}", tick, hash);
+    }
+}
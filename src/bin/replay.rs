@@ -0,0 +1,54 @@
+use std::{fs::File, io::BufReader, process::exit};
+
+use sample_lib::{state_hash, Replay, Thing};
+
+/// plays back a recorded replay headless, verifying the checksum of each tick
+/// prints the state hash of each tick followed by the final scores
+/// exits with 1 if the replay cannot be read or a checksum does not match
+/// usage: replay <file.replay>
+fn main() {
+    let args:Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <file.replay>", args[0]);
+        exit(1);
+    }
+
+    let file = match File::open(&args[1]) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("could not open replay {}: {}", args[1], err);
+            exit(1);
+        }
+    };
+
+    let replay = match Replay::read(BufReader::new(file)) {
+        Ok(replay) => replay,
+        Err(err) => {
+            eprintln!("could not read replay {}: {}", args[1], err);
+            exit(1);
+        }
+    };
+
+    let mut state = replay.header.initial.clone();
+    println!("{} {:016x}", 0, state_hash(&state));
+    for index in 0..replay.frames.len() {
+        state = match replay.apply_frame(index, &state) {
+            Ok(state) => state,
+            Err(err) => {
+                eprintln!("replay diverged: {}", err);
+                exit(1);
+            }
+        };
+        println!("{} {:016x}", replay.frames[index].seq, replay.frames[index].checksum);
+    }
+
+    println!("{} ticks, {:.1} seconds at {} ticks per second", replay.frames.len(), replay.duration_sec(), replay.header.tick_rate);
+    for (_, thing) in state.things.iter() {
+        if let Thing::Player(player) = thing {
+            println!("{}: {} kills, {} deaths", player.name, player.kills, player.deaths);
+        }
+    }
+    for departed in state.departed.iter() {
+        println!("{} (left): {} kills, {} deaths", departed.name, departed.kills, departed.deaths);
+    }
+}
@@ -1,45 +1,456 @@
+use std::{str::FromStr, sync::Arc};
+
 use generational_arena::Index;
 use glam::Vec2;
-use sample_lib::{State, Thing, move_thing_direct_sweep};
+use rand::{Rng, RngCore};
+use sample_lib::{Ability, GameMode, Input, NavGraph, PickupKind, PlayerThing, SimRng, State, Thing};
+
+/// how close a bot needs to get to a position on its path to move on to the next
+const WAYPOINT_REACHED:f32 = 0.5;
+
+/// how far a bot strafes when close enough to its target
+const STRAFE_DISTANCE:f32 = 3.0;
+
+/// how well bots play
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard
+}
+
+/// the parameters of a difficulty
+struct DifficultyStats {
+    /// seconds between a bot reconsidering what to do
+    reaction_sec:f64,
+    /// the most the aim of a bot is off, in radians
+    aim_error:f32,
+    /// how much of the movement of the target is predicted when aiming, from none to all of it
+    lead:f32,
+    /// the distance within which a bot fires at its target
+    range:f32,
+    /// a bot flees from its target when having this many hearts or fewer
+    flee_hearts:i8,
+    /// true if a bot switches to the ability best suited to the distance to its target
+    switch_ability:bool
+}
+
+impl Default for Difficulty {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
+impl Difficulty {
+    fn stats(&self) -> DifficultyStats {
+        match self {
+            Difficulty::Easy => DifficultyStats {
+                reaction_sec:0.8,
+                aim_error:0.35,
+                lead:0.0,
+                range:10.0,
+                flee_hearts:0,
+                switch_ability:false
+            },
+            Difficulty::Normal => DifficultyStats {
+                reaction_sec:0.4,
+                aim_error:0.15,
+                lead:0.5,
+                range:15.0,
+                flee_hearts:1,
+                switch_ability:true
+            },
+            Difficulty::Hard => DifficultyStats {
+                reaction_sec:0.15,
+                aim_error:0.04,
+                lead:1.0,
+                range:22.0,
+                flee_hearts:1,
+                switch_ability:true
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Difficulty::Easy => "Easy",
+            Difficulty::Normal => "Normal",
+            Difficulty::Hard => "Hard"
+        }
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "easy" => Ok(Difficulty::Easy),
+            "normal" => Ok(Difficulty::Normal),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(format!("unknown bot difficulty {}, expected easy, normal or hard", s))
+        }
+    }
+}
+
+/// decides what a bot does, given a read-only view of the state
+/// the input returned is applied to the thing of the bot the same way as the inputs of players
+pub trait BotBrain: Send {
+    /// returns the input of the bot for this tick
+    /// only the movement direction and the ability fields are used, the rest is filled in by the bot
+    /// any randomness is drawn from the rng of the bot, such that a simulation with bots can be reproduced
+    fn think(&mut self, thing_id: Index, state: &State, nav: &NavGraph, rng: &mut SimRng, delta: f64) -> Input;
+}
+
+/// creates the brains of bots of the given difficulty, e.g. running a script
+pub type BrainFactory = Arc<dyn Fn(Difficulty) -> Box<dyn BotBrain> + Send + Sync>;
 
 pub struct Bot {
     pub thing_id: Index,
-    pub think: f64,
-    pub dir: Vec2,
+    brain: Box<dyn BotBrain>,
+    /// seeded from the rng of the state when the bot is added
+    rng: SimRng,
 }
 
 impl Bot {
-    pub fn new(thing_id: Index) -> Self {
+    pub fn new(thing_id: Index, brain: Box<dyn BotBrain>, state_rng: &mut SimRng) -> Self {
         Self {
             thing_id,
+            brain,
+            rng: SimRng::new(state_rng.next_u64()),
+        }
+    }
+
+    /// lets the brain decide what the bot does this tick, returning the input to apply to its thing
+    /// the input is limited as if made by a player, e.g. the bot always controls its own thing and moves at most as fast as a player
+    pub fn tick(&mut self, state: &State, nav: &NavGraph, delta: f64) -> Input {
+        let mut input = self.brain.think(self.thing_id, state, nav, &mut self.rng, delta);
+        input.seq = 0;
+        input.timestamp_sec = state.timestamp;
+        input.view_timestamp_sec = state.timestamp;
+        input.thing_id = Some(self.thing_id);
+        input.movement_dir = input.movement_dir.clamp(Vec2::splat(-1.0), Vec2::splat(1.0));
+        if !input.movement_dir.is_finite() {
+            input.movement_dir = Vec2::ZERO;
+        }
+        input.movement = input.movement_dir * delta as f32;
+        input
+    }
+}
+
+/// follows paths found on the navigation graph towards a goal
+#[derive(Default)]
+pub struct PathFollower {
+    goal: Option<Vec2>,
+    /// the positions left to move through
+    path: Vec<Vec2>,
+}
+
+impl PathFollower {
+    /// finds a path from pos to the goal, unless already following a path to about the same goal
+    pub fn go_to(&mut self, nav: &NavGraph, pos: Vec2, goal: Vec2) {
+        if let Some(current) = self.goal {
+            if current.distance(goal) < WAYPOINT_REACHED && self.path.len() > 0 {
+                return;
+            }
+        }
+
+        self.goal = Some(goal);
+        self.path = nav.find_path(pos, goal).unwrap_or_default();
+    }
+
+    pub fn goal(&self) -> Option<Vec2> {
+        self.goal
+    }
+
+    /// true if there is nothing left of the path to follow
+    pub fn is_done(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    pub fn stop(&mut self) {
+        self.goal = None;
+        self.path.clear();
+    }
+
+    /// the direction to move in from pos to follow the path, zero when the goal is reached
+    /// positions on the path which can be moved to directly are skipped
+    pub fn step(&mut self, nav: &NavGraph, pos: Vec2) -> Vec2 {
+        while self.path.first().map(|p| p.distance(pos) < WAYPOINT_REACHED).unwrap_or(false) {
+            self.path.remove(0);
+        }
+        while self.path.len() > 1 && nav.is_clear(pos, self.path[1], nav.clearance) {
+            self.path.remove(0);
+        }
+
+        match self.path.first() {
+            Some(next) => (*next - pos).normalize_or_zero(),
+            None => Vec2::ZERO
+        }
+    }
+}
+
+/// the built-in brain, chasing enemies and fleeing from them using the navigation graph
+/// and aiming where the target is going to be
+pub struct PathfindingBrain {
+    difficulty: Difficulty,
+    /// seconds until the bot reconsiders what to do
+    think: f64,
+    /// the enemy chased and fired at, or fled from
+    target: Option<Index>,
+    /// the position of the target on the previous tick, used to estimate its velocity
+    target_pos: Option<Vec2>,
+    target_vel: Vec2,
+    fleeing: bool,
+    path: PathFollower,
+    /// where the bot aimed last
+    aim: Vec2,
+}
+
+impl PathfindingBrain {
+    pub fn new(difficulty: Difficulty) -> Self {
+        Self {
+            difficulty,
             think: 0.0,
-            dir: Vec2::new(0.0, 0.0),
+            target: None,
+            target_pos: None,
+            target_vel: Vec2::ZERO,
+            fleeing: false,
+            path: PathFollower::default(),
+            aim: Vec2::ZERO,
         }
     }
-    pub fn tick(&mut self, state: &mut State, delta: f64) {
+}
+
+impl BotBrain for PathfindingBrain {
+    fn think(&mut self, thing_id: Index, state: &State, nav: &NavGraph, rng: &mut SimRng, delta: f64) -> Input {
+        let mut input = Input::default();
+        let me = match state.things.get(thing_id) {
+            Some(Thing::Player(player)) if player.is_alive() => player,
+            _ => {
+                self.path.stop();
+                self.target = None;
+                return input;
+            }
+        };
+
+        let stats = self.difficulty.stats();
+        self.track_target(state, delta);
+
+        self.think -= delta;
         if self.think <= 0.0 {
-            if self.dir.length() == 0.0 {
-                self.dir = Vec2::new(rand::random::<f32>() - 0.5, rand::random::<f32>() - 0.5).normalize();
-            } else {
-                self.dir = Vec2::new(0.0, 0.0);
-            }
-            self.think = rand::random::<f64>() * 0.5 + 0.5;
-        }
-
-        // how to avoid clone?
-        let cloned = state.clone();
-        if let Some(thing) = state.things.get_mut(self.thing_id) {
-            if let Thing::Player(player) = thing {
-                if player.is_alive() {
-                    let v = self.dir;
-                    let v = v * player.speed * delta as f32;
-                    let new_pos = player.pos + v;
-                    move_thing_direct_sweep((self.thing_id, thing), new_pos, &cloned, None);
+            self.think = stats.reaction_sec * rng.gen_range(0.75..1.25);
+            self.target = find_target(state, nav, thing_id, me);
+            self.fleeing = self.target.is_some() && me.hearts <= stats.flee_hearts;
+            match self.goal(state, nav, thing_id, me, &stats, rng) {
+                Some(goal) => self.path.go_to(nav, me.pos, goal),
+                None => self.path.stop()
+            }
+        }
+
+        input.movement_dir = self.path.step(nav, me.pos);
+
+        // aim at the target if it can be seen and fire away
+        input.ability = me.ability;
+        input.ability_target = self.aim;
+        let target = self.target.and_then(|id| match state.things.get(id) {
+            Some(Thing::Player(target)) if target.is_alive() => Some(target.pos),
+            _ => None
+        });
+        if let Some(pos) = target {
+            let distance = me.pos.distance(pos);
+            if distance <= stats.range && nav.is_clear(me.pos, pos, 0.0) {
+                if stats.switch_ability {
+                    input.ability = choose_ability(me, distance);
                 }
+
+                let speed = input.ability.stats().speed;
+                let aim = lead(me.pos, pos, self.target_vel * stats.lead, speed) - me.pos;
+                let error = (rng.gen::<f32>() - 0.5) * 2.0 * stats.aim_error;
+                let (sin, cos) = error.sin_cos();
+                self.aim = me.pos + Vec2::new(aim.x * cos - aim.y * sin, aim.x * sin + aim.y * cos);
+                input.ability_target = self.aim;
+
+                // abilities which charge are held until fully charged and then released
+                let charge_sec = input.ability.stats().charge_sec;
+                input.ability_trigger = charge_sec == 0.0 || me.charge.map(|charge| charge < charge_sec).unwrap_or(true);
             }
-           
         }
 
-        self.think -= delta;
+        input
+    }
+}
+
+impl PathfindingBrain {
+    /// estimates the velocity of the target from its movement since the previous tick
+    fn track_target(&mut self, state: &State, delta: f64) {
+        let pos = self.target.and_then(|id| state.things.get(id)).map(|thing| *thing.pos());
+        match (pos, self.target_pos) {
+            (Some(pos), Some(prev)) if delta > 0.0 => {
+                let vel = (pos - prev) / delta as f32;
+                // a target moving further than possible in a tick has respawned
+                self.target_vel = if vel.length() < 50.0 { self.target_vel.lerp(vel, 0.5) } else { Vec2::ZERO };
+            }
+            _ => {
+                self.target_vel = Vec2::ZERO;
+            }
+        }
+
+        self.target_pos = pos;
+    }
+
+    /// where the bot wants to go
+    fn goal(&self, state: &State, nav: &NavGraph, thing_id: Index, me: &PlayerThing, stats: &DifficultyStats, rng: &mut SimRng) -> Option<Vec2> {
+        let target = self.target.and_then(|id| state.things.get(id)).map(|thing| *thing.pos());
+        if let Some(target) = target {
+            // run to the place furthest from the target, preferring hearts
+            if self.fleeing {
+                let hearts = state.things.iter().filter_map(|(_, thing)| match thing {
+                    Thing::Pickup(pickup) if pickup.is_active() && pickup.kind == PickupKind::Heart => Some(pickup.pos),
+                    _ => None
+                });
+                let safety = |pos: &Vec2| pos.distance(target) - pos.distance(me.pos) * 0.5;
+                return hearts
+                    .chain(state.map.spawn_points.iter().copied())
+                    .max_by(|a, b| safety(a).total_cmp(&safety(b)));
+            }
+        }
+
+        if state.mode == GameMode::CaptureTheFlag {
+            if let Some(objective) = objective(state, thing_id, me) {
+                return Some(objective);
+            }
+        }
+
+        if let Some(target) = target {
+            // close enough, strafe around the target instead of running into it
+            let distance = me.pos.distance(target);
+            if distance < stats.range * 0.5 && nav.is_clear(me.pos, target, 0.0) {
+                let side = if rng.gen::<bool>() { 1.0 } else { -1.0 };
+                let perp = (target - me.pos).normalize_or_zero().perp() * side;
+                return Some(me.pos + perp * STRAFE_DISTANCE);
+            }
+
+            return Some(target);
+        }
+
+        // nothing to chase, wander to a pickup or a spawn point
+        if !self.path.is_done() {
+            return self.path.goal();
+        }
+
+        let pickups:Vec<Vec2> = state.things.iter().filter_map(|(_, thing)| match thing {
+            Thing::Pickup(pickup) if pickup.is_active() => Some(pickup.pos),
+            _ => None
+        }).collect();
+        if pickups.len() > 0 && rng.gen::<bool>() {
+            return Some(pickups[rng.gen_range(0..pickups.len())]);
+        }
+
+        let spawn_points = &state.map.spawn_points;
+        if spawn_points.len() > 0 {
+            return Some(spawn_points[rng.gen_range(0..spawn_points.len())]);
+        }
+
+        None
+    }
+}
+
+/// where to go when capturing the flag
+/// to the capture zone when carrying the flag of the other team, otherwise to that flag unless a team mate has it
+fn objective(state: &State, thing_id: Index, me: &PlayerThing) -> Option<Vec2> {
+    let team = me.team?;
+    for (_, thing) in state.things.iter() {
+        if let Thing::Flag(flag) = thing {
+            if flag.team == team {
+                continue;
+            }
+
+            if flag.carrier == Some(thing_id) {
+                return state.map.capture_zones.iter().find(|z| z.team == team).map(|z| z.pos);
+            }
+
+            if flag.carrier == None {
+                return Some(flag.pos);
+            }
+        }
+    }
+
+    None
+}
+
+/// the closest enemy, preferring those which can be seen
+fn find_target(state: &State, nav: &NavGraph, thing_id: Index, me: &PlayerThing) -> Option<Index> {
+    let score = |pos: Vec2| {
+        let distance = me.pos.distance(pos);
+        if nav.is_clear(me.pos, pos, 0.0) { distance } else { distance + 10.0 }
+    };
+
+    state.things.iter().filter_map(|(id, thing)| match thing {
+        Thing::Player(player) if id != thing_id && player.is_alive() => {
+            let friendly = state.mode.is_team_based() && player.team.is_some() && player.team == me.team;
+            if friendly { None } else { Some((id, score(player.pos))) }
+        }
+        _ => None
+    }).min_by(|a, b| a.1.total_cmp(&b.1)).map(|(id, _)| id)
+}
+
+/// the ability best suited to the distance to the target, out of those with ammo left
+fn choose_ability(me: &PlayerThing, distance: f32) -> Ability {
+    // do not waste a charge
+    if me.charge.is_some() {
+        return me.ability;
+    }
+
+    if distance < 4.0 && me.has_ammo(Ability::Shotgun) {
+        Ability::Shotgun
+    } else if distance > 10.0 && me.has_ammo(Ability::Rail) {
+        Ability::Rail
+    } else if me.has_ammo(Ability::Bouncer) {
+        Ability::Bouncer
+    } else {
+        Ability::Blaster
+    }
+}
+
+/// where to aim from the origin to hit a target at pos moving with vel, using a projectile with the speed
+fn lead(origin: Vec2, pos: Vec2, vel: Vec2, speed: f32) -> Vec2 {
+    let d = pos - origin;
+    let a = vel.dot(vel) - speed * speed;
+    let b = 2.0 * d.dot(vel);
+    let c = d.dot(d);
+
+    // the target is faster than the projectile, aim straight at it
+    if a >= 0.0 {
+        return pos;
+    }
+
+    // as a is negative and c positive, this is the single positive time of impact
+    let t = (-b - (b * b - 4.0 * a * c).sqrt()) / (2.0 * a);
+    pos + vel * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lead_hits_a_target_moving_in_a_straight_line() {
+        let origin = Vec2::new(5.0, 5.0);
+        let speed = 20.0;
+        for (pos, vel) in [(Vec2::new(25.0, 5.0), Vec2::new(0.0, 7.5)), (Vec2::new(5.0, 30.0), Vec2::new(-3.0, -4.0)), (Vec2::new(-10.0, 0.0), Vec2::new(-7.5, 2.0))] {
+            let aim = lead(origin, pos, vel, speed);
+
+            // the projectile and the target arrive at the aim point at the same time
+            let t = origin.distance(aim) / speed;
+            assert!(t > 0.0);
+            assert!((pos + vel * t).distance(aim) < 1e-3, "aiming at {:?} misses the target at {:?}", aim, pos + vel * t);
+        }
+    }
+
+    #[test]
+    fn lead_aims_straight_at_a_target_faster_than_the_projectile() {
+        let pos = Vec2::new(25.0, 5.0);
+        assert_eq!(lead(Vec2::ZERO, pos, Vec2::new(0.0, 30.0), 20.0), pos);
     }
 }
@@ -0,0 +1,195 @@
+use std::{collections::HashMap, sync::Arc};
+
+use generational_arena::Index;
+use glam::Vec2;
+
+use crate::{Polyline, Solid, State};
+
+/// size of the cells of the uniform grid
+const CELL_SIZE:f32 = 2.0;
+
+/// a closed polyline of the map together with its bounding box
+#[derive(Clone, Debug)]
+pub struct MapCollider {
+    pub min:Vec2,
+    pub max:Vec2,
+    /// the points of the polyline, with the first point repeated at the end
+    pub points:Vec<Vec2>
+}
+
+impl MapCollider {
+    pub fn new(polyline:&Polyline) -> Self {
+        let mut points = Vec::new();
+        let mut min = Vec2::splat(f32::MAX);
+        let mut max = Vec2::splat(f32::MIN);
+        for p in &polyline.points {
+            points.push(*p);
+            min = min.min(*p);
+            max = max.max(*p);
+        }
+        if let Some(p) = points.first() {
+            let p = *p;
+            points.push(p);
+        }
+
+        Self {
+            min,
+            max,
+            points
+        }
+    }
+
+    /// the segments of the polyline, as pairs of points
+    pub fn segments(&self) -> impl Iterator<Item = (Vec2, Vec2)> + '_ {
+        self.points.windows(2).map(|w| (w[0], w[1]))
+    }
+
+    /// true if a circle at pos with the radius overlaps the bounding box
+    pub fn overlaps(&self, pos:Vec2, radius:f32) -> bool {
+        pos.x + radius >= self.min.x && pos.x - radius <= self.max.x &&
+        pos.y + radius >= self.min.y && pos.y - radius <= self.max.y
+    }
+}
+
+/// a solid thing as seen by others when moving
+#[derive(Clone, Copy, Debug)]
+pub struct ThingCollider {
+    pub id:Index,
+    pub pos:Vec2,
+    pub radius:f32
+}
+
+/// read-only view of the colliders of a state, i.e. the solid things and the map
+/// things are kept in a uniform grid built once per tick, used to find the things near
+/// a position without looking at every thing, while the colliders of the map are only built once per map
+/// being separate from the state, a thing of the state can be moved while the view is queried
+pub struct Broadphase {
+    cells:HashMap<(i32, i32), Vec<ThingCollider>>,
+    /// the lowest and highest cells with things in them
+    min_cell:(i32, i32),
+    max_cell:(i32, i32),
+    max_radius:f32,
+    /// true if queries return every solid thing instead of those in nearby cells, as before the grid
+    brute_force:bool,
+    map:Arc<Vec<MapCollider>>
+}
+
+fn cell(pos:Vec2) -> (i32, i32) {
+    ((pos.x / CELL_SIZE).floor() as i32, (pos.y / CELL_SIZE).floor() as i32)
+}
+
+impl Broadphase {
+    pub fn new(state:&State) -> Self {
+        let mut cells:HashMap<(i32, i32), Vec<ThingCollider>> = HashMap::new();
+        let mut min_cell = (i32::MAX, i32::MAX);
+        let mut max_cell = (i32::MIN, i32::MIN);
+        let mut max_radius:f32 = 0.0;
+        for (id, thing) in state.things.iter() {
+            // only solid things influence the movement of others
+            if *thing.solid() != Solid::Solid {
+                continue;
+            }
+
+            max_radius = max_radius.max(*thing.radius());
+            let (x, y) = cell(*thing.pos());
+            min_cell = (min_cell.0.min(x), min_cell.1.min(y));
+            max_cell = (max_cell.0.max(x), max_cell.1.max(y));
+            cells.entry((x, y)).or_default().push(ThingCollider {
+                id,
+                pos:*thing.pos(),
+                radius:*thing.radius()
+            });
+        }
+
+        Self {
+            cells,
+            min_cell,
+            max_cell,
+            max_radius,
+            brute_force:false,
+            map:state.map.colliders().clone()
+        }
+    }
+
+    /// a broadphase whose queries return every solid thing, used to measure what the grid saves
+    pub fn brute_force(state:&State) -> Self {
+        Self {
+            brute_force:true,
+            ..Self::new(state)
+        }
+    }
+
+    pub fn map_colliders(&self) -> &[MapCollider] {
+        &self.map
+    }
+
+    /// returns the solid things which might overlap a circle at pos with the radius
+    pub fn query(&self, pos:Vec2, radius:f32) -> impl Iterator<Item = &ThingCollider> + '_ {
+        // only the part of the grid with things in it is looked at
+        let r = Vec2::splat(radius + self.max_radius);
+        let (x0, y0) = cell(pos - r);
+        let (x1, y1) = cell(pos + r);
+        let (x0, y0) = (x0.max(self.min_cell.0), y0.max(self.min_cell.1));
+        let (x1, y1) = (x1.min(self.max_cell.0), y1.min(self.max_cell.1));
+
+        // a range covering more cells than there are things in is found by looking at the things instead
+        let range = (x1 as i64 - x0 as i64 + 1).max(0) * (y1 as i64 - y0 as i64 + 1).max(0);
+        let brute_force = self.brute_force;
+        let (xs, all) = match brute_force || range > self.cells.len() as i64 {
+            true => (1..=0, Some(self.cells.iter())),
+            false => (x0..=x1, None)
+        };
+        let within = move |(x, y):&(i32, i32)| brute_force || (*x >= x0 && *x <= x1 && *y >= y0 && *y <= y1);
+        xs.flat_map(move |x| (y0..=y1).map(move |y| (x, y)))
+            .filter_map(move |c| self.cells.get(&c))
+            .chain(all.into_iter().flatten().filter(move |(c, _)| within(*c)).map(|(_, colliders)| colliders))
+            .flat_map(|colliders| colliders.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Map, PlayerThing, Thing};
+
+    fn state_with_players(positions:&[Vec2]) -> State {
+        let mut state = State::with_map(Map::new(), 100.0, 100.0);
+        for pos in positions {
+            state.things.insert(Thing::Player(PlayerThing {
+                pos:*pos,
+                radius:0.5,
+                solid:Solid::Solid,
+                ..Default::default()
+            }));
+        }
+
+        state
+    }
+
+    #[test]
+    fn query_finds_the_same_things_as_brute_force() {
+        let positions:Vec<Vec2> = (0..50).map(|i| Vec2::new((i * 7 % 40) as f32 + 0.3, (i * 13 % 30) as f32 + 0.6)).collect();
+        let state = state_with_players(&positions);
+        let grid = Broadphase::new(&state);
+        let brute_force = Broadphase::brute_force(&state);
+        for (pos, radius) in [(Vec2::new(10.0, 10.0), 1.0), (Vec2::new(0.0, 0.0), 5.0), (Vec2::new(20.0, 15.0), 1e6), (Vec2::new(-50.0, -50.0), 2.0)] {
+            let near = |broadphase:&Broadphase| {
+                let mut ids:Vec<_> = broadphase.query(pos, radius)
+                    .filter(|c| c.pos.distance(pos) < c.radius + radius)
+                    .map(|c| c.id.into_raw_parts())
+                    .collect();
+                ids.sort();
+                ids
+            };
+            assert_eq!(near(&grid), near(&brute_force), "{:?} {}", pos, radius);
+        }
+    }
+
+    #[test]
+    fn huge_query_looks_at_each_thing_once() {
+        let state = state_with_players(&[Vec2::new(1.0, 1.0), Vec2::new(90.0, 90.0)]);
+        let broadphase = Broadphase::new(&state);
+        assert_eq!(broadphase.query(Vec2::new(50.0, 50.0), 1e9).count(), 2);
+        assert_eq!(Broadphase::new(&state_with_players(&[])).query(Vec2::ZERO, 1e9).count(), 0);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::HashSet, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+/// the most characters of a chat message
+pub const MAX_CHAT_LENGTH:usize = 120;
+
+/// the messages a player can send in quick succession
+const CHAT_BURST:f32 = 3.0;
+
+/// the seconds until a player can send another message once the burst is used up
+const CHAT_RATE_SEC:f32 = 2.0;
+
+/// trims the text and removes control characters, limiting it to `MAX_CHAT_LENGTH` characters
+/// returns None if nothing is left to say
+pub fn sanitize_chat(text:&str) -> Option<String> {
+    let text:String = text.trim().chars().filter(|c| !c.is_control()).take(MAX_CHAT_LENGTH).collect();
+    let text = text.trim();
+    match text.is_empty() {
+        true => None,
+        false => Some(text.to_string())
+    }
+}
+
+/// limits how often a player can chat, allowing a few messages in quick succession
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatLimiter {
+    /// the number of messages the player can send right now
+    allowance:f32,
+    last_sec:f64
+}
+
+impl Default for ChatLimiter {
+    fn default() -> Self {
+        Self {
+            allowance:CHAT_BURST,
+            last_sec:0.0
+        }
+    }
+}
+
+impl ChatLimiter {
+    /// true if the player can send a message at the time, using up some of the allowance
+    pub fn allow(&mut self, now_sec:f64) -> bool {
+        let elapsed = (now_sec - self.last_sec).max(0.0) as f32;
+        self.last_sec = now_sec;
+        self.allowance = (self.allowance + elapsed / CHAT_RATE_SEC).min(CHAT_BURST);
+        if self.allowance < 1.0 {
+            return false;
+        }
+
+        self.allowance -= 1.0;
+        true
+    }
+}
+
+/// filters chat messages before they are relayed to other players
+pub trait WordFilter: Send + Sync {
+    fn filter(&self, text:&str) -> String;
+}
+
+/// masks the words of a list with asterisks, ignoring case
+pub struct WordListFilter {
+    words:HashSet<String>
+}
+
+impl WordListFilter {
+    pub fn new<I:IntoIterator<Item = String>>(words:I) -> Self {
+        Self {
+            words:words.into_iter().map(|word| word.trim().to_lowercase()).filter(|word| !word.is_empty()).collect()
+        }
+    }
+
+    /// loads the words from a file with a word on each line, lines starting with # are ignored
+    pub fn load(path:&Path) -> Result<Self, std::io::Error> {
+        let list = std::fs::read_to_string(path)?;
+        Ok(Self::new(list.lines().filter(|line| !line.trim_start().starts_with('#')).map(|line| line.to_string())))
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    fn mask(&self, word:&str, filtered:&mut String) {
+        if self.words.contains(&word.to_lowercase()) {
+            filtered.extend(word.chars().map(|_| '*'));
+        } else {
+            filtered.push_str(word);
+        }
+    }
+}
+
+impl WordFilter for WordListFilter {
+    fn filter(&self, text:&str) -> String {
+        let mut filtered = String::with_capacity(text.len());
+        let mut word = String::new();
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+
+            self.mask(&word, &mut filtered);
+            word.clear();
+            filtered.push(c);
+        }
+
+        self.mask(&word, &mut filtered);
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limiter_allows_a_burst_then_refills() {
+        let mut limiter = ChatLimiter::default();
+        for _ in 0..CHAT_BURST as usize {
+            assert!(limiter.allow(10.0));
+        }
+        assert!(!limiter.allow(10.0));
+
+        // half a message refilled is not enough
+        assert!(!limiter.allow(10.0 + CHAT_RATE_SEC as f64 / 2.0));
+        assert!(limiter.allow(10.0 + CHAT_RATE_SEC as f64));
+        assert!(!limiter.allow(10.0 + CHAT_RATE_SEC as f64));
+
+        // waiting long refills no more than the burst
+        let later = 1000.0;
+        for _ in 0..CHAT_BURST as usize {
+            assert!(limiter.allow(later));
+        }
+        assert!(!limiter.allow(later));
+    }
+
+    #[test]
+    fn masks_whole_words_ignoring_case() {
+        let filter = WordListFilter::new(vec!["heck".to_string(), " Darn ".to_string(), "".to_string()]);
+        assert_eq!(filter.len(), 2);
+        assert_eq!(filter.filter("Heck, DARN it!"), "****, **** it!");
+        assert_eq!(filter.filter("heckler darned"), "heckler darned");
+        assert_eq!(filter.filter("heck"), "****");
+    }
+
+    #[test]
+    fn strips_control_characters() {
+        assert_eq!(sanitize_chat("  hi\u{7}\tthere\r\n ").as_deref(), Some("hithere"));
+        assert_eq!(sanitize_chat("a\u{1b}[31mb").as_deref(), Some("a[31mb"));
+        assert_eq!(sanitize_chat(" \n\u{0}\t "), None);
+        assert_eq!(sanitize_chat(""), None);
+    }
+
+    #[test]
+    fn caps_length_in_characters() {
+        let long:String = "é".repeat(MAX_CHAT_LENGTH * 2);
+        let text = sanitize_chat(&long).unwrap();
+        assert_eq!(text.chars().count(), MAX_CHAT_LENGTH);
+        assert_eq!(text.len(), MAX_CHAT_LENGTH * 'é'.len_utf8());
+
+        let emoji:String = "🙂".repeat(MAX_CHAT_LENGTH + 1);
+        assert_eq!(sanitize_chat(&emoji).unwrap().chars().count(), MAX_CHAT_LENGTH);
+    }
+}
@@ -0,0 +1,334 @@
+//! configuration of the server, read from a json file and overridden by command line flags
+//!
+//! the file given by `--config` is read, or `server.json` in the working directory if it exists, see `server.example.json`
+//! every field of the file is optional, fields not given keep their default
+
+use std::{fmt::Display, net::SocketAddr, path::Path};
+
+use sample_lib::GameMode;
+use serde::{Deserialize, Serialize};
+
+use crate::bot::Difficulty;
+
+/// the config file read if no config file is given
+pub const DEFAULT_CONFIG_FILE:&str = "server.json";
+
+/// the tick rate is sent to clients as a single byte
+const MAX_TICK_RATE:u64 = 255;
+const MAX_INSTANCES:usize = 64;
+const MAX_PLAYERS:usize = 64;
+
+pub const USAGE:&str = "usage: server [--config <file.json>] [--bind <addr:port>] [--instances <n>] [--tick-rate <n>]
+              [--tick-rates <n,...>] [--max-players <n>] [--bot-count <n>] [--maps-dir <dir>] [--maps <name,...>] [--mode <dm|tdm|ctf>] [--friendly-fire]
+              [--bot-difficulties <easy|normal|hard,...>] [--bot-script <file.rhai>] [--stats-file <file.json>]
+              [--replay-dir <dir>] [--no-replays] [--admin-password <password>] [--audit-log <file>]
+              [--word-filter <file.txt>]";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// the address the server listens on
+    pub bind:String,
+    /// the number of instances players can join
+    pub instances:usize,
+    /// the ticks per second of each instance
+    pub tick_rate:u64,
+    /// the ticks per second of the instances, assigned round robin to instances, `tick_rate` is used if empty
+    pub tick_rates:Vec<u64>,
+    /// the most players playing an instance, spectators not included
+    pub max_players:usize,
+    /// the number of players each instance is filled up to with bots
+    pub bot_count:usize,
+    /// the directory maps are loaded from, the built-in map is used if it does not exist
+    pub maps_dir:String,
+    /// the names of the maps played in rotation, every map in the directory if empty
+    pub maps:Vec<String>,
+    /// dm, tdm or ctf
+    pub mode:String,
+    /// whether players hurt their team mates in team based game modes
+    pub friendly_fire:bool,
+    /// the difficulties of the bots, assigned round robin to instances
+    pub bot_difficulties:Vec<String>,
+    /// a script for the brains of bots, the built-in brain is used if None
+    pub bot_script:Option<String>,
+    /// the file the lifetime stats of players are kept in
+    pub stats_file:String,
+    /// the directory rounds are recorded to as replays, not recorded if None
+    pub replay_dir:Option<String>,
+    /// the password clients authenticate as admin with, remote admin is disabled if None
+    /// better given in the config file than as flag, where it is visible to other users of the machine
+    pub admin_password:Option<String>,
+    /// the file admin commands are recorded in
+    pub audit_log:String,
+    /// a file with a word on each line masked in chat messages, not filtered if None
+    pub word_filter:Option<String>
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind:"0.0.0.0:8080".into(),
+            instances:8,
+            tick_rate:20,
+            tick_rates:Vec::new(),
+            max_players:8,
+            bot_count:4,
+            maps_dir:"maps".into(),
+            maps:Vec::new(),
+            mode:"dm".into(),
+            friendly_fire:false,
+            bot_difficulties:vec!["normal".into()],
+            bot_script:None,
+            stats_file:"stats.json".into(),
+            replay_dir:Some("replays".into()),
+            admin_password:None,
+            audit_log:"admin.log".into(),
+            word_filter:None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// a flag is unknown or is missing its value
+    Flag(String),
+    /// a setting is out of range or cannot be parsed
+    Invalid(String)
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "{}", err),
+            ConfigError::Json(err) => write!(f, "{}", err),
+            ConfigError::Flag(err) => write!(f, "{}\n{}", err, USAGE),
+            ConfigError::Invalid(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err:std::io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(err:serde_json::Error) -> Self {
+        ConfigError::Json(err)
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path:&Path) -> Result<Self, ConfigError> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// reads the config file and applies the flags given, without the name of the program, on top of it
+    pub fn from_args(args:&[String]) -> Result<Self, ConfigError> {
+        let mut config = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => match args.get(i + 1) {
+                Some(path) => Self::load(Path::new(path)).map_err(|err| ConfigError::Invalid(format!("failed to read config {}: {}", path, err)))?,
+                None => return Err(ConfigError::Flag("--config expects a file".into()))
+            },
+            None => match Self::load(Path::new(DEFAULT_CONFIG_FILE)) {
+                Ok(config) => config,
+                Err(ConfigError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+                Err(err) => return Err(ConfigError::Invalid(format!("failed to read config {}: {}", DEFAULT_CONFIG_FILE, err)))
+            }
+        };
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            // flags without a value
+            if flag == "--no-replays" {
+                config.replay_dir = None;
+                continue;
+            }
+            if flag == "--friendly-fire" {
+                config.friendly_fire = true;
+                continue;
+            }
+
+            let value = args.next().ok_or_else(|| ConfigError::Flag(format!("{} expects a value", flag)))?;
+            let number = || value.parse::<usize>().map_err(|_| ConfigError::Flag(format!("{} expects a number, got {}", flag, value)));
+            let list = || value.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect::<Vec<_>>();
+            let numbers = || list().iter().map(|v| v.parse::<u64>()).collect::<Result<Vec<_>, _>>()
+                .map_err(|_| ConfigError::Flag(format!("{} expects numbers, got {}", flag, value)));
+            match flag.as_str() {
+                "--config" => {}
+                "--bind" => config.bind = value.clone(),
+                "--instances" => config.instances = number()?,
+                "--tick-rate" => config.tick_rate = number()? as u64,
+                "--tick-rates" => config.tick_rates = numbers()?,
+                "--max-players" => config.max_players = number()?,
+                "--bot-count" => config.bot_count = number()?,
+                "--maps-dir" => config.maps_dir = value.clone(),
+                "--maps" => config.maps = list(),
+                "--mode" => config.mode = value.clone(),
+                "--bot-difficulties" => config.bot_difficulties = list(),
+                "--bot-script" => config.bot_script = Some(value.clone()),
+                "--stats-file" => config.stats_file = value.clone(),
+                "--replay-dir" => config.replay_dir = Some(value.clone()),
+                "--admin-password" => config.admin_password = Some(value.clone()),
+                "--audit-log" => config.audit_log = value.clone(),
+                "--word-filter" => config.word_filter = Some(value.clone()),
+                _ => return Err(ConfigError::Flag(format!("unknown flag {}", flag)))
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn bind_addr(&self) -> Result<SocketAddr, ConfigError> {
+        self.bind.parse().map_err(|err| ConfigError::Invalid(format!("invalid bind address {}: {}", self.bind, err)))
+    }
+
+    pub fn game_mode(&self) -> Result<GameMode, ConfigError> {
+        self.mode.parse().map_err(ConfigError::Invalid)
+    }
+
+    pub fn difficulties(&self) -> Result<Vec<Difficulty>, ConfigError> {
+        self.bot_difficulties.iter().map(|d| d.parse().map_err(ConfigError::Invalid)).collect()
+    }
+
+    /// the tick rates assigned round robin to instances
+    pub fn instance_tick_rates(&self) -> Vec<u64> {
+        match self.tick_rates.is_empty() {
+            true => vec![self.tick_rate],
+            false => self.tick_rates.clone()
+        }
+    }
+
+    /// checks that every setting is usable, the maps and scripts are checked as they are loaded
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.bind_addr()?;
+        self.game_mode()?;
+        self.difficulties()?;
+
+        if self.instances == 0 || self.instances > MAX_INSTANCES {
+            return Err(ConfigError::Invalid(format!("instances must be between 1 and {}, got {}", MAX_INSTANCES, self.instances)));
+        }
+        for tick_rate in self.instance_tick_rates() {
+            if tick_rate == 0 || tick_rate > MAX_TICK_RATE {
+                return Err(ConfigError::Invalid(format!("tick rate must be between 1 and {}, got {}", MAX_TICK_RATE, tick_rate)));
+            }
+        }
+        if self.max_players == 0 || self.max_players > MAX_PLAYERS {
+            return Err(ConfigError::Invalid(format!("max players must be between 1 and {}, got {}", MAX_PLAYERS, self.max_players)));
+        }
+        if self.bot_count > self.max_players {
+            return Err(ConfigError::Invalid(format!("bot count {} exceeds max players {}", self.bot_count, self.max_players)));
+        }
+        if self.stats_file.is_empty() {
+            return Err(ConfigError::Invalid("stats file must not be empty".into()));
+        }
+        if self.audit_log.is_empty() {
+            return Err(ConfigError::Invalid("audit log must not be empty".into()));
+        }
+        if self.admin_password.as_ref().map(|p| p.is_empty()).unwrap_or(false) {
+            return Err(ConfigError::Invalid("admin password must not be empty, leave it out to disable remote admin".into()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// writes the json to a config file of its own, such that a server.json in the working directory is not read
+    fn config_file(name:&str, json:&str) -> String {
+        let path = std::env::temp_dir().join(format!("server-config-test-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, json).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn args(config:&str, flags:&[&str]) -> Vec<String> {
+        let mut args = vec!["--config".to_string(), config.to_string()];
+        args.extend(flags.iter().map(|f| f.to_string()));
+        args
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let file = config_file("override", r#"{ "tick_rate": 30, "instances": 2, "mode": "tdm" }"#);
+        let config = ServerConfig::from_args(&args(&file, &["--tick-rate", "60", "--no-replays", "--maps", "arena, pillars", "--tick-rates", "20,40", "--friendly-fire"])).unwrap();
+        assert_eq!(config.tick_rate, 60);
+        assert_eq!(config.instances, 2);
+        assert_eq!(config.mode, "tdm");
+        assert_eq!(config.replay_dir, None);
+        assert_eq!(config.maps, vec!["arena", "pillars"]);
+        assert_eq!(config.instance_tick_rates(), vec![20, 40]);
+        assert!(config.friendly_fire);
+        // not given, kept at the default
+        assert_eq!(config.max_players, ServerConfig::default().max_players);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn bot_count_is_given_without_a_bot_script() {
+        let file = config_file("bots", "{}");
+        let config = ServerConfig::from_args(&args(&file, &["--bot-count", "6"])).unwrap();
+        assert_eq!(config.bot_count, 6);
+        assert_eq!(config.bot_script, None);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn unknown_flag_is_rejected() {
+        let file = config_file("unknown", "{}");
+        assert!(matches!(ServerConfig::from_args(&args(&file, &["--tickrate", "20"])), Err(ConfigError::Flag(_))));
+    }
+
+    #[test]
+    fn missing_value_is_rejected() {
+        let file = config_file("missing", "{}");
+        assert!(matches!(ServerConfig::from_args(&args(&file, &["--bind"])), Err(ConfigError::Flag(_))));
+        assert!(matches!(ServerConfig::from_args(&["--config".to_string()]), Err(ConfigError::Flag(_))));
+        assert!(matches!(ServerConfig::from_args(&args(&file, &["--instances", "many"])), Err(ConfigError::Flag(_))));
+        assert!(matches!(ServerConfig::from_args(&args(&file, &["--tick-rates", "20,fast"])), Err(ConfigError::Flag(_))));
+    }
+
+    #[test]
+    fn unknown_field_is_rejected() {
+        let file = config_file("field", r#"{ "tickrate": 20 }"#);
+        assert!(matches!(ServerConfig::load(Path::new(&file)), Err(ConfigError::Json(_))));
+        assert!(matches!(ServerConfig::from_args(&args(&file, &[])), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        assert!(ServerConfig::default().validate().is_ok());
+
+        let invalid:Vec<fn(&mut ServerConfig)> = vec![
+            |c| c.instances = 0,
+            |c| c.instances = MAX_INSTANCES + 1,
+            |c| c.tick_rate = 0,
+            |c| c.tick_rate = MAX_TICK_RATE + 1,
+            |c| c.tick_rates = vec![20, 0],
+            |c| c.tick_rates = vec![MAX_TICK_RATE + 1],
+            |c| c.max_players = 0,
+            |c| c.max_players = MAX_PLAYERS + 1,
+            |c| c.bot_count = c.max_players + 1,
+            |c| c.bind = "nowhere".into(),
+            |c| c.mode = "football".into(),
+            |c| c.bot_difficulties = vec!["impossible".into()],
+            |c| c.stats_file = String::new(),
+            |c| c.audit_log = String::new(),
+            |c| c.admin_password = Some(String::new())
+        ];
+        for (i, change) in invalid.iter().enumerate() {
+            let mut config = ServerConfig::default();
+            change(&mut config);
+            assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))), "change {} was accepted", i);
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use bincode::Options;
+use generational_arena::Index;
+use glam::Vec2;
+use hostess::log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{Ability, DepartedScore, Event, GameMode, Map, MatchPhase, Solid, State, Thing, MAX_THINGS};
+
+/// positions are quantized to 1/QUANTIZATION units
+const QUANTIZATION:f32 = 128.0;
+
+/// a position quantized to 1/128 units, covering -256 to 256 units
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct QVec2(i16, i16);
+
+impl QVec2 {
+    pub fn new(v:Vec2) -> Self {
+        Self((v.x * QUANTIZATION).round() as i16, (v.y * QUANTIZATION).round() as i16)
+    }
+
+    pub fn to_vec2(&self) -> Vec2 {
+        Vec2::new(self.0 as f32 / QUANTIZATION, self.1 as f32 / QUANTIZATION)
+    }
+}
+
+/// rounds the position to what it will be after being quantized
+pub fn quantize(v:Vec2) -> Vec2 {
+    QVec2::new(v).to_vec2()
+}
+
+/// a change of a single field of a thing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum FieldDelta {
+    Pos(QVec2),
+    Vel(QVec2),
+    Solid(Solid),
+    Hearts(i8),
+    RespawnTimer(f32),
+    ReturnTimer(f32),
+    AbilityCooldown(f32),
+    SpeedBoost(f32),
+    Shield(f32),
+    Ability(Ability),
+    Ammo(Vec<u8>),
+    Charge(Option<f32>),
+    Kills(i32),
+    Deaths(i32),
+    SpawnPos(Option<QVec2>),
+    /// fields which rarely change are not tracked, instead the whole thing is sent
+    Thing(Thing)
+}
+
+/// the difference between two states, by the things added, removed and changed
+/// positions are quantized and the map is only included if it has changed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StateDelta {
+    pub timestamp:f64,
+    pub next_spawn:i16,
+    pub phase:MatchPhase,
+    pub phase_timer:f32,
+    pub score_limit:i32,
+    pub mode:GameMode,
+    pub friendly_fire:bool,
+    pub team_scores:[i32; 2],
+    /// the scores of players which left, if changed
+    pub departed:Option<Vec<DepartedScore>>,
+    pub events:Vec<Event>,
+    /// the map with its width and height, if changed
+    pub map:Option<(Map, f32, f32)>,
+    pub removed:Vec<Index>,
+    pub added:Vec<(Index, Thing)>,
+    pub changed:Vec<(Index, Vec<FieldDelta>)>
+}
+
+impl State {
+    /// returns a clone of the state with positions quantized
+    /// this is the state as it will be seen by the clients
+    pub fn quantized(&self) -> State {
+        let mut state = self.clone();
+        for (_, thing) in state.things.iter_mut() {
+            *thing.pos_mut() = quantize(*thing.pos());
+            match thing {
+                Thing::Player(player) => {
+                    player.spawn_pos = player.spawn_pos.map(quantize);
+                }
+                Thing::Projectile(projectile) => {
+                    projectile.vel = quantize(projectile.vel);
+                    // the lifetime is only needed by the server, not sending it keeps projectiles unchanged between ticks
+                    projectile.lifetime = 0.0;
+                }
+                Thing::Pickup(_) | Thing::Flag(_) => {}
+            }
+        }
+
+        state
+    }
+}
+
+impl StateDelta {
+    /// computes the delta from base to state, both expected to be quantized
+    pub fn new(base:&State, state:&State) -> Self {
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+
+        for (id, _) in base.things.iter() {
+            if !state.things.contains(id) {
+                removed.push(id);
+            }
+        }
+
+        for (id, thing) in state.things.iter() {
+            match base.things.get(id) {
+                Some(prev) => {
+                    let fields = diff_thing(prev, thing);
+                    if fields.len() > 0 {
+                        changed.push((id, fields));
+                    }
+                }
+                None => added.push((id, thing.clone()))
+            }
+        }
+
+        let map = if base.map.same_layout(&state.map) && base.width == state.width && base.height == state.height {
+            None
+        } else {
+            Some((state.map.clone(), state.width, state.height))
+        };
+
+        Self {
+            timestamp:state.timestamp,
+            next_spawn:state.next_spawn,
+            phase:state.phase,
+            phase_timer:state.phase_timer,
+            score_limit:state.score_limit,
+            mode:state.mode,
+            friendly_fire:state.friendly_fire,
+            team_scores:state.team_scores,
+            departed:if base.departed != state.departed { Some(state.departed.clone()) } else { None },
+            events:state.events.clone(),
+            map,
+            removed,
+            added,
+            changed
+        }
+    }
+
+    /// applies the delta to the base state, returning the new state
+    /// returns None if a thing is added beyond `MAX_THINGS`
+    pub fn apply(&self, base:&State) -> Option<State> {
+        let mut state = base.clone();
+        if let Some((map, width, height)) = &self.map {
+            state.map = map.clone();
+            state.width = *width;
+            state.height = *height;
+        }
+
+        for id in self.removed.iter() {
+            state.things.remove(*id);
+        }
+
+        for (id, fields) in self.changed.iter() {
+            if let Some(thing) = state.things.get_mut(*id) {
+                for field in fields.iter() {
+                    apply_field(thing, field);
+                }
+            }
+        }
+
+        for (id, thing) in self.added.iter() {
+            if !state.things.place(*id, thing.clone()) {
+                error!("Thing added at {:?} is beyond the most things a state can hold", id);
+                return None;
+            }
+        }
+
+        state.timestamp = self.timestamp;
+        state.next_spawn = self.next_spawn;
+        state.phase = self.phase;
+        state.phase_timer = self.phase_timer;
+        state.score_limit = self.score_limit;
+        state.mode = self.mode;
+        state.friendly_fire = self.friendly_fire;
+        state.team_scores = self.team_scores;
+        if let Some(departed) = &self.departed {
+            state.departed = departed.clone();
+        }
+        state.events = self.events.clone();
+        Some(state)
+    }
+
+    /// encodes the delta using variable length integers
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::DefaultOptions::new().serialize(self).unwrap_or_default()
+    }
+
+    pub fn decode(bytes:&[u8]) -> Option<Self> {
+        bincode::DefaultOptions::new().deserialize(bytes).ok()
+    }
+}
+
+/// returns the changed fields of the thing, or the whole thing if untracked fields changed
+fn diff_thing(prev:&Thing, thing:&Thing) -> Vec<FieldDelta> {
+    let mut fields = Vec::new();
+    match (prev, thing) {
+        (Thing::Player(a), Thing::Player(b)) => {
+            if a.pos != b.pos {
+                fields.push(FieldDelta::Pos(QVec2::new(b.pos)));
+            }
+            if a.solid != b.solid {
+                fields.push(FieldDelta::Solid(b.solid.clone()));
+            }
+            if a.hearts != b.hearts {
+                fields.push(FieldDelta::Hearts(b.hearts));
+            }
+            if a.respawn_timer != b.respawn_timer {
+                fields.push(FieldDelta::RespawnTimer(b.respawn_timer));
+            }
+            if a.ability_cooldown != b.ability_cooldown {
+                fields.push(FieldDelta::AbilityCooldown(b.ability_cooldown));
+            }
+            if a.speed_boost != b.speed_boost {
+                fields.push(FieldDelta::SpeedBoost(b.speed_boost));
+            }
+            if a.shield != b.shield {
+                fields.push(FieldDelta::Shield(b.shield));
+            }
+            if a.ability != b.ability {
+                fields.push(FieldDelta::Ability(b.ability));
+            }
+            if a.ammo != b.ammo {
+                fields.push(FieldDelta::Ammo(b.ammo.clone()));
+            }
+            if a.charge != b.charge {
+                fields.push(FieldDelta::Charge(b.charge));
+            }
+            if a.kills != b.kills {
+                fields.push(FieldDelta::Kills(b.kills));
+            }
+            if a.deaths != b.deaths {
+                fields.push(FieldDelta::Deaths(b.deaths));
+            }
+            if a.spawn_pos != b.spawn_pos {
+                fields.push(FieldDelta::SpawnPos(b.spawn_pos.map(QVec2::new)));
+            }
+        }
+        (Thing::Projectile(a), Thing::Projectile(b)) => {
+            if a.pos != b.pos {
+                fields.push(FieldDelta::Pos(QVec2::new(b.pos)));
+            }
+            if a.vel != b.vel {
+                fields.push(FieldDelta::Vel(QVec2::new(b.vel)));
+            }
+            if a.solid != b.solid {
+                fields.push(FieldDelta::Solid(b.solid.clone()));
+            }
+        }
+        (Thing::Pickup(a), Thing::Pickup(b)) => {
+            if a.pos != b.pos {
+                fields.push(FieldDelta::Pos(QVec2::new(b.pos)));
+            }
+            if a.respawn_timer != b.respawn_timer {
+                fields.push(FieldDelta::RespawnTimer(b.respawn_timer));
+            }
+        }
+        (Thing::Flag(a), Thing::Flag(b)) => {
+            if a.pos != b.pos {
+                fields.push(FieldDelta::Pos(QVec2::new(b.pos)));
+            }
+            if a.return_timer != b.return_timer {
+                fields.push(FieldDelta::ReturnTimer(b.return_timer));
+            }
+        }
+        _ => {}
+    }
+
+    // ensure untracked fields are also replicated by sending the whole thing
+    let mut tracked = prev.clone();
+    for field in &fields {
+        apply_field(&mut tracked, field);
+    }
+    if tracked != *thing {
+        return vec![FieldDelta::Thing(thing.clone())];
+    }
+
+    fields
+}
+
+fn apply_field(thing:&mut Thing, field:&FieldDelta) {
+    match (thing, field) {
+        (thing, FieldDelta::Thing(t)) => *thing = t.clone(),
+        (thing, FieldDelta::Pos(pos)) => *thing.pos_mut() = pos.to_vec2(),
+        (thing, FieldDelta::Solid(solid)) => *thing.solid_mut() = solid.clone(),
+        (Thing::Projectile(projectile), FieldDelta::Vel(vel)) => projectile.vel = vel.to_vec2(),
+        (Thing::Player(player), FieldDelta::Hearts(hearts)) => player.hearts = *hearts,
+        (Thing::Player(player), FieldDelta::RespawnTimer(timer)) => player.respawn_timer = *timer,
+        (Thing::Pickup(pickup), FieldDelta::RespawnTimer(timer)) => pickup.respawn_timer = *timer,
+        (Thing::Flag(flag), FieldDelta::ReturnTimer(timer)) => flag.return_timer = *timer,
+        (Thing::Player(player), FieldDelta::SpeedBoost(timer)) => player.speed_boost = *timer,
+        (Thing::Player(player), FieldDelta::Shield(timer)) => player.shield = *timer,
+        (Thing::Player(player), FieldDelta::AbilityCooldown(cooldown)) => player.ability_cooldown = *cooldown,
+        (Thing::Player(player), FieldDelta::Ability(ability)) => player.ability = *ability,
+        (Thing::Player(player), FieldDelta::Ammo(ammo)) => player.ammo = ammo.clone(),
+        (Thing::Player(player), FieldDelta::Charge(charge)) => player.charge = *charge,
+        (Thing::Player(player), FieldDelta::Kills(kills)) => player.kills = *kills,
+        (Thing::Player(player), FieldDelta::Deaths(deaths)) => player.deaths = *deaths,
+        (Thing::Player(player), FieldDelta::SpawnPos(pos)) => player.spawn_pos = pos.map(|p| p.to_vec2()),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::Rng;
+
+    use super::*;
+    use crate::{state_hash, Harness, SimRng, SnapshotBuffer};
+
+    enum Snapshot {
+        Full(State),
+        Delta(u32, Vec<u8>)
+    }
+
+    /// true if the states are the same, except for the rng which is not sent to clients
+    fn same(client:&State, server:&State) -> bool {
+        let mut client = client.clone();
+        client.rng = server.rng.clone();
+        state_hash(&client) == state_hash(server)
+    }
+
+    /// plays the server and a client over a connection losing and reordering messages
+    /// returns the number of snapshots the client applied and how many were sent in full
+    fn run_lossy(seed:u64, server_capacity:usize, ack_outage:std::ops::Range<u64>) -> (u32, u32) {
+        let mut rng = SimRng::new(seed);
+        let mut harness = Harness::new(State::new(), seed, 20);
+        let players = [harness.add_player("a"), harness.add_player("b")];
+
+        let mut server = SnapshotBuffer::new(server_capacity);
+        let mut acked:Option<u32> = None;
+        let mut client = SnapshotBuffer::new(16);
+        let mut sent:HashMap<u32, State> = HashMap::new();
+        let mut to_client:Vec<(u64, u32, Snapshot)> = Vec::new();
+        let mut to_server:Vec<(u64, u32)> = Vec::new();
+        let (mut applied, mut full) = (0, 0);
+
+        for tick in 0..400 {
+            let inputs:Vec<_> = players.iter().map(|id| {
+                let dir = Vec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                harness.input(*id, dir, Ability::default(), rng.gen_bool(0.2), Vec2::new(20.0, 15.0))
+            }).collect();
+            harness.step(&inputs);
+
+            // acks arriving out of order, the newest is kept
+            to_server.retain(|(arrival, seq)| {
+                if *arrival <= tick {
+                    acked = Some(acked.map(|a| a.max(*seq)).unwrap_or(*seq));
+                    return false;
+                }
+                true
+            });
+
+            let seq = tick as u32 + 1;
+            let snapshot = harness.state.quantized();
+            let msg = match server.baseline(acked) {
+                Some((baseline_seq, baseline)) => Snapshot::Delta(baseline_seq, StateDelta::new(baseline, &snapshot).encode()),
+                None => Snapshot::Full(snapshot.clone())
+            };
+            let (lost, delay) = (rng.gen_bool(0.2), rng.gen_range(0..4));
+            if !lost {
+                to_client.push((tick + delay, seq, msg));
+            }
+            sent.insert(seq, snapshot.clone());
+            server.push(seq, snapshot);
+
+            let arrived:Vec<_> = to_client.iter().enumerate().filter(|(_, (arrival, _, _))| *arrival <= tick).map(|(i, _)| i).collect();
+            for i in arrived.into_iter().rev() {
+                let (_, seq, msg) = to_client.remove(i);
+                if client.latest_seq().map(|latest| seq <= latest).unwrap_or(false) {
+                    continue;
+                }
+
+                let state = match msg {
+                    Snapshot::Full(state) => {
+                        full += 1;
+                        state
+                    }
+                    Snapshot::Delta(baseline_seq, bytes) => match client.get(baseline_seq) {
+                        Some(baseline) => StateDelta::decode(&bytes).and_then(|delta| delta.apply(baseline)).expect("delta applies"),
+                        None => continue
+                    }
+                };
+
+                assert!(same(&state, &sent[&seq]), "seed {}: snapshot {} differs from the server", seed, seq);
+                applied += 1;
+                client.push(seq, state);
+                let (lost, delay) = (rng.gen_bool(0.3), rng.gen_range(0..4));
+                if !lost && !ack_outage.contains(&tick) {
+                    to_server.push((tick + delay, seq));
+                }
+            }
+        }
+
+        (applied, full)
+    }
+
+    #[test]
+    fn client_follows_server_through_lost_and_reordered_messages() {
+        for seed in 0..8 {
+            let (applied, full) = run_lossy(seed, 64, 0..0);
+            assert!(applied > 200, "seed {}: only {} snapshots applied", seed, applied);
+            assert!(full < applied);
+        }
+    }
+
+    #[test]
+    fn evicted_baseline_falls_back_to_full_snapshot() {
+        // no acks arrive for longer than the server remembers snapshots, the acked baseline is evicted
+        let (applied, full) = run_lossy(7, 8, 100..150);
+        assert!(applied > 200);
+        assert!(full >= 20, "only {} full snapshots", full);
+    }
+
+    #[test]
+    fn delta_round_trips_through_encoding() {
+        let mut base = State::new();
+        let removed = base.things.insert(Thing::new_player("removed"));
+        let moved = base.things.insert(Thing::new_player("moved"));
+        let base = base.quantized();
+
+        let mut state = base.clone();
+        state.things.remove(removed);
+        let added = state.things.insert(Thing::new_projectile(Vec2::new(3.0, 4.0), Vec2::new(1.0, 0.0), moved));
+        *state.things[moved].pos_mut() = Vec2::new(12.5, 7.25);
+        state.timestamp = 1.5;
+        state.team_scores = [2, 3];
+        let state = state.quantized();
+
+        let delta = StateDelta::decode(&StateDelta::new(&base, &state).encode()).unwrap();
+        assert_eq!(delta.removed, vec![removed]);
+        assert_eq!(delta.added.len(), 1);
+        assert!(delta.map.is_none());
+
+        let applied = delta.apply(&base).unwrap();
+        assert!(same(&applied, &state));
+        // the projectile reuses the slot of the removed player, with a newer generation
+        assert_eq!(added.into_raw_parts().0, removed.into_raw_parts().0);
+        assert!(applied.things.get(removed).is_none());
+        assert_eq!(applied.things.get(added), state.things.get(added));
+    }
+
+    #[test]
+    fn corrupt_delta_is_rejected() {
+        let base = State::new().quantized();
+        let mut state = base.clone();
+        state.things.insert(Thing::new_player("a"));
+        let bytes = StateDelta::new(&base, &state.quantized()).encode();
+        assert!(StateDelta::decode(&bytes[..bytes.len() / 2]).is_none());
+    }
+
+    #[test]
+    fn delta_adding_too_many_things_is_rejected() {
+        let base = State::new().quantized();
+        let mut delta = StateDelta::new(&base, &base);
+        delta.added.push((Index::from_raw_parts(MAX_THINGS, 0), Thing::new_player("far")));
+        assert!(delta.apply(&base).is_none());
+
+        delta.added[0].0 = Index::from_raw_parts(MAX_THINGS - 1, 0);
+        assert!(delta.apply(&base).unwrap().things.get(delta.added[0].0).is_some());
+    }
+
+    #[test]
+    fn snapshots_carry_no_rng() {
+        let mut state = State::new();
+        state.rng = SimRng::new(1234);
+        state.rng.gen::<u64>();
+        let snapshot = state.quantized();
+
+        let bytes = bincode::serialize(&snapshot).unwrap();
+        let decoded:State = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.rng, SimRng::default());
+
+        // the bytes sent are the same whatever the rng
+        let mut other = snapshot.clone();
+        other.rng = SimRng::new(99);
+        assert_eq!(bytes, bincode::serialize(&other).unwrap());
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::VecDeque;
+
+use generational_arena::Index;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::{apply_input, update_things, use_ability, Ability, Broadphase, Input, Prediction, SimRng, State, Thing};
+
+/// a segment of scripted input, held from `from_tick` until but not including `to_tick`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ScriptedInput {
+    pub from_tick:u64,
+    pub to_tick:u64,
+    pub movement_dir:Vec2,
+    pub ability_trigger:bool,
+    pub ability_target:Vec2,
+    #[serde(default)]
+    pub ability:Ability
+}
+
+/// a player and the input it will make during the simulation
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ScriptedPlayer {
+    pub name:String,
+    pub inputs:Vec<ScriptedInput>
+}
+
+/// a scripted simulation, to be run by the `Harness`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Script {
+    pub seed:u64,
+    pub tick_rate:u64,
+    pub ticks:u64,
+    pub players:Vec<ScriptedPlayer>
+}
+
+/// how far the client side prediction was from the server
+#[derive(Clone, Debug, Default)]
+pub struct PredictionStats {
+    pub samples:u32,
+    pub mean_error:f32,
+    pub max_error:f32
+}
+
+/// headless simulation of the game, stepping the shared game logic at a fixed tick rate
+/// given the same seed and inputs, two runs produce identical states
+pub struct Harness {
+    pub state:State,
+    pub tick:u64,
+    pub tick_rate:u64,
+    players:Vec<Index>
+}
+
+impl Harness {
+    pub fn new(mut state:State, seed:u64, tick_rate:u64) -> Self {
+        state.rng = SimRng::new(seed);
+        Self {
+            state,
+            tick:0,
+            tick_rate,
+            players:Vec::new()
+        }
+    }
+
+    /// the fixed time in seconds between two ticks
+    pub fn delta(&self) -> f64 {
+        1.0 / self.tick_rate as f64
+    }
+
+    /// spawns a player thing, returning its id
+    pub fn add_player(&mut self, name:&str) -> Index {
+        let id = self.state.things.insert(Thing::new_player(name));
+        self.players.push(id);
+        id
+    }
+
+    pub fn players(&self) -> &Vec<Index> {
+        &self.players
+    }
+
+    /// makes an input for the player thing as the client would, scaling the movement by the tick delta
+    pub fn input(&self, thing_id:Index, movement_dir:Vec2, ability:Ability, ability_trigger:bool, ability_target:Vec2) -> Input {
+        let timestamp_sec = self.state.timestamp;
+        Input {
+            seq:0,
+            timestamp_sec,
+            view_timestamp_sec:timestamp_sec,
+            thing_id:Some(thing_id),
+            movement_dir,
+            movement:movement_dir * self.delta() as f32,
+            ability_trigger,
+            ability_target,
+            ability
+        }
+    }
+
+    /// advances the simulation a single tick, applying the given inputs the way the server does
+    /// returns the hash of the resulting state
+    pub fn step(&mut self, inputs:&[Input]) -> u64 {
+        let delta = self.delta();
+        self.state.events.clear();
+        self.state.timestamp = self.tick as f64 * delta;
+
+        let broadphase = Broadphase::new(&self.state);
+        for input in inputs {
+            apply_input(&mut self.state, &broadphase, input, true);
+            if let Some(thing_id) = input.thing_id {
+                for projectile in use_ability(&mut self.state, thing_id, input.ability_trigger, input.ability_target) {
+                    self.state.things.insert(projectile);
+                }
+            }
+        }
+
+        let broadphase = Broadphase::new(&self.state);
+        update_things(&mut self.state, &broadphase, delta);
+        self.tick += 1;
+        state_hash(&self.state)
+    }
+
+    /// returns the scripted input of the player at the current tick
+    fn scripted_input(&self, player:&ScriptedPlayer, thing_id:Index) -> Input {
+        let tick = self.tick;
+        let scripted = player.inputs.iter().find(|i| i.from_tick <= tick && tick < i.to_tick);
+        let scripted = scripted.cloned().unwrap_or_default();
+        self.input(thing_id, scripted.movement_dir, scripted.ability, scripted.ability_trigger, scripted.ability_target)
+    }
+
+    /// runs the script with the first player predicted by a client, with messages between
+    /// the client and the server delayed by `latency_ticks` in each direction
+    /// the prediction made for each input while alive is compared to the state of the server after processing it
+    pub fn measure_prediction(state:State, script:&Script, latency_ticks:u64) -> PredictionStats {
+        let mut server = Harness::new(state, script.seed, script.tick_rate);
+        for player in &script.players {
+            server.add_player(&player.name);
+        }
+
+        let mut client = server.state.quantized();
+        let mut prediction = Prediction::new();
+        let mut to_server:VecDeque<(u64, Input)> = VecDeque::new();
+        let mut to_client:VecDeque<(u64, u32, State)> = VecDeque::new();
+        let mut predicted:VecDeque<(u32, Vec2)> = VecDeque::new();
+        let mut processed_seq = 0;
+        let mut stats = PredictionStats::default();
+        let mut total_error = 0.0;
+
+        for _ in 0..script.ticks {
+            let tick = server.tick;
+            let mut inputs = Vec::new();
+            for (i, (player, thing_id)) in script.players.iter().zip(server.players.iter()).enumerate() {
+                let mut input = server.scripted_input(player, *thing_id);
+                if i == 0 {
+                    prediction.predict(&mut client, &mut input);
+                    // a dead player is not predicted, respawning moves the player wherever the server spawns it
+                    if let Some(Thing::Player(player)) = client.things.get(*thing_id) {
+                        if player.is_alive() {
+                            predicted.push_back((input.seq, player.pos));
+                        }
+                    }
+                    to_server.push_back((tick + latency_ticks, input));
+                } else {
+                    inputs.push(input);
+                }
+            }
+
+            // inputs of the predicted player arrive at the server late
+            while to_server.front().map(|(arrival, _)| *arrival <= tick).unwrap_or(false) {
+                if let Some((_, input)) = to_server.pop_front() {
+                    processed_seq = input.seq;
+                    inputs.push(input);
+                }
+            }
+
+            server.step(&inputs);
+            to_client.push_back((tick + latency_ticks, processed_seq, server.state.quantized()));
+
+            // and so do the snapshots at the client
+            while to_client.front().map(|(arrival, _, _)| *arrival <= tick).unwrap_or(false) {
+                if let Some((_, seq, snapshot)) = to_client.pop_front() {
+                    let thing_id = server.players.first().copied();
+                    while predicted.front().map(|(s, _)| *s < seq).unwrap_or(false) {
+                        predicted.pop_front();
+                    }
+
+                    let authoritative = thing_id.and_then(|id| snapshot.things.get(id)).map(|thing| *thing.pos());
+                    if let (Some((s, predicted_pos)), Some(pos)) = (predicted.front(), authoritative) {
+                        if *s == seq {
+                            let error = predicted_pos.distance(pos);
+                            stats.samples += 1;
+                            stats.max_error = stats.max_error.max(error);
+                            total_error += error;
+                        }
+                    }
+
+                    let predicted_pos = thing_id.and_then(|id| client.things.get(id)).map(|thing| *thing.pos());
+                    client = snapshot;
+                    prediction.reconcile(&mut client, seq, thing_id, predicted_pos);
+                }
+            }
+        }
+
+        if stats.samples > 0 {
+            stats.mean_error = total_error / stats.samples as f32;
+        }
+
+        stats
+    }
+
+    /// runs the script from the beginning, returning the state hash of each tick
+    pub fn run(state:State, script:&Script) -> Vec<u64> {
+        let mut harness = Harness::new(state, script.seed, script.tick_rate);
+        for player in &script.players {
+            harness.add_player(&player.name);
+        }
+
+        let mut hashes = Vec::with_capacity(script.ticks as usize);
+        for _ in 0..script.ticks {
+            let mut inputs = Vec::new();
+            for (player, thing_id) in script.players.iter().zip(harness.players.iter()) {
+                inputs.push(harness.scripted_input(player, *thing_id));
+            }
+
+            hashes.push(harness.step(&inputs));
+        }
+
+        hashes
+    }
+}
+
+/// hashes the bincode representation of the state using FNV-1a
+pub fn state_hash(state:&State) -> u64 {
+    // the rng is not serialized with the state, but is part of what is simulated
+    let mut bytes = bincode::serialize(state).unwrap_or_default();
+    bytes.extend(bincode::serialize(&state.rng).unwrap_or_default());
+    let mut hash:u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(seed:u64) -> Script {
+        let moving = |from_tick, to_tick, x, y, fire| ScriptedInput {
+            from_tick,
+            to_tick,
+            movement_dir:Vec2::new(x, y),
+            ability_trigger:fire,
+            ability_target:Vec2::new(20.0, 15.0),
+            ability:Ability::default()
+        };
+
+        Script {
+            seed,
+            tick_rate:20,
+            ticks:200,
+            players:vec![
+                ScriptedPlayer {
+                    name:"a".into(),
+                    inputs:vec![moving(0, 60, 1.0, 0.0, false), moving(60, 120, 0.0, 1.0, true), moving(120, 200, -1.0, -1.0, true)]
+                },
+                ScriptedPlayer {
+                    name:"b".into(),
+                    inputs:vec![moving(0, 80, -1.0, 0.0, true), moving(80, 200, 0.0, -1.0, false)]
+                }
+            ]
+        }
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_same_hashes() {
+        let first = Harness::run(State::new(), &script(42));
+        let second = Harness::run(State::new(), &script(42));
+        assert_eq!(first.len(), 200);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn different_seed_diverges() {
+        let first = Harness::run(State::new(), &script(42));
+        let second = Harness::run(State::new(), &script(43));
+        assert_ne!(first, second);
+    }
+
+    /// a single player running around the built-in map, into and along its walls
+    fn running_script() -> Script {
+        let running = |from_tick, to_tick, x, y| ScriptedInput {
+            from_tick,
+            to_tick,
+            movement_dir:Vec2::new(x, y),
+            ..Default::default()
+        };
+
+        Script {
+            seed:7,
+            tick_rate:20,
+            ticks:400,
+            players:vec![ScriptedPlayer {
+                name:"runner".into(),
+                inputs:vec![running(0, 120, 1.0, 0.0), running(120, 200, 0.0, 1.0), running(200, 300, -1.0, 0.3), running(300, 400, 0.7, -0.7)]
+            }]
+        }
+    }
+
+    #[test]
+    fn prediction_without_latency_matches_the_server() {
+        let stats = Harness::measure_prediction(State::new(), &running_script(), 0);
+        assert!(stats.samples > 300);
+        // the client predicts from quantized positions, so it can only be off by the rounding
+        assert!(stats.max_error < 0.01, "max error {}", stats.max_error);
+    }
+
+    #[test]
+    fn prediction_error_is_bounded_with_latency() {
+        let stats = Harness::measure_prediction(State::new(), &running_script(), 5);
+        assert!(stats.samples > 300);
+        assert!(stats.mean_error < 0.01, "mean error {}", stats.mean_error);
+        assert!(stats.max_error < 0.1, "max error {}", stats.max_error);
+    }
+}
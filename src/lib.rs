@@ -8,15 +8,51 @@ pub use state::*;
 mod thing;
 pub use thing::*;
 
+mod things;
+pub use things::*;
+
 mod msg;
 pub use msg::*;
 
 mod player;
 pub use player::*;
 
+mod team;
+pub use team::*;
+
+mod ability;
+pub use ability::*;
+
 mod simulation;
 pub use simulation::*;
 
+mod broadphase;
+pub use broadphase::*;
+
+mod nav;
+pub use nav::*;
+
+mod delta;
+pub use delta::*;
+
+mod prediction;
+pub use prediction::*;
+
+mod mapfile;
+pub use mapfile::*;
+
+mod rng;
+pub use rng::*;
+
+mod harness;
+pub use harness::*;
+
+mod replay;
+pub use replay::*;
+
+mod chat;
+pub use chat::*;
+
 mod platform;
 use hostess::{client::ServerMsg, log::{error}};
 pub use platform::*;
@@ -121,6 +157,16 @@ pub fn mousemove(x:f32, y:f32) {
     }
 }
 
+/// plays back the replay, e.g. from a file dropped on the page
+#[wasm_bindgen]
+pub fn load_replay(data:&[u8]) {
+    unsafe {
+        if let Some(client) = &mut APP {
+            client.load_replay(data);
+        }
+    }
+}
+
 #[wasm_bindgen]
 pub fn connected() {
     unsafe {
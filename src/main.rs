@@ -1,21 +1,193 @@
-use hostess::{master::{Master}, log::{LevelFilter, info}, server::Constructor, client::Uuid, tokio};
+use std::{path::Path, sync::Arc};
 
-use crate::server::Server;
+use hostess::{master::{Master}, log::{LevelFilter, info, warn, error}, server::Constructor, client::Uuid, tokio};
+use sample_lib::{MapError, MapFile, WordListFilter};
+
+use crate::{admin::AuditLog, config::ServerConfig, server::Server, stats::FileStatsStore};
+mod admin;
 mod server;
 mod bot;
+mod stats;
+mod config;
+#[cfg(feature = "scripting")]
+mod script;
 
 #[tokio::main]
 async fn main() {
   
     env_logger::builder().filter_level(LevelFilter::Info).init();
 
+    let args:Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", config::USAGE);
+        return;
+    }
+
+    let config = match ServerConfig::from_args(&args).and_then(|config| config.validate().map(|_| config)) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+
     let working_directory = std::env::current_dir().unwrap_or_default();
     info!("Working directory: {}", working_directory.to_str().unwrap_or_default());
 
-    let mut server = Master::new("0.0.0.0:8080", Constructor::new::<Server>());
+    load_maps(&config);
+
+    // lifetime stats of players are kept in the stats file
+    let stats_path = Path::new(&config.stats_file);
+    match FileStatsStore::open(stats_path) {
+        Ok(store) => {
+            info!("Loaded stats of {} players from {}", store.len(), stats_path.display());
+            server::set_stats_store(Arc::new(store));
+        }
+        Err(err) => {
+            error!("Failed to load stats from {}: {}", stats_path.display(), err);
+            std::process::exit(1);
+        }
+    }
+
+    // rounds are recorded as replays, unless disabled
+    if let Some(replay_dir) = &config.replay_dir {
+        let replay_dir = Path::new(replay_dir);
+        match std::fs::create_dir_all(replay_dir) {
+            Ok(_) => {
+                info!("Recording replays to {}", replay_dir.display());
+                server::set_replay_dir(replay_dir.into());
+            }
+            Err(err) => {
+                warn!("Failed to create replay directory {}, rounds will not be recorded: {}", replay_dir.display(), err);
+            }
+        }
+    }
+
+    // admin commands are recorded in the audit log, whether given in the console or by clients
+    let audit_path = Path::new(&config.audit_log);
+    match AuditLog::open(audit_path) {
+        Ok(audit) => {
+            info!("Recording admin commands to {}", audit_path.display());
+            server::set_audit_log(Arc::new(audit));
+        }
+        Err(err) => {
+            error!("Failed to open audit log {}: {}", audit_path.display(), err);
+            std::process::exit(1);
+        }
+    }
+
+    match &config.admin_password {
+        Some(password) => {
+            info!("Remote admin enabled");
+            server::set_admin_password(password.clone());
+        }
+        None => info!("Remote admin disabled, no admin password configured")
+    }
+
+    // chat messages are filtered with the words of the word filter
+    if let Some(path) = &config.word_filter {
+        let path = Path::new(path);
+        match WordListFilter::load(path) {
+            Ok(filter) => {
+                info!("Filtering {} words from chat", filter.len());
+                server::set_word_filter(Arc::new(filter));
+            }
+            Err(err) => {
+                error!("Failed to load word filter {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // validated above, such that parsing cannot fail
+    let mode = config.game_mode().unwrap_or_default();
+    info!("Playing {}", mode.name());
+    server::set_game_mode(mode);
+    if config.friendly_fire {
+        info!("Friendly fire is on");
+    }
+    server::set_friendly_fire(config.friendly_fire);
+
+    let difficulties = config.difficulties().unwrap_or_default();
+    info!("Bot difficulties: {}", difficulties.iter().map(|d| d.name()).collect::<Vec<_>>().join(", "));
+    server::set_bot_difficulties(difficulties);
+
+    let tick_rates = config.instance_tick_rates();
+    let ticks = tick_rates.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ");
+    info!("{} instances at {} ticks per second with up to {} players, filled up to {} players with bots", config.instances, ticks, config.max_players, config.bot_count);
+    server::set_tick_rates(tick_rates);
+    server::set_max_players(config.max_players);
+    server::set_bot_count(config.bot_count);
+
+    if let Some(path) = &config.bot_script {
+        load_bot_script(Path::new(path));
+    }
+
+    let mut server = Master::new(&config.bind, Constructor::new::<Server>());
+    info!("Listening on {}", config.bind);
     
-    for _ in 0..8 {
+    for _ in 0..config.instances {
         server.new_instance(Uuid::nil()).await;
     }
+
+    info!("Type help for the admin commands");
+    admin::spawn_console(config.instances);
     let _ = server.start().await; 
-}
\ No newline at end of file
+}
+
+/// loads the maps of the rotation from the maps directory, in the order given
+fn load_maps(config:&ServerConfig) {
+    let maps_dir = Path::new(&config.maps_dir);
+    let maps = match MapFile::load_dir(maps_dir) {
+        Ok(maps) => maps,
+        Err(MapError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound && config.maps.is_empty() => {
+            warn!("Map directory {} not found, using built-in map", maps_dir.display());
+            return;
+        }
+        Err(err) => {
+            error!("Failed to load maps from {}: {}", maps_dir.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    let maps = match config.maps.len() {
+        0 => maps,
+        _ => {
+            let mut rotation = Vec::new();
+            for name in config.maps.iter() {
+                match maps.iter().find(|map| &map.name == name) {
+                    Some(map) => rotation.push(map.clone()),
+                    None => {
+                        let found:Vec<&str> = maps.iter().map(|map| map.name.as_str()).collect();
+                        error!("Map {} not found in {}, found {}", name, maps_dir.display(), found.join(", "));
+                        std::process::exit(1);
+                    }
+                }
+            }
+            rotation
+        }
+    };
+
+    info!("Loaded {} maps from {}: {}", maps.len(), maps_dir.display(), maps.iter().map(|map| map.name.as_str()).collect::<Vec<_>>().join(", "));
+    server::set_maps(maps);
+}
+
+#[cfg(feature = "scripting")]
+fn load_bot_script(path:&Path) {
+    match script::BotScript::load(path) {
+        Ok(script) => {
+            info!("Bots running {}", path.display());
+            server::set_bot_brains(Arc::new(move |difficulty| Box::new(script::ScriptBrain::new(script.clone(), difficulty)) as Box<dyn bot::BotBrain>));
+        }
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn load_bot_script(path:&Path) {
+    error!("Cannot run bot script {}, the server is built without the scripting feature", path.display());
+    std::process::exit(1);
+}
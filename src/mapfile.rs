@@ -0,0 +1,305 @@
+use std::{fmt::Display, fs, path::Path};
+
+use generational_arena::Arena;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::{bounds, CaptureZone, Map, PickupSpawn, Polyline, State, TeamSpawn};
+
+/// the version of the map file format written and understood by this build
+pub const MAP_FILE_VERSION:u32 = 1;
+
+/// how far from walls a spawn point must be, i.e. the radius of a player
+const SPAWN_CLEARANCE:f32 = 0.5;
+
+/// the largest width and height of a map, as positions sent to clients are quantized to cover at most 256 units
+pub const MAX_MAP_SIZE:f32 = 255.0;
+
+/// a map as stored on disk in json
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MapFile {
+    pub version:u32,
+    pub name:String,
+    pub width:f32,
+    pub height:f32,
+    /// closed polygons, each given as a list of points
+    pub polylines:Vec<Vec<Vec2>>,
+    pub spawn_points:Vec<Vec2>,
+    #[serde(default)]
+    pub pickups:Vec<PickupSpawn>,
+    #[serde(default)]
+    pub team_spawn_points:Vec<TeamSpawn>,
+    #[serde(default)]
+    pub flags:Vec<TeamSpawn>,
+    #[serde(default)]
+    pub capture_zones:Vec<CaptureZone>
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    UnsupportedVersion(u32),
+    InvalidSize { width:f32, height:f32 },
+    DegeneratePolyline { polyline:usize },
+    NoSpawnPoints,
+    SpawnOutOfBounds { spawn:usize, pos:Vec2 },
+    SpawnInsideWall { spawn:usize, polyline:usize, pos:Vec2 },
+    /// a position of something other than a spawn point is out of bounds, e.g. of a pickup
+    OutOfBounds { what:&'static str, index:usize, pos:Vec2 },
+    InsideWall { what:&'static str, index:usize, polyline:usize, pos:Vec2 },
+    DuplicateFlag { flag:usize }
+}
+
+impl Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapError::Io(err) => write!(f, "could not read map: {}", err),
+            MapError::Parse(err) => write!(f, "could not parse map: {}", err),
+            MapError::UnsupportedVersion(version) => write!(f, "unsupported map version {}, expected {}", version, MAP_FILE_VERSION),
+            MapError::InvalidSize { width, height } => write!(f, "invalid map size {}x{}, at most {}x{}", width, height, MAX_MAP_SIZE, MAX_MAP_SIZE),
+            MapError::DegeneratePolyline { polyline } => write!(f, "polyline {} is degenerate", polyline),
+            MapError::NoSpawnPoints => write!(f, "map has no spawn points"),
+            MapError::SpawnOutOfBounds { spawn, pos } => write!(f, "spawn point {} at {} is out of bounds", spawn, pos),
+            MapError::SpawnInsideWall { spawn, polyline, pos } => write!(f, "spawn point {} at {} is inside polyline {}", spawn, pos, polyline),
+            MapError::OutOfBounds { what, index, pos } => write!(f, "{} {} at {} is out of bounds", what, index, pos),
+            MapError::InsideWall { what, index, polyline, pos } => write!(f, "{} {} at {} is inside polyline {}", what, index, pos, polyline),
+            MapError::DuplicateFlag { flag } => write!(f, "flag {} belongs to a team which already has a flag", flag),
+        }
+    }
+}
+
+impl std::error::Error for MapError {
+}
+
+impl From<std::io::Error> for MapError {
+    fn from(err: std::io::Error) -> Self {
+        MapError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for MapError {
+    fn from(err: serde_json::Error) -> Self {
+        MapError::Parse(err)
+    }
+}
+
+impl MapFile {
+    /// parses and validates a map from json
+    pub fn from_json(json:&str) -> Result<Self, MapError> {
+        let map:MapFile = serde_json::from_str(json)?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// loads and validates a map from a json file
+    pub fn load(path:&Path) -> Result<Self, MapError> {
+        let json = fs::read_to_string(path)?;
+        Self::from_json(&json)
+    }
+
+    /// loads all json maps found in the directory, sorted by file name
+    pub fn load_dir(dir:&Path) -> Result<Vec<Self>, MapError> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+                paths.push(path);
+            }
+        }
+
+        paths.sort();
+        let mut maps = Vec::new();
+        for path in paths {
+            maps.push(Self::load(&path)?);
+        }
+
+        Ok(maps)
+    }
+
+    /// creates a map file from the map of the given state
+    pub fn from_state(name:&str, state:&State) -> Self {
+        Self {
+            version:MAP_FILE_VERSION,
+            name:name.into(),
+            width:state.width,
+            height:state.height,
+            polylines:state.map.polylines.iter().map(|(_, p)| p.points.clone()).collect(),
+            spawn_points:state.map.spawn_points.clone(),
+            pickups:state.map.pickups.clone(),
+            team_spawn_points:state.map.team_spawn_points.clone(),
+            flags:state.map.flags.clone(),
+            capture_zones:state.map.capture_zones.clone()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), MapError> {
+        if self.version != MAP_FILE_VERSION {
+            return Err(MapError::UnsupportedVersion(self.version));
+        }
+
+        if !(self.width > 2.0 && self.height > 3.0 && self.width <= MAX_MAP_SIZE && self.height <= MAX_MAP_SIZE) {
+            return Err(MapError::InvalidSize { width:self.width, height:self.height });
+        }
+
+        for (i, points) in self.polylines.iter().enumerate() {
+            if is_degenerate(points) {
+                return Err(MapError::DegeneratePolyline { polyline:i });
+            }
+        }
+
+        if self.spawn_points.len() == 0 {
+            return Err(MapError::NoSpawnPoints);
+        }
+
+        let (min, max) = bounds(self.width, self.height);
+        for (spawn, pos) in self.spawn_points.iter().enumerate() {
+            if pos.clamp(min, max) != *pos {
+                return Err(MapError::SpawnOutOfBounds { spawn, pos:*pos });
+            }
+
+            for (polyline, points) in self.polylines.iter().enumerate() {
+                if is_inside_polygon(*pos, points) || distance_to_polygon(*pos, points) < SPAWN_CLEARANCE {
+                    return Err(MapError::SpawnInsideWall { spawn, polyline, pos:*pos });
+                }
+            }
+        }
+
+        for (index, pickup) in self.pickups.iter().enumerate() {
+            self.validate_pos("pickup", index, pickup.pos)?;
+        }
+
+        for (index, spawn) in self.team_spawn_points.iter().enumerate() {
+            self.validate_pos("team spawn point", index, spawn.pos)?;
+        }
+
+        for (index, flag) in self.flags.iter().enumerate() {
+            self.validate_pos("flag", index, flag.pos)?;
+            if self.flags[..index].iter().any(|f| f.team == flag.team) {
+                return Err(MapError::DuplicateFlag { flag:index });
+            }
+        }
+
+        for (index, zone) in self.capture_zones.iter().enumerate() {
+            if zone.pos.clamp(min, max) != zone.pos {
+                return Err(MapError::OutOfBounds { what:"capture zone", index, pos:zone.pos });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// ensures things placed at the position are within bounds and clear of walls
+    fn validate_pos(&self, what:&'static str, index:usize, pos:Vec2) -> Result<(), MapError> {
+        let (min, max) = bounds(self.width, self.height);
+        if pos.clamp(min, max) != pos {
+            return Err(MapError::OutOfBounds { what, index, pos });
+        }
+
+        for (polyline, points) in self.polylines.iter().enumerate() {
+            if is_inside_polygon(pos, points) || distance_to_polygon(pos, points) < SPAWN_CLEARANCE {
+                return Err(MapError::InsideWall { what, index, polyline, pos });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// returns a new state with this map
+    pub fn to_state(&self) -> State {
+        let mut map = Map::new();
+        let mut polylines = Arena::new();
+        for points in &self.polylines {
+            polylines.insert(Polyline {
+                points:points.clone()
+            });
+        }
+
+        map.polylines = polylines;
+        map.spawn_points = self.spawn_points.clone();
+        map.pickups = self.pickups.clone();
+        map.team_spawn_points = self.team_spawn_points.clone();
+        map.flags = self.flags.clone();
+        map.capture_zones = self.capture_zones.clone();
+        State::with_map(map, self.width, self.height)
+    }
+}
+
+/// a polygon is degenerate if it has fewer than three points,
+/// repeats a point right after itself or has no area
+fn is_degenerate(points:&Vec<Vec2>) -> bool {
+    if points.len() < 3 {
+        return true;
+    }
+
+    let mut area = 0.0;
+    for i in 0..points.len() {
+        let a = points[i];
+        let b = points[(i + 1) % points.len()];
+        if a == b {
+            return true;
+        }
+
+        area += a.x * b.y - b.x * a.y;
+    }
+
+    area.abs() / 2.0 < f32::EPSILON
+}
+
+fn is_inside_polygon(pos:Vec2, points:&Vec<Vec2>) -> bool {
+    let mut inside = false;
+    let mut j = points.len() - 1;
+    for i in 0..points.len() {
+        let a = points[i];
+        let b = points[j];
+        if (a.y > pos.y) != (b.y > pos.y) && pos.x < (b.x - a.x) * (pos.y - a.y) / (b.y - a.y) + a.x {
+            inside = !inside;
+        }
+
+        j = i;
+    }
+
+    inside
+}
+
+fn distance_to_polygon(pos:Vec2, points:&Vec<Vec2>) -> f32 {
+    let mut dist = f32::MAX;
+    for i in 0..points.len() {
+        let a = points[i];
+        let b = points[(i + 1) % points.len()];
+        let ab = b - a;
+        let t = ((pos - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0);
+        dist = dist.min(pos.distance(a + ab * t));
+    }
+
+    dist
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quantize;
+
+    #[test]
+    fn maps_larger_than_quantized_range_are_rejected() {
+        let mut map = MapFile::from_state("test", &State::new());
+        assert!(map.validate().is_ok());
+
+        map.width = 300.0;
+        assert!(matches!(map.validate(), Err(MapError::InvalidSize { .. })));
+
+        map.width = MAX_MAP_SIZE;
+        map.height = MAX_MAP_SIZE + 1.0;
+        assert!(matches!(map.validate(), Err(MapError::InvalidSize { .. })));
+    }
+
+    #[test]
+    fn largest_map_survives_quantization() {
+        let corner = Vec2::new(MAX_MAP_SIZE, MAX_MAP_SIZE);
+        assert!(quantize(corner).distance(corner) < 1.0 / 128.0);
+    }
+}
@@ -1,28 +1,191 @@
-use crate::{Input, State};
+use std::fmt::Display;
+
+use crate::{Input, State, Team};
 use generational_arena::Index;
-use hostess::{client::Bincoded};
+use hostess::{client::Bincoded, uuid::Uuid};
 use serde::{Serialize, Deserialize};
 
+/// a command operating an instance, given in the server console or by a client authenticated as admin
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AdminCommand {
+    /// lists the players of the instance
+    Players,
+    Kick {
+        name:String
+    },
+    /// kicks the player and keeps the client from joining any instance until the server restarts
+    Ban {
+        name:String
+    },
+    /// ends the round and plays the map, or the next map in the rotation if None
+    ChangeMap {
+        map:Option<String>
+    },
+    /// ends the round and starts a new round on the same map
+    RestartRound,
+    SetBotCount {
+        count:usize
+    },
+    SetTickRate {
+        tick_rate:u64
+    },
+    /// shows the text to every player of the instance
+    Broadcast {
+        text:String
+    }
+}
+
+impl AdminCommand {
+    pub const HELP:[&'static str; 8] = [
+        "players - lists the players",
+        "kick <name or client id> - kicks the player",
+        "ban <name or client id> - kicks the player and bans its client id until the server restarts",
+        "map [name] - changes to the map, or to the next map in the rotation",
+        "restart - restarts the round",
+        "bots <count> - fills up to the number of players with bots",
+        "tickrate <ticks per second> - changes the tick rate",
+        "say <text> - shows the text to every player"
+    ];
+
+    /// parses a command written as shown by `HELP`
+    pub fn parse(line:&str) -> Result<Self, String> {
+        let line = line.trim();
+        let (command, arg) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, "")
+        };
+        let required = |what:&str| match arg.is_empty() {
+            true => Err(format!("{} expects {}", command, what)),
+            false => Ok(arg.to_string())
+        };
+        let number = |what:&str| arg.parse::<usize>().map_err(|_| format!("{} expects {}, got '{}'", command, what, arg));
+
+        match command.to_lowercase().as_str() {
+            "players" => Ok(AdminCommand::Players),
+            "kick" => Ok(AdminCommand::Kick { name:required("a name or client id")? }),
+            "ban" => Ok(AdminCommand::Ban { name:required("a name or client id")? }),
+            "map" => Ok(AdminCommand::ChangeMap { map:Some(arg.to_string()).filter(|map| !map.is_empty()) }),
+            "restart" => Ok(AdminCommand::RestartRound),
+            "bots" => Ok(AdminCommand::SetBotCount { count:number("a count")? }),
+            "tickrate" => Ok(AdminCommand::SetTickRate { tick_rate:number("ticks per second")? as u64 }),
+            "say" => Ok(AdminCommand::Broadcast { text:required("a text")? }),
+            _ => Err(format!("unknown command '{}'", command))
+        }
+    }
+}
+
+impl Display for AdminCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminCommand::Players => write!(f, "players"),
+            AdminCommand::Kick { name } => write!(f, "kick {}", name),
+            AdminCommand::Ban { name } => write!(f, "ban {}", name),
+            AdminCommand::ChangeMap { map:Some(map) } => write!(f, "map {}", map),
+            AdminCommand::ChangeMap { map:None } => write!(f, "map"),
+            AdminCommand::RestartRound => write!(f, "restart"),
+            AdminCommand::SetBotCount { count } => write!(f, "bots {}", count),
+            AdminCommand::SetTickRate { tick_rate } => write!(f, "tickrate {}", tick_rate),
+            AdminCommand::Broadcast { text } => write!(f, "say {}", text)
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CustomMsg {
     ServerSnapshotFull {
-        /// the timestamp of the last input recv and processed by the server
-        input_timestamp_sec:f64,
+        /// the sequence number of the snapshot
+        seq:u32,
+        /// the sequence number of the last input recv and processed by the server
+        input_seq:u32,
         state:State
     },
     ServerSnapshotDelta {
-        /// the timestamp of the last input recv and processed by the server
-        input_timestamp_sec:f64,
+        /// the sequence number of the snapshot
+        seq:u32,
+        /// the sequence number of the snapshot acknowledged by the client which the delta is based on
+        baseline_seq:u32,
+        /// the sequence number of the last input recv and processed by the server
+        input_seq:u32,
+        /// encoded StateDelta from the baseline snapshot
         delta:Vec<u8>
     },
     ServerPlayerInfo {
         thing_id:Option<Index>,
-        tick_rate:u8
+        tick_rate:u8,
+        /// true if the player is spectating, never having a thing
+        spectator:bool
+    },
+
+    /// the client has been kicked and is ignored by the server from now on
+    ServerKicked {
+        reason:String
+    },
+
+    /// a message from an admin to every player of the instance
+    ServerBroadcast {
+        text:String
+    },
+
+    /// a chat message from a player, relayed to the players of the instance
+    ServerChat {
+        /// the name of the player saying it
+        from:String,
+        /// the team of the player saying it, if any
+        team:Option<Team>,
+        /// true if only said to the team of the player
+        team_only:bool,
+        text:String
+    },
+
+    /// a chat message from the client was not relayed, e.g. because it was sent too fast
+    ServerChatRejected {
+        reason:String
+    },
+
+    /// the outcome of an admin login or command sent by the client
+    ServerAdminResult {
+        ok:bool,
+        message:String
     },
 
     /// input from a client, such as position, ability usage, e.g.
     ClientInput {
         input:Input
+    },
+
+    /// asks to spectate instead of playing, or to play instead of spectating
+    /// players only stop spectating if the game is not full
+    ClientSpectate {
+        spectate:bool
+    },
+
+    /// says the text to every player, or only to the team of the player
+    ClientChat {
+        text:String,
+        team_only:bool
+    },
+
+    /// authenticates the client as admin of the instance
+    /// clients failing to authenticate too many times are kicked
+    ClientAdminLogin {
+        password:String
+    },
+
+    /// runs the command, only if the client is authenticated as admin
+    ClientAdminCommand {
+        command:AdminCommand
+    },
+
+    /// acknowledges that a snapshot was received by the client
+    /// such that it can be used as baseline for later snapshots
+    ClientSnapshotAck {
+        seq:u32
+    },
+
+    /// the identity of the player kept by the browser, which lifetime stats are recorded by
+    /// unlike the client id, which differs for every connection, it is shared by every tab of the browser
+    ClientIdentity {
+        player_id:Uuid
     }
 }
 
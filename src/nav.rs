@@ -0,0 +1,317 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use glam::Vec2;
+
+use crate::{bounds, Map, MapCollider};
+
+/// distance between the nodes of the navigation graph
+const NAV_CELL_SIZE:f32 = 1.0;
+
+/// the number of nodes closest to a position checked for being reachable from it
+const NEAREST_CANDIDATES:usize = 8;
+
+/// a graph of the positions a thing can stand on, connected to the neighbouring positions it can move to
+/// the nodes are placed on a grid covering the bounds of the map, leaving out those too close to or inside walls
+#[derive(Clone, Debug, Default)]
+pub struct NavGraph {
+    pub nodes:Vec<Vec2>,
+    /// the nodes connected to each node
+    pub edges:Vec<Vec<usize>>,
+    /// the distance kept from walls
+    pub clearance:f32,
+    colliders:Vec<MapCollider>
+}
+
+/// a node to be visited by A*, ordered such that the lowest estimated cost is visited first
+struct Open {
+    node:usize,
+    estimate:f32
+}
+
+impl PartialEq for Open {
+    fn eq(&self, other:&Self) -> bool {
+        self.estimate == other.estimate
+    }
+}
+
+impl Eq for Open {
+}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other:&Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Open {
+    fn cmp(&self, other:&Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate)
+    }
+}
+
+impl NavGraph {
+    /// builds the graph of the map, keeping the clearance from the polylines of the map
+    pub fn new(map:&Map, width:f32, height:f32, clearance:f32) -> Self {
+        let colliders:Vec<MapCollider> = map.colliders().iter().cloned().collect();
+        let (min, max) = bounds(width, height);
+        let columns = ((max.x - min.x) / NAV_CELL_SIZE).floor() as usize + 1;
+        let rows = ((max.y - min.y) / NAV_CELL_SIZE).floor() as usize + 1;
+
+        let mut graph = Self {
+            nodes:Vec::new(),
+            edges:Vec::new(),
+            clearance,
+            colliders
+        };
+
+        // the node of each cell of the grid, if any
+        let mut grid = vec![None; columns * rows];
+        for y in 0..rows {
+            for x in 0..columns {
+                let pos = min + Vec2::new(x as f32, y as f32) * NAV_CELL_SIZE;
+                if graph.is_free(pos) {
+                    grid[y * columns + x] = Some(graph.nodes.len());
+                    graph.nodes.push(pos);
+                    graph.edges.push(Vec::new());
+                }
+            }
+        }
+
+        for y in 0..rows {
+            for x in 0..columns {
+                let node = match grid[y * columns + x] {
+                    Some(node) => node,
+                    None => continue
+                };
+
+                // the neighbours to the right and below, the others are connected from their side
+                for (dx, dy) in [(1, 0), (0, 1), (1, 1), (-1, 1)] {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if nx < 0 || nx as usize >= columns || ny as usize >= rows {
+                        continue;
+                    }
+
+                    if let Some(other) = grid[ny as usize * columns + nx as usize] {
+                        if graph.is_clear(graph.nodes[node], graph.nodes[other], clearance) {
+                            graph.edges[node].push(other);
+                            graph.edges[other].push(node);
+                        }
+                    }
+                }
+            }
+        }
+
+        graph
+    }
+
+    /// true if a thing with the clearance as radius can stand at pos without touching or being inside a wall
+    fn is_free(&self, pos:Vec2) -> bool {
+        self.colliders.iter().all(|collider| {
+            if !collider.overlaps(pos, self.clearance) {
+                return true;
+            }
+
+            collider.segments().all(|(a, b)| distance_to_segment(pos, a, b) > self.clearance) && !contains(collider, pos)
+        })
+    }
+
+    /// true if a circle with the radius can move in a straight line from a to b without hitting a wall
+    /// with a radius of zero, this is whether b can be seen from a
+    pub fn is_clear(&self, a:Vec2, b:Vec2, radius:f32) -> bool {
+        let center = (a + b) * 0.5;
+        let reach = radius + a.distance(b) * 0.5;
+        self.colliders.iter().all(|collider| {
+            !collider.overlaps(center, reach) || collider.segments().all(|(c, d)| distance_between_segments(a, b, c, d) > radius)
+        })
+    }
+
+    /// the node closest to pos which can be reached in a straight line
+    /// or the closest node if none of the few closest can be reached
+    pub fn nearest(&self, pos:Vec2) -> Option<usize> {
+        // the closest nodes seen so far by distance, found in a single pass without allocating
+        let mut closest = [(f32::MAX, usize::MAX); NEAREST_CANDIDATES];
+        for (node, p) in self.nodes.iter().enumerate() {
+            let d = p.distance_squared(pos);
+            if d >= closest[NEAREST_CANDIDATES - 1].0 {
+                continue;
+            }
+
+            let mut i = NEAREST_CANDIDATES - 1;
+            while i > 0 && closest[i - 1].0 > d {
+                closest[i] = closest[i - 1];
+                i -= 1;
+            }
+            closest[i] = (d, node);
+        }
+
+        let mut candidates = closest.iter().map(|(_, node)| *node).filter(|node| *node != usize::MAX);
+        let first = candidates.clone().next();
+        candidates.find(|node| self.is_clear(pos, self.nodes[*node], 0.0)).or(first)
+    }
+
+    /// finds the shortest path from one position to another using A*
+    /// returns the positions to move through, ending with the position moved to
+    /// or None if the position cannot be reached
+    pub fn find_path(&self, from:Vec2, to:Vec2) -> Option<Vec<Vec2>> {
+        if self.is_clear(from, to, self.clearance) {
+            return Some(vec![to]);
+        }
+
+        let start = self.nearest(from)?;
+        let goal = self.nearest(to)?;
+        let mut cost = vec![f32::MAX; self.nodes.len()];
+        let mut came_from = vec![usize::MAX; self.nodes.len()];
+        let mut open = BinaryHeap::new();
+        cost[start] = 0.0;
+        open.push(Open {
+            node:start,
+            estimate:self.nodes[start].distance(self.nodes[goal])
+        });
+
+        while let Some(Open { node, estimate }) = open.pop() {
+            if node == goal {
+                let mut path = vec![to];
+                let mut node = goal;
+                while node != start {
+                    path.push(self.nodes[node]);
+                    node = came_from[node];
+                }
+                path.push(self.nodes[start]);
+                path.reverse();
+                return Some(path);
+            }
+
+            // already visited at a lower cost
+            if estimate - self.nodes[node].distance(self.nodes[goal]) > cost[node] {
+                continue;
+            }
+
+            for next in &self.edges[node] {
+                let next = *next;
+                let next_cost = cost[node] + self.nodes[node].distance(self.nodes[next]);
+                if next_cost < cost[next] {
+                    cost[next] = next_cost;
+                    came_from[next] = node;
+                    open.push(Open {
+                        node:next,
+                        estimate:next_cost + self.nodes[next].distance(self.nodes[goal])
+                    });
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// the distance from p to the closest point of the segment from a to b
+fn distance_to_segment(p:Vec2, a:Vec2, b:Vec2) -> f32 {
+    let edge = b - a;
+    if edge.length_squared() == 0.0 {
+        return p.distance(a);
+    }
+
+    let t = ((p - a).dot(edge) / edge.length_squared()).clamp(0.0, 1.0);
+    p.distance(a + edge * t)
+}
+
+/// the distance between the closest points of the segment from a to b and the segment from c to d
+fn distance_between_segments(a:Vec2, b:Vec2, c:Vec2, d:Vec2) -> f32 {
+    let cross = |o:Vec2, p:Vec2, q:Vec2| (p - o).perp_dot(q - o);
+    let (d1, d2) = (cross(a, b, c), cross(a, b, d));
+    let (d3, d4) = (cross(c, d, a), cross(c, d, b));
+    if d1 * d2 < 0.0 && d3 * d4 < 0.0 {
+        return 0.0;
+    }
+
+    distance_to_segment(a, c, d)
+        .min(distance_to_segment(b, c, d))
+        .min(distance_to_segment(c, a, b))
+        .min(distance_to_segment(d, a, b))
+}
+
+/// true if pos is inside the closed polyline of the collider
+fn contains(collider:&MapCollider, pos:Vec2) -> bool {
+    let mut inside = false;
+    for (a, b) in collider.segments() {
+        if (a.y > pos.y) != (b.y > pos.y) && pos.x < a.x + (pos.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::{Polyline, SimRng, State};
+
+    /// the nearest node as found by sorting every node
+    fn nearest_sorted(nav:&NavGraph, pos:Vec2) -> Option<usize> {
+        let mut nodes:Vec<usize> = (0..nav.nodes.len()).collect();
+        nodes.sort_by(|a, b| nav.nodes[*a].distance_squared(pos).total_cmp(&nav.nodes[*b].distance_squared(pos)));
+        nodes.iter().take(NEAREST_CANDIDATES).copied().find(|node| nav.is_clear(pos, nav.nodes[*node], 0.0)).or(nodes.first().copied())
+    }
+
+    #[test]
+    fn nearest_matches_sorting_every_node() {
+        let state = State::new();
+        let nav = NavGraph::new(&state.map, state.width, state.height, 0.6);
+        let mut rng = SimRng::new(3);
+        for _ in 0..500 {
+            let pos = Vec2::new(rng.gen_range(-5.0..45.0), rng.gen_range(-5.0..35.0));
+            assert_eq!(nav.nearest(pos), nearest_sorted(&nav, pos), "at {:?}", pos);
+        }
+
+        assert_eq!(NavGraph::default().nearest(Vec2::ZERO), None);
+    }
+
+    /// a map of 40 by 30 with a wall from top to bottom at x 20, leaving a gap above y 22 unless closed
+    fn walled(closed:bool) -> NavGraph {
+        let bottom = if closed { 35.0 } else { 22.0 };
+        let mut map = Map::new();
+        map.polylines.insert(Polyline {
+            points:vec![Vec2::new(19.5, -5.0), Vec2::new(20.5, -5.0), Vec2::new(20.5, bottom), Vec2::new(19.5, bottom)]
+        });
+        NavGraph::new(&map, 40.0, 30.0, 0.6)
+    }
+
+    #[test]
+    fn path_goes_around_the_wall() {
+        let nav = walled(false);
+        let (from, to) = (Vec2::new(10.0, 10.0), Vec2::new(30.0, 10.0));
+        let path = nav.find_path(from, to).expect("the gap should be found");
+
+        assert_eq!(path.last(), Some(&to));
+        assert!(path.iter().any(|p| p.y > 22.0), "{:?} does not go through the gap", path);
+        let mut length = 0.0;
+        let mut pos = from;
+        for next in &path {
+            assert!(nav.is_clear(pos, *next, 0.0), "{:?} to {:?} goes through the wall", pos, next);
+            length += pos.distance(*next);
+            pos = *next;
+        }
+
+        // down to the gap and back up, while not much longer than that
+        let around = from.distance(Vec2::new(20.0, 22.6)) + Vec2::new(20.0, 22.6).distance(to);
+        assert!(length >= around - 1e-3 && length < around * 1.2, "{} for {}", length, around);
+    }
+
+    #[test]
+    fn path_in_the_open_goes_straight() {
+        let nav = walled(false);
+        let to = Vec2::new(15.0, 5.0);
+        assert_eq!(nav.find_path(Vec2::new(5.0, 20.0), to), Some(vec![to]));
+    }
+
+    #[test]
+    fn unreachable_goal_has_no_path() {
+        let nav = walled(true);
+        assert_eq!(nav.find_path(Vec2::new(10.0, 10.0), Vec2::new(30.0, 10.0)), None);
+        // while each side can still be crossed
+        assert!(nav.find_path(Vec2::new(25.0, 5.0), Vec2::new(35.0, 25.0)).is_some());
+    }
+}
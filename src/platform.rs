@@ -104,10 +104,14 @@ impl Canvas {
         self.context.stroke();
     }
 
-    pub fn _fill_rect(&self, x:f64, y:f64, w:f64, h:f64) {
+    pub fn fill_rect(&self, x:f64, y:f64, w:f64, h:f64) {
         self.context.fill_rect(x, y, w, h);
     }
 
+    pub fn set_global_alpha(&self, alpha:f64) {
+        self.context.set_global_alpha(alpha);
+    }
+
     pub fn set_text_style(&self, text_align:&str, baseline:&str) {
         self.context.set_text_align(text_align);
         self.context.set_text_baseline(baseline);
@@ -143,6 +147,15 @@ impl Canvas {
         }
     }
 
+    /// scales such that the center is in the middle of the canvas
+    pub fn set_camera(&self, scale:f64, center_x:f64, center_y:f64) {
+        let _ = self.context.set_transform(1.0, 0.0, 0.0, 1.0, 0.0, 0.0);
+        let _ = self.context.translate(self.width() as f64 / 2.0, self.height() as f64 / 2.0);
+        let _ = self.context.scale(scale, scale);
+        let _ = self.context.translate(-center_x, -center_y);
+        self.context.set_line_width(1.0 / scale);
+    }
+
     pub fn set_scale(&self, scale:f64) {
         let _ = self.context.set_transform(1.0, 0.0, 0.0, 1.0, 0.0, 0.0);
         let _ = self.context.scale(scale, scale);
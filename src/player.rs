@@ -5,15 +5,22 @@ use glam::Vec2;
 use hostess::uuid::Uuid;
 use serde::{Deserialize, Serialize};
 
-use crate::State;
+use crate::{Ability, ChatLimiter, Team};
 
 /// struct holding Input for a player
 /// send by clients to the server
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Input {
+    /// the sequence number of the input, increasing with each input made by the client
+    pub seq:u32,
+
     /// the timestamp of the input
     pub timestamp_sec:f64,
 
+    /// the server timestamp of the state the player was looking at when the input was made
+    /// i.e. the interpolated time between the two latest snapshots received by the client
+    pub view_timestamp_sec:f64,
+
     /// the id of the thing controlled by a player owning the Input
     pub thing_id:Option<Index>,
 
@@ -29,23 +36,238 @@ pub struct Input {
 
     /// where the player is targeting in the world
     pub ability_target:Vec2,
+
+    /// the ability the player wants to use
+    pub ability:Ability,
 }
 
 
+/// the most time a player can save up to move for, e.g. when inputs arrive in bursts
+const MAX_MOVEMENT_BUDGET_SEC:f32 = 0.5;
+
+/// how much movement beyond the budget is tolerated before it counts as a violation
+const MOVEMENT_TOLERANCE_SEC:f32 = 0.1;
+
+/// the most inputs kept for processing, the oldest are dropped when more arrive
+pub const MAX_QUEUED_INPUTS:usize = 16;
+
+/// how often a violation is forgiven
+const VIOLATION_DECAY_SEC:f32 = 10.0;
+
+/// the violations after which a player is kicked
+pub const KICK_VIOLATIONS:u32 = 10;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Player {
     pub client_id:Uuid,
+    /// the identity lifetime stats are recorded by, the client id until the client tells its identity
+    pub player_id:Uuid,
     pub client_name:String,
     pub thing:Option<Index>,
-    pub latest_input_timestamp_sec:f64,
+    /// the team of the player, if playing a team based game mode
+    pub team:Option<Team>,
+    /// true if the player is only watching, never having a thing or a team
+    pub spectator:bool,
+    /// the sequence number of the last input processed
+    pub latest_input_seq:u32,
     pub inputs:VecDeque<Input>,
-    /// last state transmitted to player
-    pub state:State
+    /// the sequence number of the newest snapshot acknowledged by the player
+    pub acked_seq:Option<u32>,
+    /// seconds of movement the player is allowed, grows as time passes on the server
+    pub movement_budget_sec:f32,
+    /// the number of invalid inputs recently received from the player
+    pub violations:u32,
+    pub violation_decay_sec:f32,
+    /// true once authenticated as admin of the instance
+    pub admin:bool,
+    /// limits how often the player can chat
+    pub chat:ChatLimiter
 }
 
 impl Player {
-   
+    pub fn new(client_id:Uuid, client_name:String) -> Self {
+        Self {
+            client_id,
+            player_id:client_id,
+            client_name,
+            thing:None,
+            team:None,
+            spectator:false,
+            latest_input_seq:0,
+            inputs:VecDeque::default(),
+            acked_seq:None,
+            movement_budget_sec:0.0,
+            violations:0,
+            violation_decay_sec:VIOLATION_DECAY_SEC,
+            admin:false,
+            chat:ChatLimiter::default()
+        }
+    }
+
+    /// gives the player time to move as time passes on the server, and forgives old violations
+    pub fn grant_time(&mut self, delta:f32) {
+        self.movement_budget_sec = (self.movement_budget_sec + delta).min(MAX_MOVEMENT_BUDGET_SEC);
+        self.violation_decay_sec -= delta;
+        if self.violation_decay_sec <= 0.0 {
+            self.violations = self.violations.saturating_sub(1);
+            self.violation_decay_sec = VIOLATION_DECAY_SEC;
+        }
+    }
+
+    /// queues the input for processing, unless not newer than the inputs already received
+    /// when too many inputs are queued, e.g. arriving in a burst after a network hiccup, the oldest are dropped
+    pub fn queue_input(&mut self, input:Input) {
+        let newest_seq = self.inputs.back().map(|input| input.seq).unwrap_or(self.latest_input_seq);
+        if input.seq <= newest_seq {
+            return;
+        }
+
+        if self.inputs.len() >= MAX_QUEUED_INPUTS {
+            self.inputs.pop_front();
+        }
+
+        self.inputs.push_back(input);
+    }
+
+    /// true if the player sent too many invalid inputs recently
+    pub fn should_kick(&self) -> bool {
+        self.violations >= KICK_VIOLATIONS
+    }
+
+    /// makes the input valid for the player
+    /// the thing controlled is always the thing of the player, whatever the client claims
+    /// and movement is limited by the time the player has been granted
+    pub fn validate_input(&mut self, input:&mut Input) {
+        input.thing_id = self.thing;
+
+        // movement is the direction scaled by the time passed on the client
+        // the components of the direction are at most one, thus the largest component is the time
+        let time = input.movement.x.abs().max(input.movement.y.abs());
+        if !input.movement.is_finite() {
+            input.movement = Vec2::ZERO;
+            self.violations += 1;
+            return;
+        }
+
+        if time > self.movement_budget_sec {
+            if time > self.movement_budget_sec + MOVEMENT_TOLERANCE_SEC {
+                self.violations += 1;
+            }
+
+            input.movement *= self.movement_budget_sec / time;
+        }
+
+        self.movement_budget_sec -= time.min(self.movement_budget_sec);
+    }
+
     pub fn clear_inputs(&mut self) {
         self.inputs.clear();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_SEC:f32 = 0.05;
+
+    fn input(seq:u32, time:f32) -> Input {
+        Input {
+            seq,
+            movement:Vec2::new(time, 0.0),
+            ..Default::default()
+        }
+    }
+
+    /// processes the queued inputs as the server does every tick, returning the movement allowed
+    fn tick(player:&mut Player) -> f32 {
+        player.grant_time(TICK_SEC);
+        let mut moved = 0.0;
+        for mut input in std::mem::take(&mut player.inputs) {
+            player.validate_input(&mut input);
+            player.latest_input_seq = input.seq;
+            moved += input.movement.x;
+        }
+
+        moved
+    }
+
+    #[test]
+    fn bursty_honest_inputs_are_not_violations() {
+        let mut player = Player::new(Uuid::nil(), "honest".into());
+        let frame = 1.0 / 60.0;
+        let mut seq = 0;
+        let mut moved = 0.0;
+        let mut elapsed = 0.0;
+        for t in 0..200 {
+            // every half second, nothing arrives for a while, after which the inputs made meanwhile arrive at once
+            let burst = match t % 10 {
+                0..=6 => 0,
+                7 => 24,
+                _ => 3
+            };
+            for _ in 0..burst {
+                seq += 1;
+                player.queue_input(input(seq, frame));
+            }
+
+            moved += tick(&mut player);
+            elapsed += TICK_SEC;
+            assert!(player.inputs.len() <= MAX_QUEUED_INPUTS);
+        }
+
+        assert_eq!(player.violations, 0);
+        assert!(!player.should_kick());
+        assert!(moved <= elapsed + MAX_MOVEMENT_BUDGET_SEC);
+        // only the oldest inputs of a burst are dropped
+        assert_eq!(player.latest_input_seq, seq);
+    }
+
+    #[test]
+    fn speed_hack_is_limited_and_kicked() {
+        let mut player = Player::new(Uuid::nil(), "cheater".into());
+        let mut moved = 0.0;
+        for seq in 1..=KICK_VIOLATIONS {
+            // moving four times as fast as time passes
+            player.queue_input(input(seq, TICK_SEC * 4.0));
+            moved += tick(&mut player);
+        }
+
+        assert!(moved <= KICK_VIOLATIONS as f32 * TICK_SEC + 1e-4);
+        assert!(player.should_kick());
+    }
+
+    #[test]
+    fn old_and_repeated_inputs_are_dropped() {
+        let mut player = Player::new(Uuid::nil(), "replaying".into());
+        player.queue_input(input(2, TICK_SEC));
+        player.queue_input(input(2, TICK_SEC));
+        player.queue_input(input(1, TICK_SEC));
+        assert_eq!(player.inputs.len(), 1);
+
+        tick(&mut player);
+        player.queue_input(input(2, TICK_SEC));
+        assert!(player.inputs.is_empty());
+        player.queue_input(input(3, TICK_SEC));
+        assert_eq!(player.inputs.len(), 1);
+    }
+
+    #[test]
+    fn invalid_movement_is_a_violation_which_decays() {
+        let mut player = Player::new(Uuid::nil(), "broken".into());
+        player.thing = Some(Index::from_raw_parts(1, 0));
+        let mut invalid = input(1, f32::NAN);
+        invalid.thing_id = Some(Index::from_raw_parts(2, 0));
+        player.validate_input(&mut invalid);
+
+        assert_eq!(invalid.movement, Vec2::ZERO);
+        // the thing of another player cannot be steered
+        assert_eq!(invalid.thing_id, player.thing);
+        assert_eq!(player.violations, 1);
+
+        for _ in 0..(VIOLATION_DECAY_SEC / TICK_SEC) as usize + 1 {
+            player.grant_time(TICK_SEC);
+        }
+        assert_eq!(player.violations, 0);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+
+use generational_arena::Index;
+use glam::Vec2;
+
+use crate::{apply_input, use_ability, Broadphase, Input, State};
+
+/// how fast the correction error is smoothed away, per second
+const SMOOTHING_RATE:f32 = 10.0;
+
+/// corrections larger than this are not smoothed, the thing is snapped into place instead
+const SNAP_DISTANCE:f32 = 2.0;
+
+/// client side prediction of the thing controlled by the player
+/// inputs are applied locally right away and remembered until the server
+/// has processed them, at which point the remaining inputs are replayed on top
+/// of the authoritative state received from the server
+pub struct Prediction {
+    next_seq:u32,
+    /// inputs applied locally, but not yet processed by the server
+    pending:VecDeque<Input>,
+    /// offset from the predicted position to where the thing is shown, decays towards zero
+    error:Vec2
+}
+
+impl Prediction {
+    pub fn new() -> Self {
+        Self {
+            next_seq:1,
+            pending:VecDeque::new(),
+            error:Vec2::ZERO
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.error = Vec2::ZERO;
+    }
+
+    /// the number of inputs not yet processed by the server
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// stamps the input with the next sequence number and applies it to the state
+    pub fn predict(&mut self, state:&mut State, input:&mut Input) {
+        input.seq = self.next_seq;
+        self.next_seq += 1;
+        let broadphase = Broadphase::new(state);
+        Self::apply(state, &broadphase, input);
+        self.pending.push_back(input.clone());
+    }
+
+    /// reconciles the authoritative state from the server with the prediction
+    /// inputs up to and including `processed_seq` are forgotten, the rest are replayed onto the state
+    /// the difference between the previous prediction and the new prediction is smoothed out over time
+    pub fn reconcile(&mut self, state:&mut State, processed_seq:u32, thing_id:Option<Index>, predicted_pos:Option<Vec2>) {
+        while let Some(input) = self.pending.front() {
+            if input.seq > processed_seq {
+                break;
+            }
+
+            self.pending.pop_front();
+        }
+
+        let broadphase = Broadphase::new(state);
+        for input in self.pending.iter() {
+            Self::apply(state, &broadphase, input);
+        }
+
+        let pos = thing_id.and_then(|id| state.things.get(id)).map(|thing| *thing.pos());
+        if let (Some(predicted_pos), Some(pos)) = (predicted_pos, pos) {
+            self.error += predicted_pos - pos;
+            if self.error.length() > SNAP_DISTANCE {
+                self.error = Vec2::ZERO;
+            }
+        }
+    }
+
+    /// applies the input as the server would, using the ability to predict cooldown and ammo
+    /// the projectiles and events are left for the server to send
+    fn apply(state:&mut State, broadphase:&Broadphase, input:&Input) {
+        apply_input(state, broadphase, input, false);
+        if let Some(thing_id) = input.thing_id {
+            let events = state.events.len();
+            use_ability(state, thing_id, input.ability_trigger, input.ability_target);
+            state.events.truncate(events);
+        }
+    }
+
+    /// decays the correction error
+    pub fn update(&mut self, dt:f32) {
+        self.error *= 1.0 - (dt * SMOOTHING_RATE).min(1.0);
+    }
+
+    /// returns where the predicted thing at pos should be shown
+    pub fn smoothed_pos(&self, pos:Vec2) -> Vec2 {
+        pos + self.error
+    }
+}
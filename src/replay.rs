@@ -0,0 +1,281 @@
+use std::{fmt::Display, io::{Read, Write}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{state_hash, State, StateDelta};
+
+/// the version of the replay format, increased when the format or the state changes
+pub const REPLAY_VERSION:u32 = 1;
+
+/// the states of a replay kept while playing it back, such that seeking only applies a few deltas
+const KEYFRAME_INTERVAL:usize = 100;
+
+/// the start of a replay file, followed by a frame for each tick
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplayHeader {
+    pub version:u32,
+    pub tick_rate:u8,
+    /// the state the first frame is based on, as seen by the clients
+    pub initial:State
+}
+
+/// a tick of a replay, holding the delta snapshot from the state of the previous frame
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplayFrame {
+    pub seq:u32,
+    /// encoded `StateDelta`, including the events of the tick
+    pub delta:Vec<u8>,
+    /// the hash of the state derived by applying the delta
+    pub checksum:u64
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(std::io::Error),
+    Encoding(bincode::Error),
+    Version {
+        found:u32,
+        expected:u32
+    },
+    /// the frame could not be decoded or applied
+    Corrupt {
+        seq:u32
+    },
+    /// the state derived from the frame differs from the state derived when recording
+    Checksum {
+        seq:u32,
+        expected:u64,
+        found:u64
+    }
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "{}", err),
+            ReplayError::Encoding(err) => write!(f, "{}", err),
+            ReplayError::Version { found, expected } => write!(f, "replay version {} is not supported, expected {}", found, expected),
+            ReplayError::Corrupt { seq } => write!(f, "frame {} is corrupt", seq),
+            ReplayError::Checksum { seq, expected, found } => write!(f, "frame {} has checksum {:016x}, expected {:016x}", seq, found, expected)
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {
+}
+
+impl From<std::io::Error> for ReplayError {
+    fn from(err:std::io::Error) -> Self {
+        ReplayError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for ReplayError {
+    fn from(err:bincode::Error) -> Self {
+        ReplayError::Encoding(err)
+    }
+}
+
+/// records a replay as the states of each tick are given, writing a frame per tick
+pub struct ReplayWriter<W:Write> {
+    writer:W,
+    /// the state derived from the previous frame, which the next frame is based on
+    prev:State,
+    seq:u32
+}
+
+impl<W:Write> ReplayWriter<W> {
+    /// starts the replay from the state, expected to be quantized
+    pub fn new(mut writer:W, tick_rate:u8, initial:&State) -> Result<Self, ReplayError> {
+        let header = ReplayHeader {
+            version:REPLAY_VERSION,
+            tick_rate,
+            initial:initial.clone()
+        };
+        let bytes = bincode::serialize(&header)?;
+        writer.write_all(&bytes)?;
+
+        // the first frame is based on the initial state as it is read back, such that playback derives the same states
+        let header:ReplayHeader = bincode::deserialize(&bytes)?;
+        Ok(Self {
+            writer,
+            prev:header.initial,
+            seq:0
+        })
+    }
+
+    /// records the state of the next tick, expected to be quantized
+    pub fn record(&mut self, state:&State) -> Result<(), ReplayError> {
+        let delta = StateDelta::new(&self.prev, state);
+        self.seq += 1;
+        self.prev = delta.apply(&self.prev).ok_or(ReplayError::Corrupt { seq:self.seq })?;
+        let frame = ReplayFrame {
+            seq:self.seq,
+            delta:delta.encode(),
+            checksum:state_hash(&self.prev)
+        };
+        bincode::serialize_into(&mut self.writer, &frame)?;
+        Ok(())
+    }
+
+    /// the number of frames recorded
+    pub fn frames(&self) -> u32 {
+        self.seq
+    }
+
+    /// flushes the frames recorded, returning the writer
+    pub fn finish(mut self) -> Result<W, ReplayError> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// a recorded replay
+#[derive(Clone, Debug)]
+pub struct Replay {
+    pub header:ReplayHeader,
+    pub frames:Vec<ReplayFrame>
+}
+
+impl Replay {
+    /// reads the header and every frame, a partially written frame at the end is ignored
+    pub fn read<R:Read>(mut reader:R) -> Result<Self, ReplayError> {
+        let header:ReplayHeader = bincode::deserialize_from(&mut reader)?;
+        if header.version != REPLAY_VERSION {
+            return Err(ReplayError::Version {
+                found:header.version,
+                expected:REPLAY_VERSION
+            });
+        }
+
+        let mut frames = Vec::new();
+        loop {
+            match bincode::deserialize_from::<_, ReplayFrame>(&mut reader) {
+                Ok(frame) => frames.push(frame),
+                Err(err) => match *err {
+                    bincode::ErrorKind::Io(ref io) if io.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                    _ => return Err(err.into())
+                }
+            }
+        }
+
+        Ok(Self {
+            header,
+            frames
+        })
+    }
+
+    pub fn decode(bytes:&[u8]) -> Result<Self, ReplayError> {
+        Self::read(bytes)
+    }
+
+    /// the seconds between two frames
+    pub fn delta(&self) -> f64 {
+        1.0 / self.header.tick_rate.max(1) as f64
+    }
+
+    pub fn duration_sec(&self) -> f64 {
+        self.frames.len() as f64 * self.delta()
+    }
+
+    /// derives the state of the frame from the state of the previous frame, verifying the checksum
+    pub fn apply_frame(&self, index:usize, prev:&State) -> Result<State, ReplayError> {
+        let frame = match self.frames.get(index) {
+            Some(frame) => frame,
+            None => return Ok(prev.clone())
+        };
+
+        let delta = StateDelta::decode(&frame.delta).ok_or(ReplayError::Corrupt { seq:frame.seq })?;
+        let state = delta.apply(prev).ok_or(ReplayError::Corrupt { seq:frame.seq })?;
+        let checksum = state_hash(&state);
+        if checksum != frame.checksum {
+            return Err(ReplayError::Checksum {
+                seq:frame.seq,
+                expected:frame.checksum,
+                found:checksum
+            });
+        }
+
+        Ok(state)
+    }
+}
+
+/// plays back a replay, from the initial state at position zero to the state after the last frame
+pub struct ReplayPlayer {
+    replay:Replay,
+    /// the state of every `KEYFRAME_INTERVAL`th position
+    keyframes:Vec<State>,
+    state:State,
+    pos:usize
+}
+
+impl ReplayPlayer {
+    /// derives every state of the replay once, verifying the checksums and keeping keyframes for seeking
+    pub fn new(replay:Replay) -> Result<Self, ReplayError> {
+        let mut keyframes = vec![replay.header.initial.clone()];
+        let mut state = replay.header.initial.clone();
+        for index in 0..replay.frames.len() {
+            state = replay.apply_frame(index, &state)?;
+            if (index + 1) % KEYFRAME_INTERVAL == 0 {
+                keyframes.push(state.clone());
+            }
+        }
+
+        let state = replay.header.initial.clone();
+        Ok(Self {
+            replay,
+            keyframes,
+            state,
+            pos:0
+        })
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// the last position, after every frame has been applied
+    pub fn end(&self) -> usize {
+        self.replay.frames.len()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pos >= self.end()
+    }
+
+    /// advances to the next frame, returning false if already at the end
+    pub fn step(&mut self) -> Result<bool, ReplayError> {
+        if self.is_finished() {
+            return Ok(false);
+        }
+
+        self.state = self.replay.apply_frame(self.pos, &self.state)?;
+        self.pos += 1;
+        Ok(true)
+    }
+
+    /// jumps to the position, starting from the closest keyframe before it
+    /// the events of the state are those of the last frame applied
+    pub fn seek(&mut self, pos:usize) -> Result<(), ReplayError> {
+        let pos = pos.min(self.end());
+        let keyframe = (pos / KEYFRAME_INTERVAL).min(self.keyframes.len() - 1);
+        if pos < self.pos || keyframe * KEYFRAME_INTERVAL > self.pos {
+            self.state = self.keyframes[keyframe].clone();
+            self.pos = keyframe * KEYFRAME_INTERVAL;
+        }
+
+        while self.pos < pos {
+            self.step()?;
+        }
+
+        Ok(())
+    }
+}
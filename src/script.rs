@@ -0,0 +1,337 @@
+//! bot brains scripted in Rhai, such that bot behaviour can be authored without recompiling the server
+//!
+//! a script defines `fn think(bot, delta)`, called once per tick for each bot, where `bot` is a map of
+//! * `x`, `y`, `hearts`, `ability`, `ammo`, `cooldown`, `charge` and `team` of the bot itself
+//! * `enemies` and `friends`, arrays of maps with `x`, `y`, `vx`, `vy`, `hearts` and `visible` of the other players alive
+//! * `pickups`, an array of maps with `x`, `y` and `kind` of the pickups which can be collected
+//! * `flags`, an array of maps with `x`, `y`, `team` and `carried` when capturing the flag
+//! * `memory`, whatever the script returned as memory on the previous tick
+//!
+//! and which returns a map with any of
+//! * `goal`, a map with `x` and `y` the bot finds its way to
+//! * `aim`, a map with `x` and `y` the bot aims at
+//! * `trigger`, true to use the ability
+//! * `ability`, the name of the ability to use
+//! * `memory`, any value the script wants to remember until the next tick, kept separately for each bot
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use generational_arena::Index;
+use glam::Vec2;
+use hostess::log::warn;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use sample_lib::{Ability, Input, NavGraph, PickupKind, SimRng, State, Thing};
+
+use crate::bot::{BotBrain, Difficulty, PathFollower, PathfindingBrain};
+
+/// the most operations a script can run per call, such that a broken script cannot stall the server
+const MAX_OPERATIONS:u64 = 100_000;
+
+/// a compiled bot script, shared by the brains running it
+#[derive(Clone)]
+pub struct BotScript {
+    name:String,
+    engine:Arc<Engine>,
+    ast:Arc<AST>
+}
+
+impl BotScript {
+    pub fn load(path:&Path) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile_file(path.into()).map_err(|err| format!("failed to compile {}: {}", path.display(), err))?;
+        Ok(Self {
+            name:path.display().to_string(),
+            engine:Arc::new(engine),
+            ast:Arc::new(ast)
+        })
+    }
+}
+
+/// a brain running the `think` function of a script
+pub struct ScriptBrain {
+    script:BotScript,
+    scope:Scope<'static>,
+    /// the memory returned by the script on the previous tick
+    memory:Dynamic,
+    path:PathFollower,
+    /// the positions of the players on the previous tick, used to estimate their velocity
+    positions:HashMap<Index, Vec2>,
+    /// the error the script failed with, after which the built-in brain plays instead of the script
+    error:Option<String>,
+    fallback:PathfindingBrain
+}
+
+impl ScriptBrain {
+    pub fn new(script:BotScript, difficulty:Difficulty) -> Self {
+        Self {
+            script,
+            scope:Scope::new(),
+            memory:Dynamic::UNIT,
+            path:PathFollower::default(),
+            positions:HashMap::new(),
+            error:None,
+            fallback:PathfindingBrain::new(difficulty)
+        }
+    }
+
+    /// the error the script failed with, None while it runs fine
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// the map describing the state as seen by the bot, passed to the script
+    fn view(&mut self, thing_id:Index, state:&State, nav:&NavGraph, delta:f64) -> Option<Map> {
+        let me = match state.things.get(thing_id) {
+            Some(Thing::Player(player)) if player.is_alive() => player,
+            _ => return None
+        };
+
+        let mut bot = Map::new();
+        bot.insert("x".into(), Dynamic::from(me.pos.x as f64));
+        bot.insert("y".into(), Dynamic::from(me.pos.y as f64));
+        bot.insert("hearts".into(), Dynamic::from(me.hearts as i64));
+        bot.insert("ability".into(), Dynamic::from(me.ability.name().to_string()));
+        bot.insert("ammo".into(), match me.ammo(me.ability) {
+            Some(ammo) => Dynamic::from(ammo as i64),
+            None => Dynamic::UNIT
+        });
+        bot.insert("cooldown".into(), Dynamic::from(me.ability_cooldown as f64));
+        bot.insert("charge".into(), match me.charge {
+            Some(charge) => Dynamic::from(charge as f64),
+            None => Dynamic::UNIT
+        });
+        bot.insert("team".into(), match me.team {
+            Some(team) => Dynamic::from(team.name().to_string()),
+            None => Dynamic::UNIT
+        });
+
+        let mut enemies = Array::new();
+        let mut friends = Array::new();
+        let mut positions = HashMap::new();
+        for (id, thing) in state.things.iter() {
+            if let Thing::Player(player) = thing {
+                if id == thing_id || !player.is_alive() {
+                    continue;
+                }
+
+                let vel = match self.positions.get(&id) {
+                    Some(prev) if delta > 0.0 => (player.pos - *prev) / delta as f32,
+                    _ => Vec2::ZERO
+                };
+                positions.insert(id, player.pos);
+
+                let mut other = Map::new();
+                other.insert("x".into(), Dynamic::from(player.pos.x as f64));
+                other.insert("y".into(), Dynamic::from(player.pos.y as f64));
+                other.insert("vx".into(), Dynamic::from(vel.x as f64));
+                other.insert("vy".into(), Dynamic::from(vel.y as f64));
+                other.insert("hearts".into(), Dynamic::from(player.hearts as i64));
+                other.insert("visible".into(), Dynamic::from(nav.is_clear(me.pos, player.pos, 0.0)));
+
+                let friendly = state.mode.is_team_based() && player.team.is_some() && player.team == me.team;
+                if friendly {
+                    friends.push(Dynamic::from(other));
+                } else {
+                    enemies.push(Dynamic::from(other));
+                }
+            }
+        }
+        self.positions = positions;
+
+        let mut pickups = Array::new();
+        let mut flags = Array::new();
+        for (_, thing) in state.things.iter() {
+            match thing {
+                Thing::Pickup(pickup) if pickup.is_active() => {
+                    let kind = match pickup.kind {
+                        PickupKind::Heart => "Heart".to_string(),
+                        PickupKind::Speed => "Speed".to_string(),
+                        PickupKind::Shield => "Shield".to_string(),
+                        PickupKind::Weapon(ability) => ability.name().to_string()
+                    };
+
+                    let mut p = Map::new();
+                    p.insert("x".into(), Dynamic::from(pickup.pos.x as f64));
+                    p.insert("y".into(), Dynamic::from(pickup.pos.y as f64));
+                    p.insert("kind".into(), Dynamic::from(kind));
+                    pickups.push(Dynamic::from(p));
+                }
+                Thing::Flag(flag) => {
+                    let mut f = Map::new();
+                    f.insert("x".into(), Dynamic::from(flag.pos.x as f64));
+                    f.insert("y".into(), Dynamic::from(flag.pos.y as f64));
+                    f.insert("team".into(), Dynamic::from(flag.team.name().to_string()));
+                    f.insert("carried".into(), Dynamic::from(flag.carrier.is_some()));
+                    flags.push(Dynamic::from(f));
+                }
+                _ => {}
+            }
+        }
+
+        bot.insert("enemies".into(), Dynamic::from(enemies));
+        bot.insert("friends".into(), Dynamic::from(friends));
+        bot.insert("pickups".into(), Dynamic::from(pickups));
+        bot.insert("flags".into(), Dynamic::from(flags));
+        bot.insert("memory".into(), self.memory.clone());
+        Some(bot)
+    }
+}
+
+/// reads a position given as a map with x and y
+fn read_pos(map:&Map, key:&str) -> Option<Vec2> {
+    let pos = map.get(key)?.clone().try_cast::<Map>()?;
+    let number = |v:&Dynamic| v.as_float().ok().or_else(|| v.as_int().ok().map(|i| i as f64));
+    let x = number(pos.get("x")?)?;
+    let y = number(pos.get("y")?)?;
+    Some(Vec2::new(x as f32, y as f32))
+}
+
+impl BotBrain for ScriptBrain {
+    fn think(&mut self, thing_id:Index, state:&State, nav:&NavGraph, rng:&mut SimRng, delta:f64) -> Input {
+        let mut input = Input::default();
+        if self.error.is_some() {
+            return self.fallback.think(thing_id, state, nav, rng, delta);
+        }
+
+        let view = match self.view(thing_id, state, nav, delta) {
+            Some(view) => view,
+            None => {
+                self.path.stop();
+                return input;
+            }
+        };
+        let pos = match state.things.get(thing_id) {
+            Some(thing) => *thing.pos(),
+            None => return input
+        };
+
+        let script = self.script.clone();
+        let result = script.engine.call_fn::<Dynamic>(&mut self.scope, &script.ast, "think", (view, delta));
+        let result = match result {
+            Ok(result) => result.try_cast::<Map>().unwrap_or_default(),
+            Err(err) => {
+                // a bot standing still is easily mistaken for a script doing nothing, so the bot keeps playing
+                warn!("Bot script {} failed, the bot plays with the built-in brain from now on: {}", script.name, err);
+                self.error = Some(err.to_string());
+                return self.fallback.think(thing_id, state, nav, rng, delta);
+            }
+        };
+
+        self.memory = result.get("memory").cloned().unwrap_or(Dynamic::UNIT);
+        match read_pos(&result, "goal") {
+            Some(goal) => self.path.go_to(nav, pos, goal),
+            None => self.path.stop()
+        }
+        input.movement_dir = self.path.step(nav, pos);
+
+        if let Some(Thing::Player(me)) = state.things.get(thing_id) {
+            input.ability = me.ability;
+        }
+        if let Some(ability) = result.get("ability").and_then(|a| a.clone().into_string().ok()) {
+            if let Some(ability) = Ability::ALL.iter().find(|a| a.name().eq_ignore_ascii_case(&ability)) {
+                input.ability = *ability;
+            }
+        }
+
+        input.ability_target = read_pos(&result, "aim").unwrap_or(pos);
+        input.ability_trigger = result.get("trigger").and_then(|t| t.as_bool().ok()).unwrap_or(false);
+        input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sample_lib::{PlayerThing, Solid, MAX_HEARTS};
+
+    use super::*;
+
+    fn chaser() -> ScriptBrain {
+        let script = BotScript::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("bots/chaser.rhai")).unwrap();
+        ScriptBrain::new(script, Difficulty::Normal)
+    }
+
+    /// writes the script to a file of its own and loads it
+    fn script(name:&str, source:&str) -> Result<BotScript, String> {
+        let path = std::env::temp_dir().join(format!("bot-script-test-{}-{}.rhai", std::process::id(), name));
+        std::fs::write(&path, source).unwrap();
+        BotScript::load(&path)
+    }
+
+    fn player(state:&mut State, pos:Vec2) -> Index {
+        state.things.insert(Thing::Player(PlayerThing {
+            pos,
+            radius:0.5,
+            solid:Solid::Solid,
+            hearts:MAX_HEARTS,
+            ..Default::default()
+        }))
+    }
+
+    fn think(brain:&mut dyn BotBrain, id:Index, state:&State, delta:f64) -> Input {
+        let nav = NavGraph::new(&state.map, state.width, state.height, 0.6);
+        brain.think(id, state, &nav, &mut SimRng::new(1), delta)
+    }
+
+    #[test]
+    fn chaser_chases_and_leads_the_closest_enemy() {
+        let mut state = State::with_map(sample_lib::Map::new(), 40.0, 30.0);
+        let me = player(&mut state, Vec2::new(10.0, 10.0));
+        let enemy = player(&mut state, Vec2::new(20.0, 10.0));
+        player(&mut state, Vec2::new(35.0, 25.0));
+        let mut brain = chaser();
+
+        let input = think(&mut brain, me, &state, 0.1);
+        assert!(input.movement_dir.x > 0.0, "{:?} does not move to the enemy", input.movement_dir);
+        assert_eq!(input.ability, Ability::Blaster);
+        assert_eq!(input.ability_target, Vec2::new(20.0, 10.0));
+        assert!(input.ability_trigger);
+
+        // moving up by 10 units per second, the enemy is aimed at where it is once the projectile arrives
+        *state.things[enemy].pos_mut() = Vec2::new(20.0, 11.0);
+        let input = think(&mut brain, me, &state, 0.1);
+        let t = Vec2::new(10.0, 10.0).distance(Vec2::new(20.0, 11.0)) / 20.0;
+        assert!(input.ability_target.distance(Vec2::new(20.0, 11.0 + 10.0 * t)) < 1e-3, "aims at {:?}", input.ability_target);
+
+        // close by, the shotgun is used
+        *state.things[enemy].pos_mut() = Vec2::new(12.0, 11.0);
+        let input = think(&mut brain, me, &state, 0.1);
+        assert_eq!(input.ability, Ability::Shotgun);
+        assert!(brain.error().is_none());
+    }
+
+    #[test]
+    fn chaser_collects_pickups_without_enemies() {
+        let mut state = State::with_map(sample_lib::Map::new(), 40.0, 30.0);
+        let me = player(&mut state, Vec2::new(10.0, 10.0));
+        state.things.insert(Thing::new_pickup(Vec2::new(10.0, 20.0), PickupKind::Speed));
+        let mut brain = chaser();
+
+        let input = think(&mut brain, me, &state, 0.1);
+        assert!(input.movement_dir.y > 0.0, "{:?} does not move to the pickup", input.movement_dir);
+        assert!(!input.ability_trigger);
+        assert_eq!(input.ability_target, Vec2::new(10.0, 10.0));
+    }
+
+    #[test]
+    fn failing_script_is_reported_and_replaced_by_the_built_in_brain() {
+        assert!(script("syntax", "fn think(bot, delta) {").is_err());
+
+        let mut state = State::with_map(sample_lib::Map::new(), 40.0, 30.0);
+        let me = player(&mut state, Vec2::new(10.0, 10.0));
+        player(&mut state, Vec2::new(20.0, 10.0));
+        let mut brain = ScriptBrain::new(script("throws", r#"fn think(bot, delta) { throw "broken"; }"#).unwrap(), Difficulty::Normal);
+        let mut built_in = PathfindingBrain::new(Difficulty::Normal);
+
+        for _ in 0..3 {
+            let input = think(&mut brain, me, &state, 0.1);
+            let expected = think(&mut built_in, me, &state, 0.1);
+            assert_eq!(input.movement_dir, expected.movement_dir);
+            assert_eq!(input.ability, expected.ability);
+            assert_eq!(input.ability_target, expected.ability_target);
+            assert_eq!(input.ability_trigger, expected.ability_trigger);
+        }
+
+        assert!(brain.error().map(|err| err.contains("broken")).unwrap_or(false), "{:?}", brain.error());
+    }
+}
@@ -1,22 +1,290 @@
-use std::{collections::{HashMap, VecDeque}};
-use hostess::{client::Bincoded, server::{Ctx, OutMsg, InMsg, Config}, uuid::Uuid};
-use sample_lib::{CustomMsg, Player, State, StateHistory, Thing, apply_input, update_things, Event};
-use crate::bot::*;
+use std::{collections::{HashMap, HashSet}, fs::File, io::BufWriter, path::PathBuf, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::{Instant, SystemTime, UNIX_EPOCH}};
+use hostess::{client::Bincoded, server::{Ctx, OutMsg, InMsg, Config}, uuid::Uuid, log::{info, warn}};
+use sample_lib::{AdminCommand, Broadphase, CustomMsg, DepartedScore, GameMode, MapFile, MatchPhase, NavGraph, Player, ReplayError, ReplayWriter, SimRng, SnapshotBuffer, Solid, State, StateDelta, StateHistory, Team, Thing, apply_input, reset_flags, team_of, update_things, use_ability, spawn_projectile_compensated, sanitize_chat, WordFilter};
+use crate::{admin::{AuditLog, LoginThrottle, password_matches}, bot::*, stats::{PlayerStats, StatsStore}};
+
+/// maps loaded at startup, assigned round robin to instances as they are created
+static MAPS:Mutex<Vec<MapFile>> = Mutex::new(Vec::new());
+
+/// the number of the next instance created, used to assign maps and bot difficulties round robin
+static NEXT_INSTANCE:AtomicUsize = AtomicUsize::new(0);
+
+/// the difficulties of the bots, assigned round robin to instances as they are created
+static BOT_DIFFICULTIES:Mutex<Vec<Difficulty>> = Mutex::new(Vec::new());
+
+/// the ticks per second of instances, assigned round robin to instances as they are created
+static TICK_RATES:Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+/// the ticks per second of instances if no tick rates are set
+const DEFAULT_TICK_RATE:u64 = 20;
+
+/// the most players playing an instance, spectators not included
+static MAX_PLAYERS:AtomicUsize = AtomicUsize::new(8);
+
+/// the number of players instances are filled up to with bots
+static BOT_COUNT:AtomicUsize = AtomicUsize::new(4);
+
+/// creates the brains of bots, the built-in brain is used if None
+static BOT_BRAINS:Mutex<Option<BrainFactory>> = Mutex::new(None);
+
+/// the game mode played by instances created from now on
+static GAME_MODE:Mutex<GameMode> = Mutex::new(GameMode::Deathmatch);
+
+/// whether players hurt their team mates in team based game modes, in instances created from now on
+static FRIENDLY_FIRE:AtomicBool = AtomicBool::new(false);
+
+/// where the lifetime stats of players are recorded, shared by every instance
+static STATS:Mutex<Option<Arc<dyn StatsStore>>> = Mutex::new(None);
+
+/// the directory rounds are recorded to as replays, not recorded if None
+static REPLAY_DIR:Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// the password clients authenticate as admin with, remote admin is disabled if None
+static ADMIN_PASSWORD:Mutex<Option<String>> = Mutex::new(None);
+
+/// where admin commands are recorded, shared by every instance
+static AUDIT_LOG:Mutex<Option<Arc<AuditLog>>> = Mutex::new(None);
+
+/// the admin commands given in the console not yet run, indexed by the number of the instance
+static ADMIN_INBOX:Mutex<Vec<Vec<AdminCommand>>> = Mutex::new(Vec::new());
+
+/// filters chat messages before they are relayed, not filtered if None
+static WORD_FILTER:Mutex<Option<Arc<dyn WordFilter>>> = Mutex::new(None);
+
+/// clients banned by an admin, kept from joining any instance until the server restarts
+/// bans are by client id, which clients choose themselves, such that a ban is advisory:
+/// it keeps out a client reconnecting as before, but not one making up a new id
+static BANNED:Mutex<Vec<Uuid>> = Mutex::new(Vec::new());
+
+/// failed admin logins of the clients on every instance, refusing logins for a while after too many
+static LOGIN_THROTTLE:Mutex<LoginThrottle> = Mutex::new(LoginThrottle::new());
+
+/// sets the maps to assign to instances created from now on
+pub fn set_maps(maps:Vec<MapFile>) {
+    if let Ok(mut m) = MAPS.lock() {
+        *m = maps;
+    }
+}
+
+/// sets the game mode of instances created from now on
+pub fn set_game_mode(mode:GameMode) {
+    if let Ok(mut m) = GAME_MODE.lock() {
+        *m = mode;
+    }
+}
+
+/// sets whether players hurt their team mates in instances created from now on
+pub fn set_friendly_fire(enabled:bool) {
+    FRIENDLY_FIRE.store(enabled, Ordering::Relaxed);
+}
+
+/// sets the bot difficulties to assign to instances created from now on
+pub fn set_bot_difficulties(difficulties:Vec<Difficulty>) {
+    if let Ok(mut d) = BOT_DIFFICULTIES.lock() {
+        *d = difficulties;
+    }
+}
+
+/// sets the ticks per second to assign to instances created from now on
+pub fn set_tick_rates(tick_rates:Vec<u64>) {
+    if let Ok(mut t) = TICK_RATES.lock() {
+        *t = tick_rates;
+    }
+}
+
+/// sets the most players playing instances created from now on
+pub fn set_max_players(max_players:usize) {
+    MAX_PLAYERS.store(max_players, Ordering::Relaxed);
+}
+
+/// sets the number of players instances created from now on are filled up to with bots
+pub fn set_bot_count(count:usize) {
+    BOT_COUNT.store(count, Ordering::Relaxed);
+}
+
+/// sets where instances record the lifetime stats of players
+pub fn set_stats_store(store:Arc<dyn StatsStore>) {
+    if let Ok(mut s) = STATS.lock() {
+        *s = Some(store);
+    }
+}
+
+/// sets the directory instances record rounds to as replays
+pub fn set_replay_dir(dir:PathBuf) {
+    if let Ok(mut d) = REPLAY_DIR.lock() {
+        *d = Some(dir);
+    }
+}
+
+/// sets the password clients authenticate as admin with
+pub fn set_admin_password(password:String) {
+    if let Ok(mut p) = ADMIN_PASSWORD.lock() {
+        *p = Some(password);
+    }
+}
+
+/// sets where instances record admin commands
+pub fn set_audit_log(audit:Arc<AuditLog>) {
+    if let Ok(mut a) = AUDIT_LOG.lock() {
+        *a = Some(audit);
+    }
+}
+
+/// sets how instances created from now on filter chat messages
+pub fn set_word_filter(filter:Arc<dyn WordFilter>) {
+    if let Ok(mut f) = WORD_FILTER.lock() {
+        *f = Some(filter);
+    }
+}
+
+/// sends the command given in the console to the instance, or to every instance if None
+pub fn send_admin_command(instance:Option<usize>, command:AdminCommand) {
+    if let Ok(mut inbox) = ADMIN_INBOX.lock() {
+        let instances = NEXT_INSTANCE.load(Ordering::Relaxed);
+        if inbox.len() < instances {
+            inbox.resize(instances, Vec::new());
+        }
+
+        match instance {
+            Some(instance) => {
+                if let Some(commands) = inbox.get_mut(instance) {
+                    commands.push(command);
+                }
+            }
+            None => inbox.iter_mut().for_each(|commands| commands.push(command.clone()))
+        }
+    }
+}
+
+fn is_banned(client_id:Uuid) -> bool {
+    BANNED.lock().map(|banned| banned.contains(&client_id)).unwrap_or(false)
+}
+
+/// sets how the brains of bots are created by instances created from now on, e.g. running a script
+pub fn set_bot_brains(factory:BrainFactory) {
+    if let Ok(mut b) = BOT_BRAINS.lock() {
+        *b = Some(factory);
+    }
+}
 
 pub struct Server {
+    /// the number of this instance, used to name its replays
+    instance:usize,
+    /// the maps this instance can play, empty if only the built-in map is used
+    maps:Vec<MapFile>,
+    map_index:usize,
+    /// the game mode wanted, which might not be supported by every map
+    mode:GameMode,
+    friendly_fire:bool,
     current:State,
     history:StateHistory,
     players:HashMap<Uuid, Player>,
-    bots:Vec<Bot>
+    /// the ticks per second the match is updated at, at most the rate the instance ticks at
+    tick_rate:u64,
+    /// the ticks per second of the instance, as started
+    max_tick_rate:u64,
+    /// seconds ticked since the last update, when updating at a lower rate than the instance ticks at
+    since_update:f64,
+    /// the most players playing, spectators not included
+    max_players:usize,
+    /// where the lifetime stats of players are recorded, if anywhere
+    stats:Option<Arc<dyn StatsStore>>,
+    /// seconds played by each player, not yet recorded in their lifetime stats
+    playtime:HashMap<Uuid, f64>,
+    /// the directory rounds are recorded to, if anywhere
+    replay_dir:Option<PathBuf>,
+    /// records the current round, if being recorded
+    recorder:Option<ReplayWriter<BufWriter<File>>>,
+    /// the password clients authenticate as admin with, if remote admin is enabled
+    admin_password:Option<String>,
+    /// where admin commands are recorded, if anywhere
+    audit:Option<Arc<AuditLog>>,
+    /// filters chat messages before they are relayed, if anywhere
+    word_filter:Option<Arc<dyn WordFilter>>,
+    /// clients kicked, whose messages are ignored
+    kicked:HashSet<Uuid>,
+    bots:Vec<Bot>,
+    /// the number of players filled up to with bots
+    bot_count:usize,
+    bot_difficulty:Difficulty,
+    bot_brains:BrainFactory,
+    /// the navigation graph of the current map, used by bots to find their way
+    nav:NavGraph,
+    /// how far back in time hits from players are compensated for lag
+    max_rewind_sec:f64,
+    /// the recent snapshots sent to players, which deltas are based on once acknowledged
+    snapshots:SnapshotBuffer,
+    snapshot_seq:u32,
+    snapshot_bytes:usize,
+    snapshot_ticks:u32,
+    snapshot_report_timer:f64
 }
 
 impl Default for Server {
     fn default() -> Self {
+        let maps = MAPS.lock().map(|maps| maps.clone()).unwrap_or_default();
+        let instance = NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed);
+        let (map_index, current) = match maps.len() {
+            0 => (0, State::new()),
+            len => (instance % len, maps[instance % len].to_state())
+        };
+
+        let difficulties = BOT_DIFFICULTIES.lock().map(|d| d.clone()).unwrap_or_default();
+        let bot_difficulty = match difficulties.len() {
+            0 => Difficulty::default(),
+            len => difficulties[instance % len]
+        };
+
+        let tick_rates = TICK_RATES.lock().map(|t| t.clone()).unwrap_or_default();
+        let tick_rate = match tick_rates.len() {
+            0 => DEFAULT_TICK_RATE,
+            len => tick_rates[instance % len]
+        };
+
+        let bot_brains = BOT_BRAINS.lock().ok().and_then(|b| b.clone()).unwrap_or_else(|| {
+            Arc::new(|difficulty| Box::new(PathfindingBrain::new(difficulty)) as Box<dyn BotBrain>)
+        });
+
+        let mode = GAME_MODE.lock().map(|mode| *mode).unwrap_or_default();
+        let friendly_fire = FRIENDLY_FIRE.load(Ordering::Relaxed);
+        let mut current = current;
+        current.rng = SimRng::new(rand::random());
+        current.set_mode(mode);
+        current.friendly_fire = friendly_fire;
+        let nav = NavGraph::new(&current.map, current.width, current.height, NAV_CLEARANCE);
+
         Self {
-            current:State::new(),
+            instance,
+            maps,
+            map_index,
+            mode,
+            friendly_fire,
+            current,
             players:HashMap::new(),
+            tick_rate,
+            max_tick_rate:tick_rate,
+            since_update:0.0,
+            max_players:MAX_PLAYERS.load(Ordering::Relaxed),
+            stats:STATS.lock().ok().and_then(|s| s.clone()),
+            playtime:HashMap::new(),
+            replay_dir:REPLAY_DIR.lock().ok().and_then(|d| d.clone()),
+            recorder:None,
+            admin_password:ADMIN_PASSWORD.lock().ok().and_then(|p| p.clone()),
+            audit:AUDIT_LOG.lock().ok().and_then(|a| a.clone()),
+            word_filter:WORD_FILTER.lock().ok().and_then(|f| f.clone()),
+            kicked:HashSet::new(),
             bots:Vec::new(),
-            history:StateHistory::new()
+            bot_count:BOT_COUNT.load(Ordering::Relaxed),
+            bot_difficulty,
+            bot_brains,
+            nav,
+            history:StateHistory::new(),
+            max_rewind_sec:MAX_REWIND_SEC,
+            snapshots:SnapshotBuffer::new(SNAPSHOT_BUFFER_SIZE),
+            snapshot_seq:0,
+            snapshot_bytes:0,
+            snapshot_ticks:0,
+            snapshot_report_timer:SNAPSHOT_REPORT_SEC
         }
     }
 }
@@ -26,109 +294,510 @@ impl Server {
         // clear events 
         self.current.events.clear();
         self.current.timestamp = context.time;
-        if self.players.len() < 2 {
-            self.current.warmup = true;
-            // less than two players and no bots, ensure some bots are spawned
-            if self.bots.len() == 0 {
-                while self.bots.len() < 4 {
-                    let thing = Thing::new_player("bot");
-                    let index = self.current.things.insert(thing);
-                    let bot = Bot::new(index);
-                    self.bots.push(bot);
-                }
-            }
-        } else {
-            self.current.warmup = false;
-            // more than two players, remove bots and their things
-            for bot in self.bots.drain(..) {
-                self.current.things.remove(bot.thing_id);
-            }
+        self.update_phase(context.delta as f32);
+        self.fill_bots();
 
-        }
+        let tick_rate = self.tick_rate as u8;
+        let can_fire = self.current.phase != MatchPhase::Intermission;
 
-        let tick_rate = TICK_RATE as u8;
+        // the inputs of bots and players are all moved against where things were at the start of the tick
+        let broadphase = Broadphase::new(&self.current);
         
-        // process bots
+        // process bots, their inputs are applied the same way as those of players
         for bot in self.bots.iter_mut() {
-            bot.tick(&mut self.current, context.delta);
+            let input = bot.tick(&self.current, &self.nav, context.delta);
+            apply_input(&mut self.current, &broadphase, &input, true);
+            if can_fire {
+                let spawn = use_ability(&mut self.current, bot.thing_id, input.ability_trigger, input.ability_target);
+                for thing in spawn {
+                    spawn_projectile_compensated(&mut self.current, &self.history, thing, input.view_timestamp_sec, self.max_rewind_sec);
+                }
+            }
         }
         
         // process inputs from players
         for (_, player) in &mut self.players {
+            // spectators never get a thing and their inputs are of no use
+            if player.spectator {
+                player.clear_inputs();
+                continue;
+            }
+
+            *self.playtime.entry(player.client_id).or_default() += context.delta;
+
             // if player has no 'thing'
             // ensure one is spawned for the player
             if player.thing == None {
-                let thing = Thing::new_player(&player.client_name);
+                let mut thing = Thing::new_player(&player.client_name);
+                if let Thing::Player(thing) = &mut thing {
+                    thing.team = player.team;
+                }
                 player.thing = Some(self.current.things.insert(thing));
                 // let the player know his thing id and tick_rate
                 push_custom_to(context, player.client_id, CustomMsg::ServerPlayerInfo {
                     thing_id:player.thing,
-                    tick_rate:tick_rate
+                    tick_rate:tick_rate,
+                    spectator:false
                 });
             }
 
             // apply input from players
+            // after making sure it is valid
+            player.grant_time(context.delta as f32);
             let mut trigger = false;
-            for input in player.inputs.drain(..) {
-                player.latest_input_timestamp_sec = input.timestamp_sec;
+            for mut input in std::mem::take(&mut player.inputs) {
+                player.validate_input(&mut input);
+                player.latest_input_seq = input.seq;
                 let ability_target = input.ability_target;
+                let view_timestamp_sec = input.view_timestamp_sec;
                 if input.ability_trigger {
                     trigger = true;
                 }
 
-                apply_input(&mut self.current, &input, true);
+                apply_input(&mut self.current, &broadphase, &input, true);
 
                 let mut spawn = Vec::new();
                 if let Some(thing_id) = player.thing {
-                    if let Some(thing) = self.current.things.get_mut(thing_id) {
-                        if let Thing::Player(player) = thing {
-                            if player.is_alive() && trigger && player.ability_cooldown <= 0.0 {
-                                player.ability_cooldown = 0.25;
-                                let dir = ability_target - player.pos;
-                                if dir.length() > 0.0 {
-                                    let dir = dir.normalize();
-                                    let v = dir * 20.0;
-                                    let p = Thing::new_projectile(player.pos, v, thing_id);
-                                    self.current.events.push(Event::ProjectileFired {
-                                        pos:player.pos.clone()
-                                    });
-                                    spawn.push(p);
-                                }
-                            }
-                        }
+                    if can_fire {
+                        spawn = use_ability(&mut self.current, thing_id, trigger, ability_target);
                     }
                 }
                 
                 for thing in spawn.drain(..) {
-                    self.current.things.insert(thing);
+                    spawn_projectile_compensated(&mut self.current, &self.history, thing, view_timestamp_sec, self.max_rewind_sec);
                 }
             }
         }
         
+        // kick players sending too many invalid inputs
+        let offenders:Vec<Uuid> = self.players.values().filter(|p| p.should_kick()).map(|p| p.client_id).collect();
+        for client_id in offenders {
+            self.kick(context, client_id, "Too many invalid inputs");
+        }
+
         // do generic update of things, such as moving projectiles
-        update_things(&mut self.current, context.delta);
+        let broadphase = Broadphase::new(&self.current);
+        update_things(&mut self.current, &broadphase, context.delta);
 
-        // for each player, transmit state diff
+        // for each player, transmit the snapshot as a delta from the newest snapshot acknowledged
+        // or in full if no acknowledged snapshot is remembered
+        self.snapshot_seq += 1;
+        let seq = self.snapshot_seq;
+        let snapshot = self.current.quantized();
+        self.record_replay(&snapshot);
+        let mut deltas:HashMap<u32, Vec<u8>> = HashMap::new();
         for (client_id, player) in &mut self.players {
-            let delta = self.current.to_delta_bincode(&player.state);
-            push_custom_to(context, *client_id, CustomMsg::ServerSnapshotDelta {
-                input_timestamp_sec:player.latest_input_timestamp_sec,
-                delta
-            });
-
-            player.state = self.current.clone()
+            let baseline = self.snapshots.baseline(player.acked_seq);
+            match baseline {
+                Some((baseline_seq, baseline)) => {
+                    let delta = deltas.entry(baseline_seq).or_insert_with(|| StateDelta::new(baseline, &snapshot).encode());
+                    self.snapshot_bytes += delta.len();
+                    push_custom_to(context, *client_id, CustomMsg::ServerSnapshotDelta {
+                        seq,
+                        baseline_seq,
+                        input_seq:player.latest_input_seq,
+                        delta:delta.clone()
+                    });
+                }
+                None => {
+                    push_custom_to(context, *client_id, CustomMsg::ServerSnapshotFull {
+                        seq,
+                        input_seq:player.latest_input_seq,
+                        state:snapshot.clone()
+                    });
+                }
+            }
         }
+        self.snapshots.push(seq, snapshot);
+        self.report_snapshot_bytes(context.delta);
 
         // remember current state
         self.history.remember(self.current.clone());
     }
+
+    /// adds bots until players and bots add up to the bot count
+    /// or removes a single bot if there are too many, such that bots leave one at a time as players join
+    fn fill_bots(&mut self) {
+        let wanted = self.bot_count.saturating_sub(self.playing());
+        while self.bots.len() < wanted {
+            let mut thing = Thing::new_player(&self.bot_name());
+            if let Thing::Player(player) = &mut thing {
+                player.team = self.smallest_team();
+            }
+            let index = self.current.things.insert(thing);
+            let bot = Bot::new(index, (self.bot_brains)(self.bot_difficulty), &mut self.current.rng);
+            self.bots.push(bot);
+        }
+
+        if self.bots.len() > wanted {
+            if let Some(bot) = self.bots.pop() {
+                self.remove_bot(bot);
+            }
+        }
+    }
+
+    /// records the time played by the player to its lifetime stats, together with its kills and deaths
+    /// if a round is being played or has just finished, in which case the player has also played a match
+    fn record_stats(&mut self, client_id:Uuid, finished:bool) {
+        if let Some(stats) = self.take_stats(client_id, finished) {
+            self.store_stats(&[stats]);
+        }
+    }
+
+    /// the identity of the player and the stats to add to its lifetime stats, see `record_stats`
+    /// the time played is taken, such that it is only recorded once
+    fn take_stats(&mut self, client_id:Uuid, finished:bool) -> Option<(Uuid, PlayerStats)> {
+        let playtime_sec = self.playtime.remove(&client_id).unwrap_or_default();
+        let player = match (&self.stats, self.players.get(&client_id)) {
+            (Some(_), Some(player)) => player,
+            _ => return None
+        };
+
+        let mut stats = PlayerStats {
+            name:player.client_name.clone(),
+            playtime_sec,
+            ..Default::default()
+        };
+
+        if finished || self.current.phase == MatchPhase::Round {
+            if let Some(Thing::Player(thing)) = player.thing.and_then(|id| self.current.things.get(id)) {
+                stats.kills = thing.kills.max(0) as u64;
+                stats.deaths = thing.deaths.max(0) as u64;
+                stats.matches_played = if finished { 1 } else { 0 };
+            }
+        }
+
+        Some((player.player_id, stats))
+    }
+
+    fn store_stats(&self, stats:&[(Uuid, PlayerStats)]) {
+        if let Some(store) = &self.stats {
+            if let Err(err) = store.record(stats) {
+                let names:Vec<&str> = stats.iter().map(|(_, stats)| stats.name.as_str()).collect();
+                warn!("Failed to record stats of {}: {}", names.join(", "), err);
+            }
+        }
+    }
+
+    /// records the stats of every player playing, written to the store at once
+    fn record_all_stats(&mut self, finished:bool) {
+        let playing:Vec<Uuid> = self.players.values().filter(|p| !p.spectator).map(|p| p.client_id).collect();
+        let stats:Vec<(Uuid, PlayerStats)> = playing.into_iter().filter_map(|client_id| self.take_stats(client_id, finished)).collect();
+        self.store_stats(&stats);
+    }
+
+    /// sets the identity the lifetime stats of the player are recorded by
+    /// a banned identity is kicked, whatever the client id it connected with
+    fn identify(&mut self, context:&mut Ctx, client_id:Uuid, player_id:Uuid) {
+        if is_banned(player_id) {
+            self.kick(context, client_id, "Banned from this server");
+            return;
+        }
+
+        let player = match self.players.get_mut(&client_id) {
+            Some(player) => player,
+            None => return
+        };
+        player.player_id = player_id;
+        if let Some(stats) = self.stats.as_ref().and_then(|s| s.get(player_id)) {
+            info!("{} ({}) returned with {} kills and {} deaths in {} matches", player.client_name, player_id, stats.kills, stats.deaths, stats.matches_played);
+        }
+    }
+
+    /// the number of players playing, i.e. not spectating
+    fn playing(&self) -> usize {
+        self.players.values().filter(|p| !p.spectator).count()
+    }
+
+    /// removes the thing of the bot, keeping its score for the scoreboard until the next round starts
+    fn remove_bot(&mut self, bot:Bot) {
+        if let Some(Thing::Player(player)) = self.current.things.remove(bot.thing_id) {
+            if self.current.phase != MatchPhase::Warmup {
+                self.current.departed.push(DepartedScore {
+                    name:player.name,
+                    team:player.team,
+                    kills:player.kills,
+                    deaths:player.deaths
+                });
+            }
+        }
+    }
+
+    /// a name for a new bot, different from the names of the players and bots already playing
+    fn bot_name(&self) -> String {
+        let taken:HashSet<&str> = self.current.things.iter().filter_map(|(_, thing)| match thing {
+            Thing::Player(player) => Some(player.name.as_str()),
+            _ => None
+        }).collect();
+
+        BOT_NAMES.iter()
+            .map(|name| format!("Bot {}", name))
+            .chain((1..).map(|i| format!("Bot {}", i)))
+            .find(|name| !taken.contains(name.as_str()))
+            .unwrap_or_default()
+    }
+
+    /// the team with the fewest players and bots, None if the game mode is not team based
+    fn smallest_team(&self) -> Option<Team> {
+        if !self.current.mode.is_team_based() {
+            return None;
+        }
+
+        let mut count = [0; 2];
+        for player in self.players.values() {
+            if let Some(team) = player.team {
+                count[team.index()] += 1;
+            }
+        }
+
+        for bot in &self.bots {
+            if let Some(team) = team_of(&self.current, bot.thing_id) {
+                count[team.index()] += 1;
+            }
+        }
+
+        Team::ALL.iter().copied().min_by_key(|team| count[team.index()])
+    }
+
+    /// lets the player spectate, removing its thing, or play, if the game is not full
+    fn set_spectator(&mut self, context:&mut Ctx, client_id:Uuid, spectate:bool) {
+        let playing = self.playing();
+        let team = self.smallest_team();
+        let spectator = match self.players.get(&client_id) {
+            Some(player) => player.spectator,
+            None => return
+        };
+
+        if spectate == spectator || (!spectate && playing >= self.max_players) {
+            return;
+        }
+
+        if spectate {
+            self.record_stats(client_id, false);
+        }
+
+        let player = match self.players.get_mut(&client_id) {
+            Some(player) => player,
+            None => return
+        };
+
+        if spectate {
+            if let Some(thing_id) = player.thing.take() {
+                if let Some(Thing::Player(thing)) = self.current.things.remove(thing_id) {
+                    if self.current.phase != MatchPhase::Warmup {
+                        self.current.departed.push(DepartedScore {
+                            name:thing.name,
+                            team:thing.team,
+                            kills:thing.kills,
+                            deaths:thing.deaths
+                        });
+                    }
+                }
+            }
+
+            player.team = None;
+        } else {
+            // a thing is spawned for the player on the next update
+            player.team = team;
+        }
+
+        player.spectator = spectate;
+        info!("{} ({}) is {}", player.client_name, client_id, if spectate { "spectating" } else { "playing" });
+        push_custom_to(context, client_id, CustomMsg::ServerPlayerInfo {
+            thing_id:None,
+            tick_rate:self.tick_rate as u8,
+            spectator:spectate
+        });
+    }
+
+    /// removes the player and its thing, ignoring the client from now on
+    fn kick(&mut self, context:&mut Ctx, client_id:Uuid, reason:&str) {
+        self.record_stats(client_id, false);
+        if let Some(player) = self.players.remove(&client_id) {
+            if let Some(thing_id) = player.thing {
+                self.current.things.remove(thing_id);
+            }
+
+            info!("Kicked {} ({}): {}", player.client_name, client_id, reason);
+        }
+
+        self.kicked.insert(client_id);
+        push_custom_to(context, client_id, CustomMsg::ServerKicked {
+            reason:reason.into()
+        });
+    }
+
+    /// logs the average size of the delta snapshots sent to each player per tick
+    fn report_snapshot_bytes(&mut self, delta:f64) {
+        self.snapshot_ticks += 1;
+        self.snapshot_report_timer -= delta;
+        if self.snapshot_report_timer <= 0.0 {
+            if self.players.len() > 0 {
+                let bytes = self.snapshot_bytes as f64 / self.snapshot_ticks as f64 / self.players.len() as f64;
+                info!("Snapshot deltas: {:.1} bytes per tick per player", bytes);
+            }
+
+            self.snapshot_bytes = 0;
+            self.snapshot_ticks = 0;
+            self.snapshot_report_timer = SNAPSHOT_REPORT_SEC;
+        }
+    }
+
+    /// advances the match from warmup to round to intermission and then on to the next map
+    fn update_phase(&mut self, delta:f32) {
+        let enough_players = self.playing() >= MIN_PLAYERS;
+        match self.current.phase {
+            MatchPhase::Warmup => {
+                if enough_players {
+                    self.start_round();
+                }
+            }
+            MatchPhase::Round => {
+                self.current.phase_timer -= delta;
+                let score_limit = self.current.score_limit;
+                let score_reached = match self.current.mode {
+                    GameMode::Deathmatch => self.current.things.iter().any(|(_, thing)| match thing {
+                        Thing::Player(player) => player.kills >= score_limit,
+                        _ => false
+                    }),
+                    _ => self.current.team_scores.iter().any(|score| *score >= score_limit)
+                };
+
+                if !enough_players {
+                    self.record_all_stats(false);
+                    self.stop_recording();
+                    self.current.phase = MatchPhase::Warmup;
+                } else if self.current.phase_timer <= 0.0 || score_reached {
+                    self.record_all_stats(true);
+                    self.current.phase = MatchPhase::Intermission;
+                    self.current.phase_timer = INTERMISSION_SEC;
+                }
+            }
+            MatchPhase::Intermission => {
+                self.current.phase_timer -= delta;
+                if self.current.phase_timer <= 0.0 {
+                    self.next_map();
+                    if enough_players {
+                        self.start_round();
+                    }
+                }
+            }
+        }
+    }
+
+    /// resets the score and respawns all players
+    fn start_round(&mut self) {
+        for (_, thing) in self.current.things.iter_mut() {
+            if let Thing::Player(player) = thing {
+                player.kills = 0;
+                player.deaths = 0;
+                player.hearts = 0;
+                player.solid = Solid::None;
+                player.spawn_pos = None;
+                player.respawn_timer = ROUND_START_RESPAWN_SEC;
+            }
+        }
+
+        reset_flags(&mut self.current);
+        self.current.team_scores = [0, 0];
+        self.current.departed.clear();
+        self.current.phase = MatchPhase::Round;
+        self.current.phase_timer = ROUND_SEC;
+        self.current.score_limit = match self.current.mode {
+            GameMode::CaptureTheFlag => CAPTURE_LIMIT,
+            _ => SCORE_LIMIT
+        };
+        self.start_recording();
+    }
+
+    /// starts recording the round to a new replay in the replay directory, if any
+    /// the intermission following the round is recorded as well
+    fn start_recording(&mut self) {
+        self.stop_recording();
+        let dir = match &self.replay_dir {
+            Some(dir) => dir,
+            None => return
+        };
+
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let path = dir.join(format!("{}-{}.replay", secs, self.instance));
+        let recorder = File::create(&path)
+            .map_err(ReplayError::from)
+            .and_then(|file| ReplayWriter::new(BufWriter::new(file), self.tick_rate as u8, &self.current.quantized()));
+        match recorder {
+            Ok(recorder) => {
+                info!("Recording round to {}", path.display());
+                self.recorder = Some(recorder);
+            }
+            Err(err) => warn!("Failed to record round to {}: {}", path.display(), err)
+        }
+    }
+
+    /// records the snapshot of the tick, recording stops if the replay cannot be written
+    fn record_replay(&mut self, snapshot:&State) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(snapshot) {
+                warn!("Failed to record replay, recording stopped: {}", err);
+                self.recorder = None;
+            }
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            let frames = recorder.frames();
+            match recorder.finish() {
+                Ok(_) => info!("Recorded {} ticks", frames),
+                Err(err) => warn!("Failed to finish replay: {}", err)
+            }
+        }
+    }
+
+    /// replaces the current state with a fresh state using the next map in the rotation
+    /// players will get new things spawned on the next update
+    fn next_map(&mut self) {
+        self.stop_recording();
+        let timestamp = self.current.timestamp;
+        self.current = match self.maps.len() {
+            0 => State::new(),
+            len => {
+                self.map_index = (self.map_index + 1) % len;
+                self.maps[self.map_index].to_state()
+            }
+        };
+        self.current.timestamp = timestamp;
+        self.current.rng = SimRng::new(rand::random());
+        self.current.set_mode(self.mode);
+        self.current.friendly_fire = self.friendly_fire;
+        self.nav = NavGraph::new(&self.current.map, self.current.width, self.current.height, NAV_CLEARANCE);
+        self.bots.clear();
+        self.history.clear();
+        for (_, player) in &mut self.players {
+            player.thing = None;
+        }
+    }
 }
-const TICK_RATE:u64 = 20;
+const MAX_REWIND_SEC:f64 = 0.25;
+const SNAPSHOT_REPORT_SEC:f64 = 60.0;
+const SNAPSHOT_BUFFER_SIZE:usize = 32;
+/// the most characters of a broadcast
+const MAX_BROADCAST_LENGTH:usize = 200;
+const MIN_PLAYERS:usize = 2;
+const MAX_SPECTATORS:usize = 8;
+const ROUND_SEC:f32 = 300.0;
+const ROUND_START_RESPAWN_SEC:f32 = 1.0;
+const INTERMISSION_SEC:f32 = 10.0;
+const SCORE_LIMIT:i32 = 20;
+const CAPTURE_LIMIT:i32 = 3;
+const BOT_NAMES:[&str; 12] = ["Ada", "Blaise", "Curie", "Dijkstra", "Euler", "Fermat", "Gauss", "Hopper", "Kepler", "Lovelace", "Noether", "Turing"];
+/// the distance bots keep from walls when finding their way, a little more than the radius of a player
+const NAV_CLEARANCE:f32 = 0.6;
 impl hostess::server::Server for Server {
     fn init(&mut self) -> Config {
         Config {
-            tick_rate: TICK_RATE,
-            max_players: 8,
+            tick_rate: self.max_tick_rate,
+            // spectators do not count toward the players of the game, but do count toward the clients of the instance
+            max_players: (self.max_players + MAX_SPECTATORS) as _,
         }
     }
 
@@ -136,29 +805,40 @@ impl hostess::server::Server for Server {
         while let Some(msg) = context.pop_msg() {
             match msg {
                 InMsg::ClientJoined { client_id, mut client_name } => {
+                    if self.kicked.contains(&client_id) || is_banned(client_id) {
+                        push_custom_to(&mut context, client_id, CustomMsg::ServerKicked {
+                            reason:"Kicked from this server".into()
+                        });
+                        continue;
+                    }
+
                     if !self.players.contains_key(&client_id) {
                         client_name.truncate(16);
-                        self.players.insert(client_id, Player {
-                            client_id:client_id,
-                            client_name,
-                            thing:None,
-                            inputs:VecDeque::default(),
-                            latest_input_timestamp_sec: 0.0,
-                            state:self.current.clone()
-                        });
+                        let mut player = Player::new(client_id, client_name);
+                        // the game is full, join as spectator
+                        if self.playing() >= self.max_players {
+                            player.spectator = true;
+                        } else {
+                            player.team = self.smallest_team();
+                        }
+                        self.players.insert(client_id, player);
                     }
 
-                    push_custom_to(&mut context, client_id, CustomMsg::ServerSnapshotFull {
-                        input_timestamp_sec:0.0,
-                        state:self.current.clone()
-                    });
+                    // the client does not remember any snapshot, ensure the next is sent in full
+                    let mut spectator = false;
+                    if let Some(player) = self.players.get_mut(&client_id) {
+                        player.acked_seq = None;
+                        spectator = player.spectator;
+                    }
 
                     push_custom_to(&mut context, client_id, CustomMsg::ServerPlayerInfo {
                         thing_id:None,
-                        tick_rate:TICK_RATE as u8
+                        tick_rate:self.tick_rate as u8,
+                        spectator
                     });
                 },
                 InMsg::ClientLeft { client_id } => {
+                    self.record_stats(client_id, false);
                     if let Some(player) = self.players.remove(&client_id) {
                         if let Some(thing_id) = player.thing {
                             self.current.things.remove(thing_id);
@@ -173,6 +853,17 @@ impl hostess::server::Server for Server {
             }
         }
 
+        self.run_console_commands(&mut context);
+
+        // the instance keeps ticking at the rate it was started with
+        // when the tick rate has been lowered since, ticks are skipped until it is time to update
+        self.since_update += context.delta;
+        if self.since_update + 0.5 / self.max_tick_rate as f64 < 1.0 / self.tick_rate as f64 {
+            return;
+        }
+        context.delta = self.since_update;
+        self.since_update = 0.0;
+
         self.update(&mut context);
     }
 
@@ -188,15 +879,344 @@ fn push_custom_to(context:&mut Ctx, client_id:Uuid, msg:CustomMsg) {
 
 impl Server {
     /// is called on each custom message received from the clients
-    pub fn recv_custom_msg(&mut self, _context:&mut Ctx, client_id:Uuid, msg:CustomMsg) {
+    pub fn recv_custom_msg(&mut self, context:&mut Ctx, client_id:Uuid, msg:CustomMsg) {
         match msg {
             CustomMsg::ClientInput { input } => {
                 if let Some(player) = self.players.get_mut(&client_id) {
                     // remember input for later processing
-                    player.inputs.push_back(input);
+                    player.queue_input(input);
+                }
+            },
+            CustomMsg::ClientSpectate { spectate } => {
+                self.set_spectator(context, client_id, spectate);
+            },
+            CustomMsg::ClientChat { text, team_only } => {
+                self.chat(context, client_id, &text, team_only);
+            },
+            CustomMsg::ClientAdminLogin { password } => {
+                self.admin_login(context, client_id, &password);
+            },
+            CustomMsg::ClientAdminCommand { command } => {
+                let admin = match self.players.get(&client_id) {
+                    Some(player) => player.admin,
+                    None => return
+                };
+                let source = self.client_source(client_id);
+                let result = match admin {
+                    true => self.run_admin_command(context, &source, command),
+                    false => {
+                        self.audit(&source, &command.to_string(), "denied, not authenticated");
+                        Err("Not authenticated as admin".into())
+                    }
+                };
+
+                let (ok, message) = match result {
+                    Ok(message) => (true, message),
+                    Err(message) => (false, message)
+                };
+                push_custom_to(context, client_id, CustomMsg::ServerAdminResult { ok, message });
+            },
+            CustomMsg::ClientIdentity { player_id } => {
+                self.identify(context, client_id, player_id);
+            },
+            CustomMsg::ClientSnapshotAck { seq } => {
+                if let Some(player) = self.players.get_mut(&client_id) {
+                    // acks might arrive out of order, keep the newest
+                    if player.acked_seq.map(|acked| seq > acked).unwrap_or(true) {
+                        player.acked_seq = Some(seq);
+                    }
                 }
             },
             _ => {}
         }
     }
-}
\ No newline at end of file
+}
+
+impl Server {
+    /// relays the chat message of the player to every player, or to the players of its team
+    fn chat(&mut self, context:&mut Ctx, client_id:Uuid, text:&str, team_only:bool) {
+        let text = match sanitize_chat(text) {
+            Some(text) => text,
+            None => return
+        };
+
+        let player = match self.players.get_mut(&client_id) {
+            Some(player) => player,
+            None => return
+        };
+
+        let rejected = if !player.chat.allow(context.time) {
+            Some("You are chatting too fast, wait a moment")
+        } else if team_only && player.team.is_none() {
+            Some("You are not in a team, say it to everyone instead")
+        } else {
+            None
+        };
+        if let Some(reason) = rejected {
+            push_custom_to(context, client_id, CustomMsg::ServerChatRejected {
+                reason:reason.into()
+            });
+            return;
+        }
+
+        let text = match &self.word_filter {
+            Some(filter) => filter.filter(&text),
+            None => text
+        };
+        let from = player.client_name.clone();
+        let team = player.team;
+        info!("{}{}: {}", from, if team_only { " (team)" } else { "" }, text);
+
+        let recipients:Vec<Uuid> = self.players.values()
+            .filter(|p| !team_only || p.team == team)
+            .map(|p| p.client_id)
+            .collect();
+        for recipient in recipients {
+            push_custom_to(context, recipient, CustomMsg::ServerChat {
+                from:from.clone(),
+                team,
+                team_only,
+                text:text.clone()
+            });
+        }
+    }
+}
+
+impl Server {
+    /// runs the commands given in the console for this instance
+    fn run_console_commands(&mut self, context:&mut Ctx) {
+        let commands = match ADMIN_INBOX.lock() {
+            Ok(mut inbox) => inbox.get_mut(self.instance).map(std::mem::take).unwrap_or_default(),
+            Err(_) => return
+        };
+
+        for command in commands {
+            match self.run_admin_command(context, "console", command) {
+                Ok(message) => info!("[instance {}] {}", self.instance, message),
+                Err(message) => warn!("[instance {}] {}", self.instance, message)
+            }
+        }
+    }
+
+    /// authenticates the client as admin if the password is right, kicking clients failing too many times
+    fn admin_login(&mut self, context:&mut Ctx, client_id:Uuid, password:&str) {
+        // the client is throttled by its connection and by its player id
+        let ids = match self.players.get(&client_id) {
+            Some(player) => [client_id, player.player_id],
+            None => return
+        };
+
+        let source = self.client_source(client_id);
+        let now = Instant::now();
+        if let Some(wait) = LOGIN_THROTTLE.lock().ok().and_then(|throttle| throttle.refused_for(&ids, now)) {
+            self.audit(&source, "login", "refused, too many failed logins");
+            push_custom_to(context, client_id, CustomMsg::ServerAdminResult {
+                ok:false,
+                message:format!("Too many failed admin logins, try again in {} seconds", wait.as_secs() + 1)
+            });
+            return;
+        }
+
+        let ok = match &self.admin_password {
+            Some(expected) => password_matches(password, expected),
+            None => false
+        };
+
+        // failed logins only count when remote admin is enabled, as there is no password to guess otherwise
+        let mut refused = None;
+        if self.admin_password.is_some() {
+            if let Ok(mut throttle) = LOGIN_THROTTLE.lock() {
+                match ok {
+                    true => throttle.succeeded(&ids),
+                    false => refused = throttle.failed(&ids, now)
+                }
+            }
+        }
+
+        if let Some(player) = self.players.get_mut(&client_id) {
+            player.admin |= ok;
+        }
+
+        self.audit(&source, "login", if ok { "ok" } else { "denied, wrong password" });
+        let message = match (ok, self.admin_password.is_some()) {
+            (true, _) => "Authenticated as admin",
+            (false, true) => "Wrong admin password",
+            (false, false) => "Remote admin is disabled on this server"
+        };
+        push_custom_to(context, client_id, CustomMsg::ServerAdminResult {
+            ok,
+            message:message.into()
+        });
+
+        if refused.is_some() {
+            self.kick(context, client_id, "Too many failed admin logins");
+        }
+    }
+
+    /// runs the command given by the source, recording it in the audit log
+    /// returns a message describing the outcome
+    fn run_admin_command(&mut self, context:&mut Ctx, source:&str, command:AdminCommand) -> Result<String, String> {
+        let action = command.to_string();
+        let result = match command {
+            AdminCommand::Players => {
+                let players:Vec<String> = self.players.values().map(|player| {
+                    let mut info = format!("{} ({})", player.client_name, player.client_id);
+                    if player.spectator {
+                        info += " spectating";
+                    }
+                    if player.admin {
+                        info += " admin";
+                    }
+                    info
+                }).collect();
+
+                match players.len() {
+                    0 => Ok("No players".into()),
+                    len => Ok(format!("{} players: {}", len, players.join(", ")))
+                }
+            }
+            AdminCommand::Kick { name } => self.find_player(&name).map(|client_id| {
+                self.kick(context, client_id, "Kicked by an admin");
+                format!("Kicked {}", name)
+            }),
+            AdminCommand::Ban { name } => self.find_player(&name).map(|client_id| {
+                let player_id = self.players.get(&client_id).map(|p| p.player_id).unwrap_or(client_id);
+                if let Ok(mut banned) = BANNED.lock() {
+                    banned.push(client_id);
+                    banned.push(player_id);
+                }
+                self.kick(context, client_id, "Banned by an admin");
+                format!("Banned {} by client id {}, a client making up a new id can rejoin", name, client_id)
+            }),
+            AdminCommand::ChangeMap { map } => self.change_map(map),
+            AdminCommand::RestartRound => {
+                if self.playing() < MIN_PLAYERS {
+                    Err("Not enough players to start a round".into())
+                } else {
+                    if self.current.phase == MatchPhase::Round {
+                        self.record_all_stats(false);
+                    }
+                    self.start_round();
+                    Ok("Restarted the round".into())
+                }
+            }
+            AdminCommand::SetBotCount { count } => {
+                if count > self.max_players {
+                    Err(format!("Bot count must be at most {}, the most players of the instance", self.max_players))
+                } else {
+                    self.bot_count = count;
+                    Ok(format!("Filling up to {} players with bots", count))
+                }
+            }
+            AdminCommand::SetTickRate { tick_rate } => self.change_tick_rate(context, tick_rate),
+            AdminCommand::Broadcast { mut text } => {
+                if let Some((i, _)) = text.char_indices().nth(MAX_BROADCAST_LENGTH) {
+                    text.truncate(i);
+                }
+                let clients:Vec<Uuid> = self.players.keys().copied().collect();
+                for client_id in clients.iter() {
+                    push_custom_to(context, *client_id, CustomMsg::ServerBroadcast {
+                        text:text.clone()
+                    });
+                }
+                Ok(format!("Broadcast to {} players", clients.len()))
+            }
+        };
+
+        let outcome = match &result {
+            Ok(message) => message.clone(),
+            Err(message) => format!("failed, {}", message)
+        };
+        self.audit(source, &action, &outcome);
+        result
+    }
+
+    /// the client id of the player given by client id, or by name ignoring case
+    /// a name shared by several players is refused, listing their client ids to pick one by
+    fn find_player(&self, name:&str) -> Result<Uuid, String> {
+        if let Ok(client_id) = Uuid::parse_str(name) {
+            return match self.players.contains_key(&client_id) {
+                true => Ok(client_id),
+                false => Err(format!("No player with client id {}", client_id))
+            };
+        }
+
+        let matches:Vec<&Player> = self.players.values().filter(|player| player.client_name.eq_ignore_ascii_case(name)).collect();
+        match matches.as_slice() {
+            [] => Err(format!("No player named {}", name)),
+            [player] => Ok(player.client_id),
+            players => {
+                let ids:Vec<String> = players.iter().map(|player| player.client_id.to_string()).collect();
+                Err(format!("{} players are named {}, give one of their client ids: {}", players.len(), name, ids.join(", ")))
+            }
+        }
+    }
+
+    /// ends the round and plays the map, or the next map in the rotation
+    fn change_map(&mut self, map:Option<String>) -> Result<String, String> {
+        let len = self.maps.len();
+        if len == 0 {
+            return Err("Only the built-in map is available".into());
+        }
+
+        if let Some(name) = map {
+            let index = match self.maps.iter().position(|map| map.name.eq_ignore_ascii_case(&name)) {
+                Some(index) => index,
+                None => {
+                    let names:Vec<&str> = self.maps.iter().map(|map| map.name.as_str()).collect();
+                    return Err(format!("No map named {}, the maps are {}", name, names.join(", ")));
+                }
+            };
+            // the next map is the map after the current map
+            self.map_index = (index + len - 1) % len;
+        }
+
+        if self.current.phase == MatchPhase::Round {
+            self.record_all_stats(false);
+        }
+        self.next_map();
+        if self.playing() >= MIN_PLAYERS {
+            self.start_round();
+        }
+
+        Ok(format!("Changed map to {}", self.maps[self.map_index].name))
+    }
+
+    /// updates the match at the tick rate, which is at most the rate the instance ticks at
+    fn change_tick_rate(&mut self, context:&mut Ctx, tick_rate:u64) -> Result<String, String> {
+        if tick_rate == 0 || tick_rate > self.max_tick_rate {
+            return Err(format!("Tick rate must be between 1 and {}, the rate the instance was started with", self.max_tick_rate));
+        }
+
+        self.tick_rate = tick_rate;
+        self.since_update = 0.0;
+        for player in self.players.values() {
+            push_custom_to(context, player.client_id, CustomMsg::ServerPlayerInfo {
+                thing_id:player.thing,
+                tick_rate:tick_rate as u8,
+                spectator:player.spectator
+            });
+        }
+
+        // the tick rate of a replay is given once, continue recording in a new replay
+        if self.recorder.is_some() {
+            self.start_recording();
+        }
+
+        Ok(format!("Changed tick rate to {}", tick_rate))
+    }
+
+    /// names the client in the audit log
+    fn client_source(&self, client_id:Uuid) -> String {
+        match self.players.get(&client_id) {
+            Some(player) => format!("{} ({})", player.client_name, client_id),
+            None => client_id.to_string()
+        }
+    }
+
+    fn audit(&self, source:&str, action:&str, outcome:&str) {
+        if let Some(audit) = &self.audit {
+            audit.record(self.instance, source, action, outcome);
+        }
+    }
+}
@@ -1,21 +1,26 @@
 use generational_arena::{Index};
 use glam::Vec2;
 use parry2d::{
-    math::Isometry,
-    query::{contact},
-    shape::{Ball, Polyline},
+    math::{Isometry, Vector},
+    query::{self, TOIStatus},
+    shape::{Ball, Segment, Shape},
 };
-use crate::{Event, Input, Solid, State, Thing};
+use crate::{team_of, update_ability, update_flags, switch_ability, Broadphase, Event, GameMode, Input, MatchPhase, MAX_HEARTS, PickupKind, PlayerThing, Solid, State, StateHistory, Thing};
 
-pub fn apply_input(state: &mut State, input: &Input, _authorative: bool) {
-    // how to avoid clone?
-    let cloned = state.clone();
+/// how much faster a player moves with a speed boost
+const SPEED_BOOST:f32 = 1.5;
+
+/// applies the input to the thing of the input, moving it against the colliders of the broadphase
+/// the broadphase is built once per tick and shared by every input of the tick
+pub fn apply_input(state: &mut State, broadphase: &Broadphase, input: &Input, _authorative: bool) {
     if let Some(thing_id) = input.thing_id {
+        switch_ability(state, thing_id, input.ability);
         if let Some(thing) = state.things.get_mut(thing_id) {
             if let Thing::Player(player) = thing {
                 if player.is_alive() {
-                    let new_pos = input.movement * player.speed as f32 + *thing.pos();
-                    move_thing_direct_sweep((thing_id, thing), new_pos, &cloned, None);
+                    let speed = if player.speed_boost > 0.0 { player.speed * SPEED_BOOST } else { player.speed };
+                    let new_pos = input.movement * speed + *thing.pos();
+                    move_thing_slide((thing_id, thing), new_pos, broadphase, None);
                     clamp_to_bounds(thing, state.width, state.height);
                 }
             }
@@ -23,76 +28,103 @@ pub fn apply_input(state: &mut State, input: &Input, _authorative: bool) {
     }
 }
 
-pub fn update_things(state: &mut State, dt: f64) {
-    // how to avoid clone
-    let cloned = state.clone();
-
+/// updates the things of the state, such as moving projectiles and collecting pickups
+/// the broadphase should be built after the inputs of the tick were applied
+pub fn update_things(state: &mut State, broadphase: &Broadphase, dt: f64) {
     let mut remove = Vec::new();
+    let mut expired = Vec::new();
     let mut hits = Vec::new();
 
     // movement and collision handling
     for (id, thing) in state.things.iter_mut() {
         if let Thing::Player(player) = thing {
-            player.ability_cooldown -= dt as f32;
-            if player.ability_cooldown < 0.0 {
-                player.ability_cooldown = 0.0;
+            update_ability(player, dt as f32);
+            for kind in update_powerups(player, dt as f32) {
+                state.events.push(Event::PickupExpired {
+                    thing_id: id,
+                    kind,
+                });
             }
         }
 
+        if let Thing::Pickup(pickup) = thing {
+            pickup.respawn_timer = (pickup.respawn_timer - dt as f32).max(0.0);
+        }
+
         if let Thing::Projectile(projectile) = thing {
             let owner = projectile.owner;
+            let damage = projectile.damage;
+            projectile.lifetime -= dt as f32;
+            if projectile.lifetime <= 0.0 {
+                expired.push(id);
+                continue;
+            }
+
             if projectile.vel.length_squared() > 0.0 {
                 let new_pos = projectile.vel * dt as f32 + *thing.pos();
-                let res = move_thing_direct_sweep((id, thing), new_pos, &cloned, Some(owner));
+                let res = move_thing_direct_sweep((id, thing), new_pos, broadphase, Some(owner));
 
                 match res {
                     CollisionResult::None => {}
                     CollisionResult::Thing(target) => {
                         remove.push(id);
-                        hits.push((owner, target));
+                        hits.push((owner, target, damage));
                     }
-                    CollisionResult::Polyline(id, _normal) => {
-                        remove.push(id);
+                    CollisionResult::Polyline(id, normal) => {
+                        if bounce_projectile(thing, normal) {
+                            state.events.push(Event::ProjectileBounced {
+                                pos: *thing.pos(),
+                                normal,
+                            });
+                        } else {
+                            remove.push(id);
+                        }
                     }
                 }
             }
         }
     }
 
+    // projectiles running out of time vanish without hitting anything
+    for id in expired.drain(..) {
+        state.things.remove(id);
+    }
+
     // hit / damage handling
-    for (owner, target) in hits.drain(..) {
-        if let Some(thing) = state.things.get_mut(target) {
-            if let Thing::Player(player) = thing {
-                if player.is_alive() {
-                    player.hearts -= 1;
-
-                    if !player.is_alive() {
-                        player.respawn_timer = 3.0;
-                        player.deaths += 1;
-                        player.solid = Solid::None;
-
-                        state.events.push(Event::PlayerDied {
-                            thing_id: target,
-                            pos: player.pos,
-                        });
-
-                        if let Some(thing) = state.things.get_mut(owner) {
-                            if let Thing::Player(owner) = thing {
-                                owner.kills += 1;
-                            }
-                        }
-                    }
+    for (owner, target, damage) in hits.drain(..) {
+        hit_thing(state, owner, target, damage);
+    }
+
+    // pickup handling, by the players touching them
+    let mut touched = Vec::new();
+    for (id, thing) in state.things.iter() {
+        if let Thing::Pickup(pickup) = thing {
+            if !pickup.is_active() {
+                continue;
+            }
+
+            for other in broadphase.query(pickup.pos, pickup.radius) {
+                if other.pos.distance(pickup.pos) < other.radius + pickup.radius {
+                    touched.push((id, other.id));
                 }
             }
         }
     }
 
+    for (pickup_id, player_id) in touched.drain(..) {
+        collect_pickup(state, pickup_id, player_id);
+    }
+
+    // flag handling, when capturing the flag
+    update_flags(state, broadphase, dt as f32);
+
     // player respawn handling
     for (_id, thing) in state.things.iter_mut() {
         if let Thing::Player(player) = thing {
             if !player.is_alive() {
                 if player.spawn_pos == None {
-                    player.spawn_pos = Some(State::next_spawn_pos(&mut state.next_spawn, &state.map.spawn_points));
+                    let spawn_points = state.map.spawn_points_of(player.team);
+                    player.spawn_pos = Some(State::next_spawn_pos(&mut state.next_spawn, &spawn_points, &mut state.rng));
                 }
                 player.respawn_timer -= dt as f32;
                 if player.respawn_timer <= 0.0 {
@@ -116,20 +148,245 @@ pub fn update_things(state: &mut State, dt: f64) {
 
     // removal of entities who needs removed
     for id in remove.drain(..) {
-        if let Some(thing) = state.things.remove(id) {
-            if let Thing::Projectile(projectile) = thing {
-                state.events.push(Event::ProjectileHit {
-                    pos: projectile.pos,
-                })
+        remove_thing(state, id);
+    }
+}
+
+/// removes the thing, pushing a hit event if the thing was a projectile
+fn remove_thing(state: &mut State, id: Index) {
+    if let Some(thing) = state.things.remove(id) {
+        if let Thing::Projectile(projectile) = thing {
+            state.events.push(Event::ProjectileHit {
+                pos: projectile.pos,
+            })
+        }
+    }
+}
+
+/// counts down the effects of pickups on the player
+/// returns the kinds of pickups whose effect ran out
+fn update_powerups(player: &mut PlayerThing, dt: f32) -> Vec<PickupKind> {
+    let mut expired = Vec::new();
+    if player.speed_boost > 0.0 {
+        player.speed_boost = (player.speed_boost - dt).max(0.0);
+        if player.speed_boost == 0.0 {
+            expired.push(PickupKind::Speed);
+        }
+    }
+
+    if player.shield > 0.0 {
+        player.shield = (player.shield - dt).max(0.0);
+        if player.shield == 0.0 {
+            expired.push(PickupKind::Shield);
+        }
+    }
+
+    expired
+}
+
+/// gives the effect of the pickup to the player, unless already collected or of no use to the player
+fn collect_pickup(state: &mut State, pickup_id: Index, player_id: Index) {
+    if let (Some(Thing::Pickup(pickup)), Some(Thing::Player(player))) = state.things.get2_mut(pickup_id, player_id) {
+        if !pickup.is_active() || !player.is_alive() {
+            return;
+        }
+
+        let kind = pickup.kind;
+        match kind {
+            PickupKind::Heart => {
+                if player.hearts >= MAX_HEARTS {
+                    return;
+                }
+
+                player.hearts += 1;
+            }
+            PickupKind::Speed => player.speed_boost = kind.duration_sec(),
+            PickupKind::Shield => player.shield = kind.duration_sec(),
+            PickupKind::Weapon(ability) => {
+                let max = ability.stats().max_ammo.unwrap_or(0);
+                if player.ammo(ability).map(|ammo| ammo >= max).unwrap_or(true) {
+                    return;
+                }
+
+                player.give_ammo(ability, max);
+            }
+        }
+
+        pickup.respawn_timer = kind.respawn_sec();
+        state.events.push(Event::PickupCollected {
+            thing_id: player_id,
+            kind,
+            pos: pickup.pos,
+        });
+    }
+}
+
+/// damages the target thing hit by something owned by owner
+pub fn hit_thing(state: &mut State, owner: Index, target: Index, damage: i8) {
+    // no damage is dealt after the round has ended
+    if state.phase == MatchPhase::Intermission {
+        return;
+    }
+
+    let owner_team = team_of(state, owner);
+    let friendly = owner != target && owner_team.is_some() && owner_team == team_of(state, target);
+    if friendly && !state.friendly_fire {
+        return;
+    }
+
+    if let Some(thing) = state.things.get_mut(target) {
+        if let Thing::Player(player) = thing {
+            // a shielded player takes no damage
+            if player.is_alive() && player.shield <= 0.0 {
+                player.hearts = (player.hearts - damage).max(0);
+
+                if !player.is_alive() {
+                    player.respawn_timer = 3.0;
+                    player.deaths += 1;
+                    player.solid = Solid::None;
+
+                    state.events.push(Event::PlayerDied {
+                        thing_id: target,
+                        pos: player.pos,
+                    });
+
+                    // killing a team mate does not count
+                    if friendly {
+                        return;
+                    }
+
+                    if let Some(thing) = state.things.get_mut(owner) {
+                        if let Thing::Player(owner) = thing {
+                            owner.kills += 1;
+                        }
+                    }
+
+                    if let (GameMode::TeamDeathmatch, Some(team)) = (state.mode, owner_team) {
+                        state.team_scores[team.index()] += 1;
+                    }
+                }
             }
         }
     }
 }
 
+/// moves things back to where they were at the given timestamp according to the history
+/// things not found in the history and the ignored thing are left as is
+/// returns the present positions of the rewound things, to be used with `restore_things`
+pub fn rewind_things(state: &mut State, history: &StateHistory, timestamp: f64, ignore: Option<Index>) -> Vec<(Index, Vec2)> {
+    let mut restore = Vec::new();
+    if let Some((prev, next, alpha)) = history.find(timestamp) {
+        for (id, thing) in state.things.iter_mut() {
+            if Some(id) == ignore {
+                continue;
+            }
+
+            if let (Some(prev), Some(next)) = (prev.things.get(id), next.things.get(id)) {
+                restore.push((id, *thing.pos()));
+                *thing.pos_mut() = next.lerp_pos(prev, alpha);
+            }
+        }
+    }
+
+    restore
+}
+
+/// moves things rewound by `rewind_things` back to their present positions
+pub fn restore_things(state: &mut State, restore: Vec<(Index, Vec2)>) {
+    for (id, pos) in restore {
+        if let Some(thing) = state.things.get_mut(id) {
+            *thing.pos_mut() = pos;
+        }
+    }
+}
+
+/// spawns a projectile which was fired by a player at the given timestamp, as seen by the player
+/// the other things are rewound to the timestamp and the projectile is moved forward
+/// by the time elapsed since, such that hits are resolved against what the player saw
+/// rewinding is limited to max_rewind_sec into the past
+pub fn spawn_projectile_compensated(
+    state: &mut State,
+    history: &StateHistory,
+    projectile: Thing,
+    timestamp: f64,
+    max_rewind_sec: f64,
+) {
+    let (owner, damage) = match &projectile {
+        Thing::Projectile(projectile) => (projectile.owner, projectile.damage),
+        _ => return,
+    };
+
+    let id = state.things.insert(projectile);
+    let timestamp = timestamp.max(state.timestamp - max_rewind_sec);
+    let elapsed = state.timestamp - timestamp;
+    if elapsed <= 0.0 {
+        return;
+    }
+
+    let restore = rewind_things(state, history, timestamp, Some(owner));
+
+    let broadphase = Broadphase::new(state);
+    let mut res = CollisionResult::None;
+    if let Some(thing) = state.things.get_mut(id) {
+        if let Thing::Projectile(projectile) = thing {
+            let new_pos = projectile.vel * elapsed as f32 + projectile.pos;
+            res = move_thing_direct_sweep((id, thing), new_pos, &broadphase, Some(owner));
+        }
+    }
+
+    restore_things(state, restore);
+
+    match res {
+        CollisionResult::None => {}
+        CollisionResult::Thing(target) => {
+            remove_thing(state, id);
+            hit_thing(state, owner, target, damage);
+        }
+        CollisionResult::Polyline(_, normal) => {
+            let mut bounced = None;
+            if let Some(thing) = state.things.get_mut(id) {
+                if bounce_projectile(thing, normal) {
+                    bounced = Some(*thing.pos());
+                }
+            }
+
+            match bounced {
+                Some(pos) => state.events.push(Event::ProjectileBounced { pos, normal }),
+                None => remove_thing(state, id),
+            }
+        }
+    }
+}
+
+/// reflects the velocity of the projectile around the normal of the wall hit, if it has bounces left
+/// the normal points out of the wall, a projectile already moving away from the wall keeps its velocity
+/// returns false if the projectile did not bounce
+pub fn bounce_projectile(thing: &mut Thing, normal: Vec2) -> bool {
+    if let Thing::Projectile(projectile) = thing {
+        if projectile.bounces > 0 && normal.length_squared() > 0.0 {
+            let normal = normal.normalize();
+            let dot = projectile.vel.dot(normal);
+            if dot < 0.0 {
+                projectile.vel -= 2.0 * dot * normal;
+            }
+            projectile.bounces -= 1;
+            return true;
+        }
+    }
+
+    false
+}
+
+/// returns the min and max positions things are allowed to be within
+pub fn bounds(width:f32, height:f32) -> (Vec2, Vec2) {
+    let margin = 1.0;
+    (Vec2::new(margin, margin * 2.0), Vec2::new(width - margin, height - margin))
+}
+
 pub fn clamp_to_bounds(thing:&mut Thing, width:f32, height:f32) -> bool {
     let pos = *thing.pos();
-    let margin = 1.0;
-    *thing.pos_mut() = pos.clamp(Vec2::new(margin, margin * 2.0), Vec2::new(width - margin, height - margin));
+    let (min, max) = bounds(width, height);
+    *thing.pos_mut() = pos.clamp(min, max);
 
     if pos != *thing.pos() {
         return true;
@@ -150,127 +407,595 @@ pub enum CollisionResult {
     Polyline(Index, Vec2),
 }
 
+/// moves the thing towards the new position, stopping at the first solid thing or wall in the way
+/// the circle of the thing is swept along the movement and the time of impact is found exactly,
+/// such that fast things neither tunnel through thin walls nor stop before reaching them
+/// things already overlapping something can always move away from it
 pub fn move_thing_direct_sweep(
     thing: (Index, &mut Thing),
     new_pos: Vec2,
-    state: &State,
+    broadphase: &Broadphase,
     ignore: Option<Index>,
 ) -> CollisionResult {
-    let mut result = CollisionResult::None;
-    let vel = new_pos - *thing.1.pos();
-    if vel.length() > 0.0 {
-        let mut dist = vel.length();
-        let max_step = *thing.1.radius() / 2.0;
-        let d = vel.normalize();
-
-        let mut new_pos = *thing.1.pos();
-        while dist > 0.0 {
-            let step = dist.min(max_step);
-            new_pos += d * step;
-            result = move_thing_direct((thing.0, thing.1), new_pos, state, ignore);
-            dist -= step;
-
-            if result != CollisionResult::None {
-                break;
-            }
+    let (thing_id, thing) = thing;
+    let pos = *thing.pos();
+    let movement = new_pos - pos;
+    if *thing.solid() == Solid::None || movement.length_squared() == 0.0 {
+        *thing.pos_mut() = new_pos;
+        return CollisionResult::None;
+    }
 
+    match sweep(thing_id, pos, *thing.radius(), movement, broadphase, ignore) {
+        Some((toi, _normal, result)) => {
+            *thing.pos_mut() = pos + movement * toi;
+            result
+        }
+        None => {
+            *thing.pos_mut() = new_pos;
+            CollisionResult::None
         }
     }
-    result
 }
 
-fn move_thing_direct(
+/// the most times the movement is resolved against what is hit when sliding
+const MAX_SLIDE_ITERATIONS: usize = 4;
+
+/// moves the thing like `move_thing_direct_sweep`, but instead of stopping at a wall or thing
+/// the remaining movement is projected along the surface hit, sliding the thing along it
+/// this is repeated for each surface hit, until the movement is used or the thing is wedged in a corner
+/// returns what was hit first
+pub fn move_thing_slide(
     thing: (Index, &mut Thing),
     new_pos: Vec2,
-    state: &State,
+    broadphase: &Broadphase,
     ignore: Option<Index>,
 ) -> CollisionResult {
+    let (thing_id, thing) = thing;
+    let mut pos = *thing.pos();
+    let mut remaining = new_pos - pos;
+    if *thing.solid() == Solid::None || remaining.length_squared() == 0.0 {
+        *thing.pos_mut() = new_pos;
+        return CollisionResult::None;
+    }
+
+    let radius = *thing.radius();
     let mut result = CollisionResult::None;
-    let (thing_id, thing1) = thing;
-    *thing1.pos_mut() = new_pos;
+    let mut prev_normal: Option<Vec2> = None;
+    for _ in 0..MAX_SLIDE_ITERATIONS {
+        match sweep(thing_id, pos, radius, remaining, broadphase, ignore) {
+            Some((toi, normal, hit)) => {
+                pos += remaining * toi;
+                if result == CollisionResult::None {
+                    result = hit;
+                }
 
-    if *thing1.solid() != Solid::None {
-        for (thing_id2, thing2) in state.things.iter() {
-            // check same
-            if thing_id == thing_id2 {
-                continue;
+                let rest = remaining * (1.0 - toi);
+                let tangent = rest - normal * rest.dot(normal);
+
+                // sliding along this surface would move into the previous one, i.e. the thing is in a corner
+                if prev_normal.map(|n| tangent.dot(n) < 0.0).unwrap_or(false) {
+                    break;
+                }
+
+                prev_normal = Some(normal);
+                remaining = tangent;
+                if remaining.length_squared() <= f32::EPSILON {
+                    break;
+                }
+            }
+            None => {
+                pos += remaining;
+                break;
             }
+        }
+    }
 
-            // check if Solid and not just partially solid
-            if *thing2.solid() != Solid::Solid {
-                continue;
+    *thing.pos_mut() = pos;
+    result
+}
+
+/// sweeps a circle at pos by the movement, returning the earliest hit
+/// as the fraction of the movement until the hit, the normal of the surface hit and what was hit
+fn sweep(
+    thing_id: Index,
+    pos: Vec2,
+    radius: f32,
+    movement: Vec2,
+    broadphase: &Broadphase,
+    ignore: Option<Index>,
+) -> Option<(f32, Vec2, CollisionResult)> {
+    let length = movement.length();
+    if length == 0.0 {
+        return None;
+    }
+
+    // everything which can be hit is within reach of the middle of the movement
+    let center = pos + movement * 0.5;
+    let reach = radius + length * 0.5;
+    let mut hit: Option<(f32, Vec2, CollisionResult)> = None;
+    let earlier = |hit: &Option<(f32, Vec2, CollisionResult)>, toi: f32| hit.as_ref().map(|h| toi < h.0).unwrap_or(true);
+
+    for other in broadphase.query(center, reach) {
+        if other.id == thing_id || Some(other.id) == ignore {
+            continue;
+        }
+
+        if let Some((toi, normal)) = time_of_impact(pos, movement, radius, other.pos, &Ball::new(other.radius)) {
+            if earlier(&hit, toi) {
+                hit = Some((toi, normal, CollisionResult::Thing(other.id)));
             }
+        }
+    }
+
+    for collider in broadphase.map_colliders() {
+        if !collider.overlaps(center, reach) {
+            continue;
+        }
 
-            // check ignore
-            if let Some(ignore) = ignore {
-                if thing_id2 == ignore {
-                    continue;
+        for (a, b) in collider.segments() {
+            let segment = Segment::new([a.x, a.y].into(), [b.x, b.y].into());
+            if let Some((toi, normal)) = time_of_impact(pos, movement, radius, Vec2::ZERO, &segment) {
+                if earlier(&hit, toi) {
+                    hit = Some((toi, normal, CollisionResult::Polyline(thing_id, normal)));
                 }
             }
+        }
+    }
 
-            let pos1: Isometry<f32> = [thing1.pos().x, thing1.pos().y].into();
-            let ball1 = Ball::new(*thing1.radius());
-
-            let pos2: Isometry<f32> = [thing2.pos().x, thing2.pos().y].into();
-            let ball2 = Ball::new(*thing2.radius());
-
-            let c = contact(&pos1, &ball1, &pos2, &ball2, 10.0);
-            match c {
-                Ok(res) => match res {
-                    Some(res) => {
-                        if res.dist < 0.0 {
-                            let p: Vec2 = [res.normal1.x, res.normal1.y].into();
-                            let p = p * res.dist;
-                            *thing1.pos_mut() += p;
-                            result = CollisionResult::Thing(thing_id2);
-                            break;
-                        }
-                    }
-                    _ => {}
-                },
-                _ => {}
-            }
+    hit
+}
+
+/// time of impact of a circle at pos moving by the movement with the shape at other_pos
+/// returns the fraction of the movement and the normal of the shape at the point of impact
+/// a circle already touching the shape hits it right away, unless moving away from it or along it
+fn time_of_impact(pos: Vec2, movement: Vec2, radius: f32, other_pos: Vec2, other: &dyn Shape) -> Option<(f32, Vec2)> {
+    let ball = Ball::new(radius);
+    let pos1: Isometry<f32> = [pos.x, pos.y].into();
+    let pos2: Isometry<f32> = [other_pos.x, other_pos.y].into();
+    let vel1 = Vector::new(movement.x, movement.y);
+    let toi = query::time_of_impact(&pos1, &vel1, &ball, &pos2, &Vector::zeros(), other, 1.0).ok()??;
+    if toi.toi > 0.0 && toi.status != TOIStatus::Penetrating {
+        return Some((toi.toi, Vec2::new(toi.normal2.x, toi.normal2.y)));
+    }
+
+    // the normals are not reliable when the shapes already touch, so they are found from the contact instead
+    let contact = query::contact(&pos1, &ball, &pos2, other, radius).ok()??;
+    let normal = Vec2::new(contact.normal2.x, contact.normal2.y);
+    match movement.dot(normal) < -GRAZING * movement.length() {
+        true => Some((0.0, normal)),
+        false => None
+    }
+}
+
+/// the cosine of the angle below which a touching thing moving towards a surface is moving along it instead
+/// such that a thing left touching a wall after sliding can keep sliding along it
+const GRAZING: f32 = 0.001;
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::{Map, Polyline, SimRng, Solid, StateHistory, Team};
+
+    fn state_with(polylines:&[&[[f32; 2]]]) -> State {
+        let mut map = Map::new();
+        for points in polylines {
+            map.polylines.insert(Polyline {
+                points:points.iter().map(|p| Vec2::from(*p)).collect()
+            });
+        }
+
+        State::with_map(map, 100.0, 100.0)
+    }
+
+    fn player(pos:Vec2) -> Thing {
+        Thing::Player(PlayerThing {
+            pos,
+            radius:0.5,
+            solid:Solid::Solid,
+            ..Default::default()
+        })
+    }
+
+    /// updates the things as the server does once the inputs are applied
+    fn tick(state:&mut State, dt:f64) {
+        let broadphase = Broadphase::new(state);
+        update_things(state, &broadphase, dt);
+    }
+
+    fn no_id() -> Index {
+        Index::from_raw_parts(usize::MAX, 0)
+    }
+
+    fn distance_to_segment(p:Vec2, a:Vec2, b:Vec2) -> f32 {
+        let edge = b - a;
+        let t = ((p - a).dot(edge) / edge.length_squared()).clamp(0.0, 1.0);
+        p.distance(a + edge * t)
+    }
+
+    /// the distance from the position to the closest wall of the map
+    fn distance_to_walls(state:&State, pos:Vec2) -> f32 {
+        let broadphase = Broadphase::new(state);
+        broadphase.map_colliders().iter()
+            .flat_map(|collider| collider.segments())
+            .map(|(a, b)| distance_to_segment(pos, a, b))
+            .fold(f32::MAX, f32::min)
+    }
+
+    #[test]
+    fn time_of_impact_hits_at_the_surface() {
+        let (toi, normal) = time_of_impact(Vec2::new(0.0, 0.0), Vec2::new(10.0, 0.0), 0.5, Vec2::new(5.0, 0.0), &Ball::new(0.5)).unwrap();
+        assert!((toi - 0.4).abs() < 1e-4);
+        assert!(normal.distance(Vec2::new(-1.0, 0.0)) < 1e-4);
+
+        // moving out of a circle already overlapped does not hit it
+        assert!(time_of_impact(Vec2::new(5.0, 0.5), Vec2::new(0.0, 10.0), 0.5, Vec2::new(5.0, 0.0), &Ball::new(0.5)).is_none());
+        // while moving further into it does right away
+        let (toi, normal) = time_of_impact(Vec2::new(5.0, 0.5), Vec2::new(0.0, -10.0), 0.5, Vec2::new(5.0, 0.0), &Ball::new(0.5)).unwrap();
+        assert_eq!(toi, 0.0);
+        assert!(normal.distance(Vec2::new(0.0, 1.0)) < 1e-4);
+    }
+
+    #[test]
+    fn time_of_impact_hits_thin_segment_at_high_speed() {
+        // the segment has no thickness at all and the circle moves a thousand times its radius in one step
+        let segment = Segment::new([10.0, -1.0].into(), [10.0, 1.0].into());
+        let (toi, normal) = time_of_impact(Vec2::new(0.0, 0.0), Vec2::new(1000.0, 0.0), 0.5, Vec2::ZERO, &segment).unwrap();
+        assert!((toi - 9.5 / 1000.0).abs() < 1e-5);
+        assert!(normal.distance(Vec2::new(-1.0, 0.0)) < 1e-4);
+
+        // touching the segment, a circle can move along it
+        assert!(time_of_impact(Vec2::new(9.5, 0.0), Vec2::new(0.0, 5.0), 0.5, Vec2::ZERO, &segment).is_none());
+    }
+
+    #[test]
+    fn sweep_stops_before_thin_wall_at_high_speed() {
+        let state = state_with(&[&[[10.0, -5.0], [10.0, 5.0], [10.05, 5.0], [10.05, -5.0]]]);
+        let broadphase = Broadphase::new(&state);
+        let mut thing = player(Vec2::new(0.0, 0.0));
+        let res = move_thing_direct_sweep((no_id(), &mut thing), Vec2::new(5000.0, 0.0), &broadphase, None);
+
+        assert!(matches!(res, CollisionResult::Polyline(_, _)));
+        assert!(thing.pos().x <= 9.5 + 1e-4);
+        assert!(thing.pos().x > 9.49);
+    }
+
+    #[test]
+    fn slide_into_concave_corner_stays_outside_both_walls() {
+        // a room, with the circle moving diagonally into its corner
+        let state = state_with(&[&[[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]]]);
+        let broadphase = Broadphase::new(&state);
+        for target in [Vec2::new(20.0, 20.0), Vec2::new(30.0, 12.0), Vec2::new(12.0, 300.0)] {
+            let mut thing = player(Vec2::new(5.0, 5.0));
+            move_thing_slide((no_id(), &mut thing), target, &broadphase, None);
+
+            let pos = *thing.pos();
+            assert!(pos.x <= 9.5 && pos.y <= 9.5, "{:?} left the room moving to {:?}", pos, target);
+            assert!(distance_to_walls(&state, pos) >= 0.5 - 1e-4);
+        }
+    }
+
+    #[test]
+    fn grazing_slide_does_not_penetrate() {
+        let state = state_with(&[&[[-100.0, 0.0], [100.0, 0.0], [100.0, -1.0], [-100.0, -1.0]]]);
+        let broadphase = Broadphase::new(&state);
+        for (x, y) in [(10.0, -0.2), (50.0, -0.01), (3.0, -1.0), (80.0, -5.0)] {
+            let mut thing = player(Vec2::new(0.0, 0.6));
+            let res = move_thing_slide((no_id(), &mut thing), Vec2::new(x, 0.6 + y), &broadphase, None);
+
+            let pos = *thing.pos();
+            assert!(matches!(res, CollisionResult::Polyline(_, _)));
+            assert!(pos.y >= 0.5 - 1e-4, "{:?} penetrated the wall", pos);
+            assert!(distance_to_walls(&state, pos) >= 0.5 - 1e-4);
+            // the movement along the wall is kept
+            assert!((pos.x - x).abs() < 0.01, "{:?} did not slide to {}", pos, x);
         }
+    }
 
-        /*if result != CollisionResult::None {
-            return result;
-        }*/
+    /// true if the point is inside the triangle
+    fn inside_triangle(p:Vec2, t:&[Vec2]) -> bool {
+        let side = |a:Vec2, b:Vec2| (b - a).perp_dot(p - a);
+        let (d0, d1, d2) = (side(t[0], t[1]), side(t[1], t[2]), side(t[2], t[0]));
+        (d0 > 0.0 && d1 > 0.0 && d2 > 0.0) || (d0 < 0.0 && d1 < 0.0 && d2 < 0.0)
+    }
 
-        for (_id, p) in &state.map.polylines {
-            let mut points = Vec::new();
-            for p in &p.points {
-                points.push([p.x, p.y].into());
+    #[test]
+    fn bouncing_projectiles_do_not_tunnel() {
+        let room = [[5.0, 5.0], [95.0, 5.0], [95.0, 95.0], [5.0, 95.0]];
+        for seed in 0..200 {
+            let mut rng = SimRng::new(seed);
+            let mut triangles = Vec::new();
+            for _ in 0..rng.gen_range(1..6) {
+                let center = Vec2::new(rng.gen_range(20.0..80.0), rng.gen_range(20.0..80.0));
+                let triangle:Vec<[f32; 2]> = (0..3).map(|i| {
+                    let angle = i as f32 * 2.1 + rng.gen_range(0.0..1.0);
+                    let p = center + Vec2::new(angle.cos(), angle.sin()) * rng.gen_range(2.0..8.0);
+                    [p.x, p.y]
+                }).collect();
+                triangles.push(triangle);
             }
-            if let Some(p) = points.first() {
-                let p = *p;
-                points.push(p);
+
+            let mut polylines:Vec<&[[f32; 2]]> = vec![&room[..]];
+            polylines.extend(triangles.iter().map(|t| t.as_slice()));
+            let mut state = state_with(&polylines);
+            let triangles:Vec<Vec<Vec2>> = triangles.iter().map(|t| t.iter().map(|p| Vec2::from(*p)).collect()).collect();
+
+            let pos = loop {
+                let pos = Vec2::new(rng.gen_range(6.0..94.0), rng.gen_range(6.0..94.0));
+                if distance_to_walls(&state, pos) > 0.5 && !triangles.iter().any(|t| inside_triangle(pos, t)) {
+                    break pos;
+                }
+            };
+            let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+            let speed = rng.gen_range(10.0..600.0);
+            let mut projectile = Thing::new_projectile(pos, Vec2::new(angle.cos(), angle.sin()) * speed, no_id());
+            if let Thing::Projectile(projectile) = &mut projectile {
+                projectile.bounces = u8::MAX;
+                projectile.lifetime = 100.0;
             }
+            let id = state.things.insert(projectile);
 
-            let lines = Polyline::new(points, None);
-
-            let pos1: Isometry<f32> = [thing1.pos().x, thing1.pos().y].into();
-            let ball1 = Ball::new(*thing1.radius());
-            let c = contact(&pos1, &ball1, &[0.0, 0.0].into(), &lines, 1.0);
-
-            match c {
-                Ok(res) => match res {
-                    Some(res) => {
-                        if res.dist < 0.0 {
-                            let p: Vec2 = [res.normal1.x, res.normal1.y].into();
-                            let p = p * res.dist;
-                            *thing1.pos_mut() += p;
-                            if result == CollisionResult::None {
-                                result = CollisionResult::Polyline(thing_id, Vec2::new(res.normal2.x, res.normal2.y));
-                            }
-                            break;
-                        }
-                    }
-                    _ => {}
-                },
-                _ => {}
+            for tick in 0..100 {
+                tick(&mut state, 0.05);
+                let pos = match state.things.get(id) {
+                    Some(thing) => *thing.pos(),
+                    None => break
+                };
+
+                assert!(pos.x > 5.0 && pos.x < 95.0 && pos.y > 5.0 && pos.y < 95.0, "seed {} tick {}: {:?} left the room", seed, tick, pos);
+                assert!(!triangles.iter().any(|t| inside_triangle(pos, t)), "seed {} tick {}: {:?} is inside a wall", seed, tick, pos);
             }
         }
     }
 
-    return result;
+    #[test]
+    fn expired_projectiles_are_removed_without_hit() {
+        let mut state = state_with(&[]);
+        let id = state.things.insert(Thing::new_projectile(Vec2::new(50.0, 50.0), Vec2::new(1.0, 0.0), no_id()));
+        if let Some(Thing::Projectile(projectile)) = state.things.get_mut(id) {
+            projectile.lifetime = 0.01;
+        }
+
+        tick(&mut state, 0.05);
+        assert!(state.things.get(id).is_none());
+        assert!(!state.events.iter().any(|e| matches!(e, Event::ProjectileHit { .. })));
+    }
+
+    /// a player with every heart
+    fn living(pos:Vec2) -> Thing {
+        let mut thing = player(pos);
+        if let Thing::Player(player) = &mut thing {
+            player.hearts = MAX_HEARTS;
+        }
+        thing
+    }
+
+    fn hearts(state:&State, id:Index) -> i8 {
+        match state.things.get(id) {
+            Some(Thing::Player(player)) => player.hearts,
+            _ => 0
+        }
+    }
+
+    /// a shooter and a target walking up by 10 units per second, remembered every 0.05 seconds for 0.5 seconds
+    /// the target is at (30, 40) half a second ago and at (30, 45) now
+    fn moving_target() -> (State, StateHistory, Index, Index) {
+        let mut state = state_with(&[]);
+        let shooter = state.things.insert(living(Vec2::new(10.0, 60.0)));
+        let target = state.things.insert(living(Vec2::new(30.0, 40.0)));
+
+        let mut history = StateHistory::new();
+        for step in 0..=10 {
+            state.timestamp = step as f64 * 0.05;
+            *state.things[target].pos_mut() = Vec2::new(30.0, 40.0 + step as f32 * 0.5);
+            history.remember(state.clone());
+        }
+
+        (state, history, shooter, target)
+    }
+
+    #[test]
+    fn compensated_projectile_hits_where_the_target_was_seen() {
+        let (mut state, history, shooter, target) = moving_target();
+        let projectile = Thing::new_projectile(Vec2::new(10.0, 40.0), Vec2::new(100.0, 0.0), shooter);
+        spawn_projectile_compensated(&mut state, &history, projectile, 0.0, 1.0);
+
+        assert_eq!(hearts(&state, target), MAX_HEARTS - 1);
+        assert!(state.events.iter().any(|e| matches!(e, Event::ProjectileHit { .. })));
+        assert_eq!(state.things.iter().filter(|(_, t)| matches!(t, Thing::Projectile(_))).count(), 0);
+        // the target is back where it is now
+        assert_eq!(*state.things[target].pos(), Vec2::new(30.0, 45.0));
+        assert_eq!(*state.things[shooter].pos(), Vec2::new(10.0, 60.0));
+    }
+
+    #[test]
+    fn uncompensated_projectile_misses_the_present_target() {
+        let (mut state, history, shooter, target) = moving_target();
+        let projectile = Thing::new_projectile(Vec2::new(10.0, 40.0), Vec2::new(100.0, 0.0), shooter);
+        spawn_projectile_compensated(&mut state, &history, projectile, 0.0, 0.0);
+        for _ in 0..10 {
+            tick(&mut state, 0.05);
+        }
+
+        assert_eq!(hearts(&state, target), MAX_HEARTS);
+        assert_eq!(*state.things[target].pos(), Vec2::new(30.0, 45.0));
+    }
+
+    #[test]
+    fn rewinding_is_clamped_to_max_rewind() {
+        // fired long ago, but rewound only 0.2 seconds, to where the target was at (30, 43)
+        let (mut state, history, shooter, target) = moving_target();
+        let projectile = Thing::new_projectile(Vec2::new(15.0, 40.0), Vec2::new(100.0, 0.0), shooter);
+        spawn_projectile_compensated(&mut state, &history, projectile, -10.0, 0.2);
+        assert_eq!(hearts(&state, target), MAX_HEARTS);
+
+        let projectile = Thing::new_projectile(Vec2::new(15.0, 43.0), Vec2::new(100.0, 0.0), shooter);
+        spawn_projectile_compensated(&mut state, &history, projectile, -10.0, 0.2);
+        assert_eq!(hearts(&state, target), MAX_HEARTS - 1);
+        assert_eq!(*state.things[target].pos(), Vec2::new(30.0, 45.0));
+    }
+
+    #[test]
+    fn history_find_clamps_to_oldest_and_interpolates() {
+        let (_, history, _, target) = moving_target();
+        let (prev, next, alpha) = history.find(-1.0).unwrap();
+        assert_eq!(prev.timestamp, 0.0);
+        assert_eq!(next.timestamp, 0.0);
+        assert_eq!(alpha, 0.0);
+
+        let (prev, next, alpha) = history.find(0.125).unwrap();
+        assert_eq!(prev.timestamp, 0.1);
+        assert!((alpha - 0.5).abs() < 1e-4);
+        assert!(next.things[target].lerp_pos(&prev.things[target], alpha).distance(Vec2::new(30.0, 41.25)) < 1e-4);
+
+        assert!(history.find(1.0).is_none());
+    }
+
+    fn collected(state:&State) -> Vec<PickupKind> {
+        state.events.iter().filter_map(|e| match e {
+            Event::PickupCollected { kind, .. } => Some(*kind),
+            _ => None
+        }).collect()
+    }
+
+    fn expired(state:&State) -> Vec<PickupKind> {
+        state.events.iter().filter_map(|e| match e {
+            Event::PickupExpired { kind, .. } => Some(*kind),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn pickup_is_collected_once_until_it_respawns() {
+        let mut state = state_with(&[]);
+        let pos = Vec2::new(50.0, 50.0);
+        let pickup = state.things.insert(Thing::new_pickup(pos, PickupKind::Heart));
+        let id = state.things.insert(living(pos));
+
+        // of no use to a player with every heart
+        tick(&mut state, 0.05);
+        assert!(collected(&state).is_empty());
+
+        let wounded = |state:&mut State| {
+            if let Some(Thing::Player(player)) = state.things.get_mut(id) {
+                player.hearts -= 1;
+            }
+        };
+        wounded(&mut state);
+        tick(&mut state, 0.05);
+        assert_eq!(collected(&state), vec![PickupKind::Heart]);
+        assert_eq!(hearts(&state, id), MAX_HEARTS);
+        assert!(matches!(&state.things[pickup], Thing::Pickup(p) if !p.is_active()));
+
+        wounded(&mut state);
+        let mut elapsed = 0.0;
+        while hearts(&state, id) < MAX_HEARTS {
+            state.events.clear();
+            tick(&mut state, 0.5);
+            elapsed += 0.5;
+            assert!(elapsed <= PickupKind::Heart.respawn_sec(), "not respawned after {} seconds", elapsed);
+        }
+
+        assert_eq!(elapsed, PickupKind::Heart.respawn_sec());
+        assert_eq!(collected(&state), vec![PickupKind::Heart]);
+    }
+
+    #[test]
+    fn speed_and_shield_expire_with_an_event() {
+        let mut state = state_with(&[]);
+        let pos = Vec2::new(50.0, 50.0);
+        state.things.insert(Thing::new_pickup(pos, PickupKind::Speed));
+        state.things.insert(Thing::new_pickup(pos, PickupKind::Shield));
+        let id = state.things.insert(living(pos));
+        let attacker = state.things.insert(living(Vec2::new(10.0, 10.0)));
+
+        tick(&mut state, 0.05);
+        assert_eq!(collected(&state).len(), 2);
+        let (speed_boost, shield) = match &state.things[id] {
+            Thing::Player(player) => (player.speed_boost, player.shield),
+            _ => (0.0, 0.0)
+        };
+        assert_eq!(speed_boost, PickupKind::Speed.duration_sec());
+        assert_eq!(shield, PickupKind::Shield.duration_sec());
+
+        // shielded, no damage is taken
+        hit_thing(&mut state, attacker, id, 1);
+        assert_eq!(hearts(&state, id), MAX_HEARTS);
+
+        state.events.clear();
+        tick(&mut state, PickupKind::Shield.duration_sec());
+        assert_eq!(expired(&state), vec![PickupKind::Shield]);
+        assert!(state.events.iter().any(|e| matches!(e, Event::PickupExpired { thing_id, .. } if *thing_id == id)));
+
+        hit_thing(&mut state, attacker, id, 1);
+        assert_eq!(hearts(&state, id), MAX_HEARTS - 1);
+
+        state.events.clear();
+        tick(&mut state, PickupKind::Speed.duration_sec() - PickupKind::Shield.duration_sec());
+        assert_eq!(expired(&state), vec![PickupKind::Speed]);
+
+        state.events.clear();
+        tick(&mut state, 1.0);
+        assert!(expired(&state).is_empty());
+    }
+
+    /// a team deathmatch between a red shooter, a red team mate and a blue player, each with a single heart
+    fn teams(friendly_fire:bool) -> (State, Index, Index, Index) {
+        let mut state = state_with(&[]);
+        state.mode = GameMode::TeamDeathmatch;
+        state.friendly_fire = friendly_fire;
+        let mut on_team = |pos:Vec2, team:Team| {
+            let mut thing = player(pos);
+            if let Thing::Player(player) = &mut thing {
+                player.hearts = 1;
+                player.team = Some(team);
+            }
+            state.things.insert(thing)
+        };
+        let shooter = on_team(Vec2::new(10.0, 10.0), Team::Red);
+        let mate = on_team(Vec2::new(20.0, 10.0), Team::Red);
+        let enemy = on_team(Vec2::new(30.0, 10.0), Team::Blue);
+        (state, shooter, mate, enemy)
+    }
+
+    fn kills_and_deaths(state:&State, id:Index) -> (i32, i32) {
+        match state.things.get(id) {
+            Some(Thing::Player(player)) => (player.kills, player.deaths),
+            _ => (0, 0)
+        }
+    }
+
+    #[test]
+    fn team_mates_are_not_hurt_without_friendly_fire() {
+        let (mut state, shooter, mate, enemy) = teams(false);
+        hit_thing(&mut state, shooter, mate, 1);
+        assert_eq!(hearts(&state, mate), 1);
+        assert!(state.events.is_empty());
+
+        hit_thing(&mut state, shooter, enemy, 1);
+        assert_eq!(hearts(&state, enemy), 0);
+        assert_eq!(kills_and_deaths(&state, shooter), (1, 0));
+        assert_eq!(state.team_scores, [1, 0]);
+    }
+
+    #[test]
+    fn friendly_fire_hurts_team_mates_without_scoring() {
+        let (mut state, shooter, mate, _) = teams(true);
+        hit_thing(&mut state, shooter, mate, 1);
+        assert_eq!(hearts(&state, mate), 0);
+        assert_eq!(kills_and_deaths(&state, mate), (0, 1));
+        assert!(matches!(state.events.as_slice(), [Event::PlayerDied { thing_id, .. }] if *thing_id == mate));
+
+        // killing a team mate is neither a kill nor a point for the team
+        assert_eq!(kills_and_deaths(&state, shooter), (0, 0));
+        assert_eq!(state.team_scores, [0, 0]);
+    }
+
+    #[test]
+    fn own_projectiles_hurt_regardless_of_friendly_fire() {
+        let (mut state, shooter, _, _) = teams(false);
+        hit_thing(&mut state, shooter, shooter, 1);
+        assert_eq!(hearts(&state, shooter), 0);
+    }
 }
@@ -1,11 +1,12 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, sync::{Arc, OnceLock}};
 
 use generational_arena::{Arena, Index};
 use glam::Vec2;
 use hostess::client::Bincoded;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
-use crate::Thing;
+use crate::{Ability, CaptureZone, GameMode, MapCollider, PickupKind, SimRng, Team, TeamSpawn, Thing, Things};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Event {
@@ -18,6 +19,37 @@ pub enum Event {
     },
     ProjectileFired {
         pos:Vec2
+    },
+    /// a projectile bounced off a wall with the given normal
+    ProjectileBounced {
+        pos:Vec2,
+        normal:Vec2
+    },
+    PickupCollected {
+        thing_id:Index,
+        kind:PickupKind,
+        pos:Vec2
+    },
+    /// the effect of a pickup collected by the thing has run out
+    PickupExpired {
+        thing_id:Index,
+        kind:PickupKind
+    },
+    FlagTaken {
+        team:Team,
+        thing_id:Index
+    },
+    FlagDropped {
+        team:Team,
+        pos:Vec2
+    },
+    FlagReturned {
+        team:Team
+    },
+    /// the flag of the other team was captured by the thing of the team
+    FlagCaptured {
+        team:Team,
+        thing_id:Index
     }
 }
 
@@ -26,31 +58,125 @@ pub struct Polyline {
     pub points:Vec<Vec2>
 }
 
+/// where a pickup is placed on the map
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PickupSpawn {
+    pub pos:Vec2,
+    pub kind:PickupKind
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Map {
     pub polylines:Arena<Polyline>,
-    pub spawn_points:Vec<Vec2>
+    pub spawn_points:Vec<Vec2>,
+    pub pickups:Vec<PickupSpawn>,
+    /// spawn points used by the players of a team instead of the spawn points of everyone
+    pub team_spawn_points:Vec<TeamSpawn>,
+    /// the flag stands of the teams, used when capturing the flag
+    pub flags:Vec<TeamSpawn>,
+    pub capture_zones:Vec<CaptureZone>,
+    /// colliders of the polylines, built on first use and shared between clones
+    #[serde(skip)]
+    colliders:OnceLock<Arc<Vec<MapCollider>>>
 }
 
 impl Map {
     pub fn new() -> Self {
         Self {
             polylines:Arena::new(),
-            spawn_points:Vec::new()
+            spawn_points:Vec::new(),
+            pickups:Vec::new(),
+            team_spawn_points:Vec::new(),
+            flags:Vec::new(),
+            capture_zones:Vec::new(),
+            colliders:OnceLock::new()
+        }
+    }
+
+    /// true if the polylines, spawn points, pickups and team positions are the same as those of the other map
+    pub fn same_layout(&self, other:&Map) -> bool {
+        if self.spawn_points != other.spawn_points || self.pickups != other.pickups || self.polylines.len() != other.polylines.len() {
+            return false;
+        }
+
+        if self.team_spawn_points != other.team_spawn_points || self.flags != other.flags || self.capture_zones != other.capture_zones {
+            return false;
         }
+
+        self.polylines.iter().zip(other.polylines.iter()).all(|((_, a), (_, b))| a.points == b.points)
+    }
+
+    /// true if every team has a flag stand and a capture zone
+    pub fn supports_ctf(&self) -> bool {
+        Team::ALL.iter().all(|team| {
+            self.flags.iter().any(|f| f.team == *team) && self.capture_zones.iter().any(|z| z.team == *team)
+        })
+    }
+
+    /// the spawn points of the team, or the spawn points of everyone if the team has none
+    pub fn spawn_points_of(&self, team:Option<Team>) -> Vec<Vec2> {
+        let points:Vec<Vec2> = self.team_spawn_points.iter().filter(|s| Some(s.team) == team).map(|s| s.pos).collect();
+        if points.len() > 0 {
+            return points;
+        }
+
+        self.spawn_points.clone()
+    }
+
+    /// returns the colliders of the polylines
+    /// the polylines should not be changed after this has been called
+    pub fn colliders(&self) -> &Arc<Vec<MapCollider>> {
+        self.colliders.get_or_init(|| {
+            Arc::new(self.polylines.iter().map(|(_, p)| MapCollider::new(p)).collect())
+        })
     }
 }
 
+/// the score of a player which left during the round, kept for the scoreboard until the next round starts
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DepartedScore {
+    pub name:String,
+    pub team:Option<Team>,
+    pub kills:i32,
+    pub deaths:i32
+}
+
+/// the phase of the match being played
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MatchPhase {
+    /// awaiting enough players to join, bots are playing in the meantime
+    Warmup,
+    /// a round is being played until the timer runs out or the score limit is reached
+    Round,
+    /// the round has ended and the final score is shown until the next map is loaded
+    Intermission
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct State {
     pub timestamp:f64,
     pub next_spawn:i16,
-    pub things: Arena<Thing>,
+    pub things: Things,
     pub events:Vec<Event>,
     pub map:Map,
     pub width: f32,
     pub height: f32,
-    pub warmup:bool
+    pub phase:MatchPhase,
+    /// seconds left of the current phase, not used during warmup
+    pub phase_timer:f32,
+    /// kills needed to win the round, or captures when capturing the flag
+    pub score_limit:i32,
+    pub mode:GameMode,
+    /// true if players can damage players of their own team
+    pub friendly_fire:bool,
+    /// the score of each team, indexed by `Team::index`
+    pub team_scores:[i32; 2],
+    /// the scores of players which left during the round
+    pub departed:Vec<DepartedScore>,
+    /// the random number generator used by the simulation
+    /// never sent to clients, which could otherwise predict spawn points and other random outcomes
+    #[serde(skip)]
+    pub rng:SimRng
 }
 
 impl Bincoded for State {
@@ -58,9 +184,13 @@ impl Bincoded for State {
 
 impl State {
 
-    pub fn next_spawn_pos(next_spawn:&mut i16, spawn_points:&Vec<Vec2>) -> Vec2 {
+    pub fn next_spawn_pos(next_spawn:&mut i16, spawn_points:&Vec<Vec2>, rng:&mut SimRng) -> Vec2 {
+        if spawn_points.len() == 0 {
+            return Vec2::new(0.0, 0.0);
+        }
+
         if *next_spawn == -1 {
-            *next_spawn = rand::random::<i16>() % spawn_points.len() as i16;
+            *next_spawn = rng.gen_range(0..spawn_points.len()) as i16;
         }
         let i = *next_spawn as usize % spawn_points.len();
         *next_spawn += 1;
@@ -109,19 +239,84 @@ impl State {
         map.spawn_points.push([2.0, 15.0].into());
         map.spawn_points.push([38.0, 15.0].into());
 
+        map.pickups.push(PickupSpawn { pos:[17.0, 10.0].into(), kind:PickupKind::Heart });
+        map.pickups.push(PickupSpawn { pos:[10.0, 18.0].into(), kind:PickupKind::Speed });
+        map.pickups.push(PickupSpawn { pos:[25.0, 24.0].into(), kind:PickupKind::Shield });
+        map.pickups.push(PickupSpawn { pos:[34.0, 8.0].into(), kind:PickupKind::Weapon(Ability::Shotgun) });
+        map.pickups.push(PickupSpawn { pos:[30.0, 27.0].into(), kind:PickupKind::Weapon(Ability::Bouncer) });
+        map.pickups.push(PickupSpawn { pos:[14.0, 3.0].into(), kind:PickupKind::Weapon(Ability::Rail) });
+
+        for pos in [[2.0, 3.0], [2.0, 15.0], [2.0, 28.0]] {
+            map.team_spawn_points.push(TeamSpawn { team:Team::Red, pos:pos.into() });
+        }
+        for pos in [[39.0, 3.0], [38.0, 15.0], [38.0, 28.0]] {
+            map.team_spawn_points.push(TeamSpawn { team:Team::Blue, pos:pos.into() });
+        }
+
+        map.flags.push(TeamSpawn { team:Team::Red, pos:[4.0, 15.0].into() });
+        map.flags.push(TeamSpawn { team:Team::Blue, pos:[36.0, 15.0].into() });
+        map.capture_zones.push(CaptureZone { team:Team::Red, pos:[4.0, 15.0].into(), radius:2.0 });
+        map.capture_zones.push(CaptureZone { team:Team::Blue, pos:[36.0, 15.0].into(), radius:2.0 });
+
+        Self::with_map(map, 40.0, 30.0)
+    }
+
+    /// creates a new state using the given map, without things except for the pickups of the map
+    pub fn with_map(map:Map, width:f32, height:f32) -> Self {
+        let mut things = Things::new();
+        for pickup in &map.pickups {
+            things.insert(Thing::new_pickup(pickup.pos, pickup.kind));
+        }
+
         Self {
-            warmup:true,
+            phase:MatchPhase::Warmup,
+            phase_timer:0.0,
+            score_limit:0,
+            mode:GameMode::Deathmatch,
+            friendly_fire:false,
+            team_scores:[0, 0],
+            departed:Vec::new(),
+            rng:SimRng::default(),
             next_spawn:-1,
             timestamp:0.0,
-            things: Arena::new(),
-            width: 40.0,
-            height: 30.0,
+            things,
+            width,
+            height,
             events:Vec::new(),
             map
         }
     }
 }
 
+impl State {
+    /// sets the game mode, spawning flags when capturing the flag and removing them otherwise
+    /// falls back to team deathmatch if the map does not support capturing the flag
+    /// returns the game mode set
+    pub fn set_mode(&mut self, mode:GameMode) -> GameMode {
+        let mode = match mode {
+            GameMode::CaptureTheFlag if !self.map.supports_ctf() => GameMode::TeamDeathmatch,
+            mode => mode
+        };
+
+        let flags:Vec<Index> = self.things.iter().filter(|(_, t)| matches!(t, Thing::Flag(_))).map(|(id, _)| id).collect();
+        for id in flags {
+            self.things.remove(id);
+        }
+
+        if mode == GameMode::CaptureTheFlag {
+            for team in Team::ALL {
+                if let Some(flag) = self.map.flags.iter().find(|f| f.team == team) {
+                    self.things.insert(Thing::new_flag(flag.pos, team));
+                }
+            }
+        }
+
+        self.mode = mode;
+        self.team_scores = [0, 0];
+        mode
+    }
+}
+
 pub struct StateHistory {
     history:VecDeque<State>,
     default_state:State
@@ -154,6 +349,28 @@ impl StateHistory {
         &self.default_state
     }
 
+    /// returns the two remembered states surrounding the given timestamp
+    /// and the alpha between them, clamped to the oldest remembered state
+    /// returns None if the timestamp is not in the past
+    pub fn find(&self, timestamp:f64) -> Option<(&State, &State, f32)> {
+        let oldest = self.history.front()?;
+        if timestamp <= oldest.timestamp {
+            return Some((oldest, oldest, 0.0));
+        }
+
+        for i in 1..self.history.len() {
+            let prev = &self.history[i - 1];
+            let next = &self.history[i];
+            if prev.timestamp <= timestamp && timestamp <= next.timestamp {
+                let span = next.timestamp - prev.timestamp;
+                let alpha = if span > 0.0 { (timestamp - prev.timestamp) / span } else { 1.0 };
+                return Some((prev, next, alpha as f32));
+            }
+        }
+
+        None
+    }
+
     pub fn prev(&self) -> &State {
         if let Some(s) = self.history.get(self.len() - 2) {
             return s;
@@ -165,4 +382,46 @@ impl StateHistory {
     pub fn clear(&mut self) {
         self.history.clear();
     }
-}
\ No newline at end of file
+}
+/// buffer of the most recent snapshots by their sequence number
+/// used to find the baseline a delta snapshot is based on
+pub struct SnapshotBuffer {
+    snapshots:VecDeque<(u32, State)>,
+    capacity:usize
+}
+
+impl SnapshotBuffer {
+    pub fn new(capacity:usize) -> Self {
+        Self {
+            snapshots:VecDeque::with_capacity(capacity),
+            capacity
+        }
+    }
+
+    pub fn push(&mut self, seq:u32, state:State) {
+        if self.snapshots.len() >= self.capacity {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back((seq, state));
+    }
+
+    pub fn get(&self, seq:u32) -> Option<&State> {
+        self.snapshots.iter().find(|(s, _)| *s == seq).map(|(_, state)| state)
+    }
+
+    /// returns the snapshot to base a delta on for a client which acknowledged the given snapshot
+    /// None if nothing was acknowledged or the snapshot is no longer remembered, then the snapshot is sent in full
+    pub fn baseline(&self, acked:Option<u32>) -> Option<(u32, &State)> {
+        acked.and_then(|acked| self.get(acked).map(|state| (acked, state)))
+    }
+
+    /// returns the sequence number of the newest snapshot
+    pub fn latest_seq(&self) -> Option<u32> {
+        self.snapshots.back().map(|(seq, _)| *seq)
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+}
@@ -0,0 +1,152 @@
+use std::{collections::HashMap, fmt::Display, path::{Path, PathBuf}, sync::Mutex};
+
+use hostess::uuid::Uuid;
+use serde::{Deserialize, Serialize};
+
+/// the lifetime stats of a player
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PlayerStats {
+    /// the name the player last played with
+    pub name:String,
+    pub kills:u64,
+    pub deaths:u64,
+    pub matches_played:u64,
+    pub playtime_sec:f64
+}
+
+impl PlayerStats {
+    /// adds the stats to these, taking the name of the other
+    pub fn add(&mut self, other:&PlayerStats) {
+        self.name = other.name.clone();
+        self.kills += other.kills;
+        self.deaths += other.deaths;
+        self.matches_played += other.matches_played;
+        self.playtime_sec += other.playtime_sec;
+    }
+}
+
+#[derive(Debug)]
+pub enum StatsError {
+    Io(std::io::Error),
+    Json(serde_json::Error)
+}
+
+impl Display for StatsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatsError::Io(err) => write!(f, "{}", err),
+            StatsError::Json(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl std::error::Error for StatsError {
+}
+
+impl From<std::io::Error> for StatsError {
+    fn from(err:std::io::Error) -> Self {
+        StatsError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for StatsError {
+    fn from(err:serde_json::Error) -> Self {
+        StatsError::Json(err)
+    }
+}
+
+/// where the lifetime stats of players are kept, by the identity of the player
+/// shared by every instance of the server
+pub trait StatsStore: Send + Sync {
+    fn get(&self, id:Uuid) -> Option<PlayerStats>;
+
+    /// adds the stats to the lifetime stats of each player, all at once
+    /// such that the stats of a whole round are recorded together
+    fn record(&self, stats:&[(Uuid, PlayerStats)]) -> Result<(), StatsError>;
+}
+
+/// keeps the stats in a json file, which is rewritten once for each batch of stats recorded
+pub struct FileStatsStore {
+    path:PathBuf,
+    stats:Mutex<HashMap<String, PlayerStats>>
+}
+
+impl FileStatsStore {
+    /// opens the stats kept in the file, starting without stats if the file does not exist
+    pub fn open(path:&Path) -> Result<Self, StatsError> {
+        let stats = match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into())
+        };
+
+        Ok(Self {
+            path:path.into(),
+            stats:Mutex::new(stats)
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats.lock().map(|stats| stats.len()).unwrap_or(0)
+    }
+}
+
+impl StatsStore for FileStatsStore {
+    fn get(&self, id:Uuid) -> Option<PlayerStats> {
+        self.stats.lock().ok()?.get(&id.to_string()).cloned()
+    }
+
+    fn record(&self, stats:&[(Uuid, PlayerStats)]) -> Result<(), StatsError> {
+        if stats.is_empty() {
+            return Ok(());
+        }
+
+        let mut all = match self.stats.lock() {
+            Ok(all) => all,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        for (id, stats) in stats {
+            all.entry(id.to_string()).or_default().add(stats);
+        }
+
+        // written to a temporary file first, such that a crash never leaves a partially written file
+        let json = serde_json::to_string_pretty(&*all)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(name:&str, kills:u64) -> PlayerStats {
+        PlayerStats {
+            name:name.into(),
+            kills,
+            deaths:1,
+            matches_played:1,
+            playtime_sec:60.0
+        }
+    }
+
+    #[test]
+    fn batch_is_recorded_and_read_back() {
+        let path = std::env::temp_dir().join(format!("stats-test-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+
+        let store = FileStatsStore::open(&path).unwrap();
+        store.record(&[(a, stats("a", 3)), (b, stats("b", 5))]).unwrap();
+        store.record(&[(a, stats("renamed", 2))]).unwrap();
+
+        let store = FileStatsStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        let a = store.get(a).unwrap();
+        assert_eq!((a.name.as_str(), a.kills, a.deaths, a.matches_played), ("renamed", 5, 2, 2));
+        assert_eq!(store.get(b).unwrap(), stats("b", 5));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,381 @@
+use std::str::FromStr;
+
+use generational_arena::Index;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::{Broadphase, Event, State, Thing};
+
+/// seconds a dropped flag stays where it was dropped before returning home
+const FLAG_RETURN_SEC:f32 = 15.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Team {
+    Red,
+    Blue
+}
+
+impl Team {
+    pub const ALL:[Team; 2] = [Team::Red, Team::Blue];
+
+    /// the index of the team, e.g. into `State::team_scores`
+    pub fn index(&self) -> usize {
+        match self {
+            Team::Red => 0,
+            Team::Blue => 1
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Team::Red => "Red",
+            Team::Blue => "Blue"
+        }
+    }
+
+    pub fn color(&self) -> &'static str {
+        match self {
+            Team::Red => "rgb(200,40,40)",
+            Team::Blue => "rgb(40,80,220)"
+        }
+    }
+}
+
+/// the rules of the match being played
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum GameMode {
+    /// every player for themselves, the player with the most kills wins
+    Deathmatch,
+    /// two teams, the team with the most kills wins
+    TeamDeathmatch,
+    /// two teams, the team capturing the flag of the other team the most wins
+    CaptureTheFlag
+}
+
+impl Default for GameMode {
+    fn default() -> Self {
+        Self::Deathmatch
+    }
+}
+
+impl GameMode {
+    pub fn is_team_based(&self) -> bool {
+        *self != GameMode::Deathmatch
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameMode::Deathmatch => "Deathmatch",
+            GameMode::TeamDeathmatch => "Team Deathmatch",
+            GameMode::CaptureTheFlag => "Capture the Flag"
+        }
+    }
+}
+
+impl FromStr for GameMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dm" | "deathmatch" => Ok(GameMode::Deathmatch),
+            "tdm" | "teamdeathmatch" => Ok(GameMode::TeamDeathmatch),
+            "ctf" | "capturetheflag" => Ok(GameMode::CaptureTheFlag),
+            _ => Err(format!("unknown game mode {}, expected dm, tdm or ctf", s))
+        }
+    }
+}
+
+/// a position on the map belonging to a team, e.g. a spawn point or a flag stand
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct TeamSpawn {
+    pub team:Team,
+    pub pos:Vec2
+}
+
+/// where a team brings the flag of the other team to capture it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct CaptureZone {
+    pub team:Team,
+    pub pos:Vec2,
+    pub radius:f32
+}
+
+impl CaptureZone {
+    pub fn contains(&self, pos:Vec2) -> bool {
+        self.pos.distance(pos) < self.radius
+    }
+}
+
+/// returns the team of the thing, if it is a player on a team
+pub fn team_of(state:&State, thing_id:Index) -> Option<Team> {
+    match state.things.get(thing_id) {
+        Some(Thing::Player(player)) => player.team,
+        _ => None
+    }
+}
+
+/// the flags of every team back at their stands
+pub fn reset_flags(state:&mut State) {
+    for (_, thing) in state.things.iter_mut() {
+        if let Thing::Flag(flag) = thing {
+            flag.pos = flag.home;
+            flag.carrier = None;
+            flag.return_timer = 0.0;
+        }
+    }
+}
+
+/// handles flags being taken, carried, dropped, returned and captured
+pub fn update_flags(state:&mut State, broadphase:&Broadphase, dt:f32) {
+    let flags:Vec<Index> = state.things.iter().filter(|(_, t)| matches!(t, Thing::Flag(_))).map(|(id, _)| id).collect();
+    for flag_id in flags {
+        let (team, carrier, pos, home, radius) = match state.things.get(flag_id) {
+            Some(Thing::Flag(flag)) => (flag.team, flag.carrier, flag.pos, flag.home, flag.radius),
+            _ => continue
+        };
+
+        match carrier {
+            Some(carrier_id) => {
+                let carrier = match state.things.get(carrier_id) {
+                    Some(Thing::Player(player)) if player.is_alive() => Some((player.pos, player.team)),
+                    _ => None
+                };
+
+                match carrier {
+                    Some((carrier_pos, Some(carrier_team))) => {
+                        // the flag can only be captured while the flag of the capturing team is home
+                        let captured = state.map.capture_zones.iter().any(|z| z.team == carrier_team && z.contains(carrier_pos))
+                            && flag_home(state, carrier_team);
+                        if captured {
+                            state.team_scores[carrier_team.index()] += 1;
+                            set_flag(state, flag_id, home, None, 0.0);
+                            state.events.push(Event::FlagCaptured { team:carrier_team, thing_id:carrier_id });
+                        } else {
+                            set_flag(state, flag_id, carrier_pos, Some(carrier_id), 0.0);
+                        }
+                    }
+                    _ => {
+                        set_flag(state, flag_id, pos, None, FLAG_RETURN_SEC);
+                        state.events.push(Event::FlagDropped { team, pos });
+                    }
+                }
+            }
+            None => {
+                let mut touched = None;
+                for other in broadphase.query(pos, radius) {
+                    if other.pos.distance(pos) < other.radius + radius {
+                        if let Some(Thing::Player(player)) = state.things.get(other.id) {
+                            if player.is_alive() && player.team.is_some() {
+                                touched = Some((other.id, player.team == Some(team)));
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                match touched {
+                    Some((player_id, false)) => {
+                        set_flag(state, flag_id, pos, Some(player_id), 0.0);
+                        state.events.push(Event::FlagTaken { team, thing_id:player_id });
+                    }
+                    Some((_, true)) if pos != home => {
+                        set_flag(state, flag_id, home, None, 0.0);
+                        state.events.push(Event::FlagReturned { team });
+                    }
+                    _ => {
+                        if pos != home {
+                            let timer = match state.things.get(flag_id) {
+                                Some(Thing::Flag(flag)) => flag.return_timer - dt,
+                                _ => 0.0
+                            };
+
+                            if timer <= 0.0 {
+                                set_flag(state, flag_id, home, None, 0.0);
+                                state.events.push(Event::FlagReturned { team });
+                            } else {
+                                set_flag(state, flag_id, pos, None, timer);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// true if the flag of the team is at its stand
+fn flag_home(state:&State, team:Team) -> bool {
+    state.things.iter().any(|(_, thing)| match thing {
+        Thing::Flag(flag) => flag.team == team && flag.carrier == None && flag.pos == flag.home,
+        _ => false
+    })
+}
+
+fn set_flag(state:&mut State, flag_id:Index, pos:Vec2, carrier:Option<Index>, return_timer:f32) {
+    if let Some(Thing::Flag(flag)) = state.things.get_mut(flag_id) {
+        flag.pos = pos;
+        flag.carrier = carrier;
+        flag.return_timer = return_timer;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Map, PlayerThing, Solid, MAX_HEARTS};
+
+    const RED_HOME:Vec2 = Vec2::new(10.0, 50.0);
+    const BLUE_HOME:Vec2 = Vec2::new(90.0, 50.0);
+
+    /// a capture the flag match with the flag and the capture zone of each team at its base
+    fn ctf() -> (State, Index, Index) {
+        let mut map = Map::new();
+        map.capture_zones.push(CaptureZone { team:Team::Red, pos:RED_HOME, radius:2.0 });
+        map.capture_zones.push(CaptureZone { team:Team::Blue, pos:BLUE_HOME, radius:2.0 });
+        let mut state = State::with_map(map, 100.0, 100.0);
+        state.mode = GameMode::CaptureTheFlag;
+        let red_flag = state.things.insert(Thing::new_flag(RED_HOME, Team::Red));
+        let blue_flag = state.things.insert(Thing::new_flag(BLUE_HOME, Team::Blue));
+        (state, red_flag, blue_flag)
+    }
+
+    fn player(state:&mut State, pos:Vec2, team:Team) -> Index {
+        state.things.insert(Thing::Player(PlayerThing {
+            pos,
+            radius:0.5,
+            solid:Solid::Solid,
+            hearts:MAX_HEARTS,
+            team:Some(team),
+            ..Default::default()
+        }))
+    }
+
+    fn update(state:&mut State, dt:f32) {
+        let broadphase = Broadphase::new(state);
+        update_flags(state, &broadphase, dt);
+    }
+
+    fn move_to(state:&mut State, id:Index, pos:Vec2) {
+        *state.things[id].pos_mut() = pos;
+    }
+
+    fn flag(state:&State, id:Index) -> (Vec2, Option<Index>) {
+        match &state.things[id] {
+            Thing::Flag(flag) => (flag.pos, flag.carrier),
+            _ => panic!("not a flag")
+        }
+    }
+
+    #[test]
+    fn flag_is_taken_carried_and_captured() {
+        let (mut state, _, blue_flag) = ctf();
+        let red = player(&mut state, BLUE_HOME, Team::Red);
+
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, blue_flag), (BLUE_HOME, Some(red)));
+        assert!(matches!(state.events.as_slice(), [Event::FlagTaken { team:Team::Blue, thing_id }] if *thing_id == red));
+
+        let halfway = Vec2::new(50.0, 50.0);
+        move_to(&mut state, red, halfway);
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, blue_flag), (halfway, Some(red)));
+
+        state.events.clear();
+        move_to(&mut state, red, RED_HOME);
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, blue_flag), (BLUE_HOME, None));
+        assert_eq!(state.team_scores, [1, 0]);
+        assert!(matches!(state.events.as_slice(), [Event::FlagCaptured { team:Team::Red, thing_id }] if *thing_id == red));
+    }
+
+    #[test]
+    fn flag_is_not_taken_by_its_own_team() {
+        let (mut state, red_flag, _) = ctf();
+        player(&mut state, RED_HOME, Team::Red);
+
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, red_flag), (RED_HOME, None));
+        assert!(state.events.is_empty());
+    }
+
+    #[test]
+    fn flag_is_not_captured_while_the_own_flag_is_away() {
+        let (mut state, red_flag, blue_flag) = ctf();
+        let red = player(&mut state, BLUE_HOME, Team::Red);
+        let blue = player(&mut state, RED_HOME, Team::Blue);
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, red_flag).1, Some(blue));
+        assert_eq!(flag(&state, blue_flag).1, Some(red));
+
+        // both carriers are in their capture zone, but neither flag is home
+        move_to(&mut state, red, RED_HOME + Vec2::new(0.0, 1.0));
+        move_to(&mut state, blue, BLUE_HOME + Vec2::new(0.0, 1.0));
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, red_flag).1, Some(blue));
+        assert_eq!(flag(&state, blue_flag).1, Some(red));
+        assert_eq!(state.team_scores, [0, 0]);
+    }
+
+    #[test]
+    fn dropped_flag_returns_home_after_a_while() {
+        let (mut state, _, blue_flag) = ctf();
+        let red = player(&mut state, BLUE_HOME, Team::Red);
+        update(&mut state, 0.05);
+
+        let dropped_at = Vec2::new(50.0, 50.0);
+        move_to(&mut state, red, dropped_at);
+        if let Thing::Player(player) = &mut state.things[red] {
+            player.hearts = 0;
+            player.solid = Solid::None;
+        }
+        state.events.clear();
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, blue_flag), (dropped_at, None));
+        assert!(matches!(state.events.as_slice(), [Event::FlagDropped { team:Team::Blue, pos }] if *pos == dropped_at));
+
+        state.events.clear();
+        update(&mut state, FLAG_RETURN_SEC - 1.0);
+        assert_eq!(flag(&state, blue_flag), (dropped_at, None));
+        assert!(state.events.is_empty());
+
+        update(&mut state, 1.0);
+        assert_eq!(flag(&state, blue_flag), (BLUE_HOME, None));
+        assert!(matches!(state.events.as_slice(), [Event::FlagReturned { team:Team::Blue }]));
+    }
+
+    #[test]
+    fn dropped_flag_is_returned_by_its_team_and_taken_by_the_other() {
+        let (mut state, _, blue_flag) = ctf();
+        let red = player(&mut state, BLUE_HOME, Team::Red);
+        update(&mut state, 0.05);
+
+        let dropped_at = Vec2::new(50.0, 50.0);
+        move_to(&mut state, red, dropped_at);
+        if let Thing::Player(player) = &mut state.things[red] {
+            player.hearts = 0;
+            player.solid = Solid::None;
+        }
+        update(&mut state, 0.05);
+
+        // another red player picks it up where it was dropped
+        let other_red = player(&mut state, dropped_at, Team::Red);
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, blue_flag), (dropped_at, Some(other_red)));
+
+        let killed_at = Vec2::new(60.0, 50.0);
+        move_to(&mut state, other_red, killed_at);
+        if let Thing::Player(player) = &mut state.things[other_red] {
+            player.hearts = 0;
+            player.solid = Solid::None;
+        }
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, blue_flag), (killed_at, None));
+
+        // a blue player touching it returns it right away
+        state.events.clear();
+        player(&mut state, killed_at, Team::Blue);
+        update(&mut state, 0.05);
+        assert_eq!(flag(&state, blue_flag), (BLUE_HOME, None));
+        assert!(matches!(state.events.as_slice(), [Event::FlagReturned { team:Team::Blue }]));
+    }
+}
@@ -2,6 +2,8 @@ use generational_arena::Index;
 use glam::Vec2;
 use serde::{Deserialize, Serialize};
 
+use crate::{Ability, Team};
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum Solid {
     /// not solid, does not influence its own movement nor others
@@ -21,16 +23,92 @@ impl Default for Solid {
 }
 
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ProjectileThing {
     pub pos:Vec2,
     pub radius:f32,
     pub solid:Solid,
     pub vel:Vec2,
     pub owner:Index,
+    /// hearts taken from the player hit
+    pub damage:i8,
+    /// times left the projectile bounces off walls
+    pub bounces:u8,
+    /// seconds left before the projectile is removed
+    pub lifetime:f32
+}
+
+/// how long a projectile flies unless given otherwise
+pub const PROJECTILE_LIFETIME_SEC:f32 = 3.0;
+
+/// the hearts of a player when spawned
+pub const MAX_HEARTS:i8 = 3;
+
+/// what a pickup gives the player collecting it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PickupKind {
+    /// restores a single heart
+    Heart,
+    /// increases the speed of the player for a while
+    Speed,
+    /// protects the player from damage for a while
+    Shield,
+    /// gives ammo for the ability
+    Weapon(Ability)
+}
+
+impl PickupKind {
+    /// the time before the pickup can be collected again
+    pub fn respawn_sec(&self) -> f32 {
+        match self {
+            PickupKind::Heart => 15.0,
+            PickupKind::Speed => 20.0,
+            PickupKind::Shield => 30.0,
+            PickupKind::Weapon(_) => 20.0
+        }
+    }
+
+    /// the time the effect of the pickup lasts, zero for instant effects
+    pub fn duration_sec(&self) -> f32 {
+        match self {
+            PickupKind::Speed => 8.0,
+            PickupKind::Shield => 5.0,
+            _ => 0.0
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PickupThing {
+    pub pos:Vec2,
+    pub radius:f32,
+    pub solid:Solid,
+    pub kind:PickupKind,
+    /// seconds until the pickup can be collected, zero if it can be collected now
+    pub respawn_timer:f32
+}
+
+impl PickupThing {
+    pub fn is_active(&self) -> bool {
+        self.respawn_timer <= 0.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FlagThing {
+    pub pos:Vec2,
+    pub radius:f32,
+    pub solid:Solid,
+    pub team:Team,
+    /// the stand of the flag, where it returns to
+    pub home:Vec2,
+    /// the player carrying the flag
+    pub carrier:Option<Index>,
+    /// seconds until a dropped flag returns home
+    pub return_timer:f32
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct PlayerThing {
     pub pos:Vec2,
     pub radius:f32,
@@ -38,12 +116,21 @@ pub struct PlayerThing {
     pub hearts:i8,
     pub respawn_timer:f32,
     pub ability_cooldown:f32,
+    pub ability:Ability,
+    /// ammo left for each ability, indexed by `Ability::index`
+    pub ammo:Vec<u8>,
+    /// seconds the ability has been charged while the trigger is held
+    pub charge:Option<f32>,
     pub speed:f32,
+    /// seconds left of increased speed
+    pub speed_boost:f32,
+    /// seconds left of protection from damage
+    pub shield:f32,
     pub deaths:i32,
     pub kills:i32,
-    pub no_interpolation:bool,
     pub name:String,
-    pub spawn_pos:Option<Vec2>
+    pub spawn_pos:Option<Vec2>,
+    pub team:Option<Team>
 }
 
 impl PlayerThing {
@@ -52,10 +139,12 @@ impl PlayerThing {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Thing {
     Player(PlayerThing),
-    Projectile(ProjectileThing)
+    Projectile(ProjectileThing),
+    Pickup(PickupThing),
+    Flag(FlagThing)
 }
 
 
@@ -77,8 +166,12 @@ impl Thing {
             player.pos = player.spawn_pos.unwrap_or_default();
             player.spawn_pos = None;
             player.solid = Solid::Solid;
-            player.hearts = 3;
+            player.hearts = MAX_HEARTS;
             player.respawn_timer = 0.0;
+            player.charge = None;
+            player.speed_boost = 0.0;
+            player.shield = 0.0;
+            player.refill_ammo();
         }
     }
 
@@ -88,7 +181,32 @@ impl Thing {
             radius:0.25,
             solid:Solid::Partial,
             owner,
-            vel
+            vel,
+            damage:1,
+            bounces:0,
+            lifetime:PROJECTILE_LIFETIME_SEC
+        })
+    }
+
+    pub fn new_pickup(pos:Vec2, kind:PickupKind) -> Self {
+        Self::Pickup(PickupThing {
+            pos,
+            radius:0.5,
+            solid:Solid::None,
+            kind,
+            respawn_timer:0.0
+        })
+    }
+
+    pub fn new_flag(home:Vec2, team:Team) -> Self {
+        Self::Flag(FlagThing {
+            pos:home,
+            radius:0.5,
+            solid:Solid::None,
+            team,
+            home,
+            carrier:None,
+            return_timer:0.0
         })
     }
 
@@ -96,6 +214,8 @@ impl Thing {
         match self {
             Thing::Player(t) => &t.pos,
             Thing::Projectile(t) => &t.pos,
+            Thing::Pickup(t) => &t.pos,
+            Thing::Flag(t) => &t.pos,
         }
     }
 
@@ -103,6 +223,8 @@ impl Thing {
         match self {
             Thing::Player(t) => t.name.as_str(),
             Thing::Projectile(_) => "",
+            Thing::Pickup(_) => "",
+            Thing::Flag(_) => "",
         }
     }
 
@@ -110,6 +232,8 @@ impl Thing {
         match self {
             Thing::Player(t) => &t.radius,
             Thing::Projectile(t) => &t.radius,
+            Thing::Pickup(t) => &t.radius,
+            Thing::Flag(t) => &t.radius,
         }
     }
 
@@ -117,6 +241,8 @@ impl Thing {
         match self {
             Thing::Player(t) => &t.solid,
             Thing::Projectile(t) => &t.solid,
+            Thing::Pickup(t) => &t.solid,
+            Thing::Flag(t) => &t.solid,
         }
     }
 
@@ -124,6 +250,8 @@ impl Thing {
         match self {
             Thing::Player(t) => &mut t.solid,
             Thing::Projectile(t) => &mut t.solid,
+            Thing::Pickup(t) => &mut t.solid,
+            Thing::Flag(t) => &mut t.solid,
         }
     }
 
@@ -131,6 +259,8 @@ impl Thing {
         match self {
             Thing::Player(t) => &mut t.pos,
             Thing::Projectile(t) => &mut t.pos,
+            Thing::Pickup(t) => &mut t.pos,
+            Thing::Flag(t) => &mut t.pos,
         }
     }
 
@@ -144,11 +274,4 @@ impl Thing {
 
         return *self.pos();
     }
-
-    pub fn no_interpolate(&self) -> bool {
-        match self {
-            Thing::Player(p) => p.no_interpolation,
-            Thing::Projectile(_) => false,
-        }
-    }
 }
\ No newline at end of file
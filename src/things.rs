@@ -0,0 +1,228 @@
+use generational_arena::Index;
+use serde::{Deserialize, Serialize};
+
+use crate::Thing;
+
+/// the most things a state can hold, such that a peer cannot make a state allocate without bound
+pub const MAX_THINGS:usize = 1 << 16;
+
+/// the things of a state, each at a generational index which is never given to another thing
+/// works like an arena, except that things can also be placed at a given index,
+/// as needed when rebuilding the things received from the server
+/// the slots never end with an empty one, such that the same things are always serialized the same
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Things {
+    /// the things at the position of their index, with the generation of their index
+    slots:Vec<Option<(u64, Thing)>>,
+    /// the generation of things inserted from now on, newer than that of any thing removed
+    #[serde(skip)]
+    generation:u64,
+    /// the positions of the empty slots, reused when inserting
+    #[serde(skip)]
+    free:Vec<usize>
+}
+
+impl Things {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// inserts the thing at a free index, returning the index
+    pub fn insert(&mut self, thing:Thing) -> Index {
+        // the free slots are not sent along with the things
+        while let Some(index) = self.free.pop() {
+            if index < self.slots.len() && self.slots[index].is_none() {
+                self.slots[index] = Some((self.generation, thing));
+                return Index::from_raw_parts(index, self.generation);
+            }
+        }
+
+        self.slots.push(Some((self.generation, thing)));
+        Index::from_raw_parts(self.slots.len() - 1, self.generation)
+    }
+
+    /// places the thing at the index, replacing what was there
+    /// returns false if the index is beyond `MAX_THINGS`
+    pub fn place(&mut self, id:Index, thing:Thing) -> bool {
+        let (index, generation) = id.into_raw_parts();
+        if index >= MAX_THINGS {
+            return false;
+        }
+
+        if index >= self.slots.len() {
+            self.free.extend(self.slots.len()..index);
+            self.slots.resize_with(index + 1, || None);
+        }
+
+        self.slots[index] = Some((generation, thing));
+        self.generation = self.generation.max(generation.saturating_add(1));
+        true
+    }
+
+    /// removes the thing, returning it if it was found
+    pub fn remove(&mut self, id:Index) -> Option<Thing> {
+        let (index, generation) = id.into_raw_parts();
+        match self.slots.get(index) {
+            Some(Some((g, _))) if *g == generation => {}
+            _ => return None
+        }
+
+        self.generation = self.generation.max(generation) + 1;
+        self.free.push(index);
+        let thing = self.slots[index].take().map(|(_, thing)| thing);
+        while let Some(None) = self.slots.last() {
+            self.slots.pop();
+        }
+
+        thing
+    }
+
+    pub fn contains(&self, id:Index) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn get(&self, id:Index) -> Option<&Thing> {
+        let (index, generation) = id.into_raw_parts();
+        match self.slots.get(index) {
+            Some(Some((g, thing))) if *g == generation => Some(thing),
+            _ => None
+        }
+    }
+
+    pub fn get_mut(&mut self, id:Index) -> Option<&mut Thing> {
+        let (index, generation) = id.into_raw_parts();
+        match self.slots.get_mut(index) {
+            Some(Some((g, thing))) if *g == generation => Some(thing),
+            _ => None
+        }
+    }
+
+    /// returns two different things at once, a thing given twice is only returned once
+    pub fn get2_mut(&mut self, a:Index, b:Index) -> (Option<&mut Thing>, Option<&mut Thing>) {
+        let (i, j) = (a.into_raw_parts().0, b.into_raw_parts().0);
+        if i == j {
+            return (self.get_mut(a), None);
+        }
+
+        if i.max(j) >= self.slots.len() {
+            return match i < j {
+                true => (self.get_mut(a), None),
+                false => (None, self.get_mut(b))
+            };
+        }
+
+        let (low, high) = self.slots.split_at_mut(i.max(j));
+        let (slot_a, slot_b) = match i < j {
+            true => (&mut low[i], &mut high[0]),
+            false => (&mut high[0], &mut low[j])
+        };
+        (matching(slot_a, a), matching(slot_b, b))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Index, &Thing)> + '_ {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.as_ref().map(|(generation, thing)| (Index::from_raw_parts(index, *generation), thing))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Index, &mut Thing)> + '_ {
+        self.slots.iter_mut().enumerate().filter_map(|(index, slot)| {
+            slot.as_mut().map(|(generation, thing)| (Index::from_raw_parts(index, *generation), thing))
+        })
+    }
+}
+
+/// the thing in the slot, if it has the generation of the index
+fn matching(slot:&mut Option<(u64, Thing)>, id:Index) -> Option<&mut Thing> {
+    match slot {
+        Some((generation, thing)) if *generation == id.into_raw_parts().1 => Some(thing),
+        _ => None
+    }
+}
+
+impl std::ops::Index<Index> for Things {
+    type Output = Thing;
+
+    fn index(&self, id:Index) -> &Thing {
+        self.get(id).expect("no thing at the index")
+    }
+}
+
+impl std::ops::IndexMut<Index> for Things {
+    fn index_mut(&mut self, id:Index) -> &mut Thing {
+        self.get_mut(id).expect("no thing at the index")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+
+    use super::*;
+
+    fn thing(x:f32) -> Thing {
+        Thing::new_projectile(Vec2::new(x, 0.0), Vec2::ZERO, Index::from_raw_parts(0, 0))
+    }
+
+    #[test]
+    fn removed_index_is_not_given_to_another_thing() {
+        let mut things = Things::new();
+        let a = things.insert(thing(1.0));
+        let b = things.insert(thing(2.0));
+        assert_eq!(things.remove(a).map(|t| t.pos().x), Some(1.0));
+        assert!(things.remove(a).is_none());
+
+        let c = things.insert(thing(3.0));
+        assert_eq!(c.into_raw_parts().0, a.into_raw_parts().0);
+        assert_ne!(c, a);
+        assert!(things.get(a).is_none());
+        assert_eq!(things[c].pos().x, 3.0);
+        assert_eq!(things.len(), 2);
+
+        match things.get2_mut(b, c) {
+            (Some(b), Some(c)) => std::mem::swap(b, c),
+            _ => panic!("both things should be found")
+        }
+        assert_eq!(things[b].pos().x, 3.0);
+        assert!(things.get2_mut(a, b).0.is_none());
+    }
+
+    #[test]
+    fn placed_things_keep_their_index() {
+        let mut things = Things::new();
+        let far = Index::from_raw_parts(40, 7);
+        assert!(things.place(far, thing(1.0)));
+        assert!(!things.place(Index::from_raw_parts(MAX_THINGS, 0), thing(2.0)));
+        assert_eq!(things.len(), 1);
+        assert_eq!(things.iter().map(|(id, _)| id).collect::<Vec<_>>(), vec![far]);
+
+        // things inserted afterwards neither reuse the index nor its generation
+        let id = things.insert(thing(3.0));
+        assert_ne!(id.into_raw_parts().0, 40);
+        assert!(id.into_raw_parts().1 > 7);
+        assert_eq!(things.len(), 2);
+    }
+
+    #[test]
+    fn round_trips_through_bincode() {
+        let mut things = Things::new();
+        let a = things.insert(thing(1.0));
+        let b = things.insert(thing(2.0));
+        things.remove(a);
+
+        let mut decoded:Things = bincode::deserialize(&bincode::serialize(&things).unwrap()).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[b].pos().x, 2.0);
+        assert!(decoded.get(a).is_none());
+        let c = decoded.insert(thing(3.0));
+        assert!(c != a && c != b);
+    }
+}